@@ -1,20 +1,93 @@
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
 
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Debug)]
 pub struct Aabb {
     pub minimum: Vec3,
     pub maximum: Vec3,
 }
 
 impl Aabb {
-    pub fn surrounding_box(self, other: Aabb) -> Self {
+    pub fn new(minimum: Vec3, maximum: Vec3) -> Self {
+        Self { minimum, maximum }
+    }
+
+    // Inverted box, union with anything gives back the other box
+    pub fn empty() -> Self {
+        Self {
+            minimum: Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            maximum: Vec3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vec3]) -> Self {
+        points
+            .iter()
+            .fold(Aabb::empty(), |bbox, p| bbox.union(Aabb::new(*p, *p)))
+    }
+
+    #[inline]
+    pub fn is_empty(self) -> bool {
+        self.minimum.x > self.maximum.x
+            || self.minimum.y > self.maximum.y
+            || self.minimum.z > self.maximum.z
+    }
+
+    #[inline]
+    pub fn union(self, other: Aabb) -> Self {
         Self {
             minimum: self.minimum.zip_with(other.minimum, f64::min),
             maximum: self.maximum.zip_with(other.maximum, f64::max),
         }
     }
-    pub fn hit(self, r: Ray, t_range: std::ops::Range<f64>) -> bool {
+
+    #[inline]
+    pub fn contains(self, p: Vec3) -> bool {
+        (self.minimum.x..=self.maximum.x).contains(&p.x)
+            && (self.minimum.y..=self.maximum.y).contains(&p.y)
+            && (self.minimum.z..=self.maximum.z).contains(&p.z)
+    }
+
+    pub fn surface_area(self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.maximum - self.minimum;
+        2.0 * (d.x * d.y + d.x * d.z + d.y * d.z)
+    }
+
+    #[inline]
+    pub fn centroid(self) -> Vec3 {
+        (self.minimum + self.maximum) * 0.5
+    }
+
+    // Index of the axis with the largest extent: 0 for X, 1 for Y and 2 for Z
+    pub fn longest_axis(self) -> usize {
+        let d = self.maximum - self.minimum;
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Grow the thin axes so that no side is smaller than delta (i.e. flat rects and triangles)
+    pub fn pad(self, delta: f64) -> Self {
+        let mut minimum = self.minimum;
+        let mut maximum = self.maximum;
+        for axis in 0..3 {
+            if maximum[axis] - minimum[axis] < delta {
+                minimum[axis] -= delta / 2.0;
+                maximum[axis] += delta / 2.0;
+            }
+        }
+        Self { minimum, maximum }
+    }
+
+    // Slab test, returns the entry and exit t of the ray clipped to t_range
+    pub fn hit(self, r: Ray, t_range: std::ops::Range<f64>) -> Option<(f64, f64)> {
         let inv_d = 1.0 / r.dir;
         let t0 = (self.minimum - r.orig) * inv_d;
         let t1 = (self.maximum - r.orig) * inv_d;
@@ -24,6 +97,37 @@ impl Aabb {
         );
         let start = t_range.start.max(t0.reduce(f64::max));
         let end = t_range.end.min(t1.reduce(f64::min));
-        end > start
+        if end > start {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_includes_the_faces() {
+        let bbox = Aabb::new(Vec3::new(-1.0, 0.0, 2.0), Vec3::new(1.0, 3.0, 4.0));
+        assert!(bbox.contains(Vec3::new(0.0, 1.5, 3.0)));
+        assert!(bbox.contains(Vec3::new(-1.0, 3.0, 2.0)));
+        assert!(!bbox.contains(Vec3::new(0.0, 1.5, 4.001)));
+        assert!(!bbox.contains(Vec3::new(f64::NAN, 1.5, 3.0)));
+        assert!(!Aabb::empty().contains(Vec3::new(0.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn surface_area() {
+        let bbox = Aabb::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(bbox.surface_area(), 22.0);
+        // A flat box still has its two faces
+        let flat = Aabb::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 2.0, 0.0));
+        assert_eq!(flat.surface_area(), 8.0);
+        assert_eq!(Aabb::empty().surface_area(), 0.0);
+        let p = Vec3::new(1.0, 1.0, 1.0);
+        assert_eq!(Aabb::new(p, p).surface_area(), 0.0);
     }
 }
@@ -4,6 +4,7 @@ use crate::accelerators::aabb::*;
 use crate::core::hittable::*;
 use crate::core::hittable_list::*;
 use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
 
 #[allow(dead_code)]
 enum BVHNode {
@@ -14,31 +15,25 @@ enum BVHNode {
 pub struct Bvh {
     pub bbox: Aabb,
     tree: BVHNode,
+    has_media: bool,
 }
 // TODO: Make sure the ranges time0..time1 are forwarded properly when implemented
 impl Bvh {
-    #[allow(dead_code)]
+    #[inline]
+    fn bbox_of(obj: &Arc<dyn Hittable>) -> Aabb {
+        obj.bounding_box(0.0, 0.0)
+            .expect("No bounding box in BVH constructor")
+    }
+
+    // Split along the axis where the centroids are spread the most
     #[inline]
     fn axis_selection(objs: &[Arc<dyn Hittable>]) -> usize {
-        fn axis_range(objs: &[Arc<dyn Hittable>], axis: usize) -> f64 {
-            let range = objs.iter().fold(f64::MAX..f64::MIN, |range, o| {
-                let mut bb: Aabb = Default::default();
-                o.bounding_box(0.0, 0.0, &mut bb);
-                let min = bb.minimum[axis].min(bb.maximum[axis]);
-                let max = bb.minimum[axis].max(bb.maximum[axis]);
-                range.start.min(min)..range.end.max(max)
-            });
-            range.end - range.start
-        }
-        {
-            let mut ranges = [
-                (0, axis_range(objs, 0)),
-                (1, axis_range(objs, 0)),
-                (2, axis_range(objs, 0)),
-            ];
-            ranges.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-            ranges[0].0
-        }
+        objs.iter()
+            .fold(Aabb::empty(), |bounds, o| {
+                let c = Bvh::bbox_of(o).centroid();
+                bounds.union(Aabb::new(c, c))
+            })
+            .longest_axis()
     }
     #[allow(dead_code)]
     pub fn new(
@@ -54,12 +49,8 @@ impl Bvh {
 
         // Better axis selection for separation
         let comparator = |a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>| {
-            let mut abb: Aabb = Default::default();
-            let mut bbb: Aabb = Default::default();
-            a.bounding_box(0.0, 0.0, &mut abb);
-            b.bounding_box(0.0, 0.0, &mut bbb);
-            let av = abb.minimum[axis] + abb.maximum[axis];
-            let bv = bbb.minimum[axis] + bbb.maximum[axis];
+            let av = Bvh::bbox_of(a).centroid()[axis];
+            let bv = Bvh::bbox_of(b).centroid()[axis];
             av.partial_cmp(&bv).unwrap()
         };
 
@@ -74,23 +65,23 @@ impl Bvh {
             1..=10 => {
                 let mut items: Vec<Arc<dyn Hittable>> = vec![];
                 objects_copy.objects[start..end].clone_into(&mut items);
-                let mut tmp_bbox: Aabb = Default::default();
                 let leaf: Arc<dyn Hittable> = Arc::new(HittableList::from(items));
-                if leaf.bounding_box(time0, time1, &mut tmp_bbox) {
-                    Bvh {
+                match leaf.bounding_box(time0, time1) {
+                    Some(bbox) => Bvh {
+                        has_media: leaf.has_media(),
                         tree: BVHNode::Leaf(leaf),
-                        bbox: tmp_bbox,
-                    }
-                } else {
-                    panic!["No bounding box in BVH Node"]
+                        bbox,
+                    },
+                    None => panic!["No bounding box in BVH Node"],
                 }
             }
             _ => {
                 let mid = start + object_span / 2;
                 let left = Bvh::new(objects_copy, start, mid, time0, time1);
                 let right = Bvh::new(objects_copy, mid, end, time0, time1);
-                let tmp_bbox = left.bbox.surrounding_box(right.bbox);
+                let tmp_bbox = left.bbox.union(right.bbox);
                 Bvh {
+                    has_media: left.has_media || right.has_media,
                     tree: BVHNode::Branch {
                         left: Arc::new(left),
                         right: Arc::new(right),
@@ -104,7 +95,7 @@ impl Bvh {
 
 impl Hittable for Bvh {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        if self.bbox.hit(r, t_min..t_max) {
+        if self.bbox.hit(r, t_min..t_max).is_some() {
             match &self.tree {
                 BVHNode::Leaf(leaf) => leaf.hit(r, t_min, t_max),
                 BVHNode::Branch { left, right } => {
@@ -125,8 +116,49 @@ impl Hittable for Bvh {
             None
         }
     }
-    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
-        *output_box = self.bbox;
-        true
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<Aabb> {
+        Some(self.bbox)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        let clear = Vec3::new(1.0, 1.0, 1.0);
+        // Without media any surface blocks it, the closest hit is as good as any
+        if !self.has_media {
+            return if self.hit(r, t_min, t_max).is_some() {
+                Vec3::new(0.0, 0.0, 0.0)
+            } else {
+                clear
+            };
+        }
+        if self.bbox.hit(r, t_min..t_max).is_none() {
+            return clear;
+        }
+        match &self.tree {
+            BVHNode::Leaf(leaf) => leaf.transmittance(r, t_min, t_max),
+            BVHNode::Branch { left, right } => {
+                let transmittance = left.transmittance(r, t_min, t_max);
+                if transmittance.max_component() <= 0.0 {
+                    return transmittance;
+                }
+                transmittance * right.transmittance(r, t_min, t_max)
+            }
+        }
+    }
+
+    fn medium_weight(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        let clear = Vec3::new(1.0, 1.0, 1.0);
+        if !self.has_media || self.bbox.hit(r, t_min..t_max).is_none() {
+            return clear;
+        }
+        match &self.tree {
+            BVHNode::Leaf(leaf) => leaf.medium_weight(r, t_min, t_max),
+            BVHNode::Branch { left, right } => {
+                left.medium_weight(r, t_min, t_max) * right.medium_weight(r, t_min, t_max)
+            }
+        }
+    }
+
+    fn has_media(&self) -> bool {
+        self.has_media
     }
 }
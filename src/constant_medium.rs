@@ -8,7 +8,7 @@ use crate::geometry::vector3::*;
 use crate::{material::*, texture::*};
 
 pub struct ConstantMedium {
-    boundary: Arc<dyn Hittable + Sync + Send>,
+    boundary: Option<Arc<dyn Hittable + Sync + Send>>, // None fills the whole scene
     phase_function: Arc<dyn Material + Sync + Send>,
     neg_inv_densiy: f64,
 }
@@ -16,7 +16,7 @@ pub struct ConstantMedium {
 impl ConstantMedium {
     pub fn from_color(b: Arc<dyn Hittable + Sync + Send>, d: f64, c: Vec3) -> Self {
         Self {
-            boundary: b,
+            boundary: Some(b),
             neg_inv_densiy: -1.0 / d,
             phase_function: Arc::new(Isotropic::from_color(c)),
         }
@@ -28,58 +28,128 @@ impl ConstantMedium {
         a: Arc<dyn Texture + Sync + Send>,
     ) -> Self {
         Self {
-            boundary: b,
+            boundary: Some(b),
             neg_inv_densiy: -1.0 / d,
             phase_function: Arc::new(Isotropic::from_tex(a)),
         }
     }
+
+    /*
+     *  Fog over the whole scene, the camera included. It has no bounding box, so it can't
+     *  go in a BVH, and rays that would leave the scene end in it instead of reaching the
+     *  background. A medium with a large boundary leaves the sky visible.
+     */
+    pub fn global(d: f64, c: Vec3) -> Self {
+        Self {
+            boundary: None,
+            neg_inv_densiy: -1.0 / d,
+            phase_function: Arc::new(Isotropic::from_color(c)),
+        }
+    }
+
+    fn segments(&self, r: Ray, t_min: f64, t_max: f64) -> MediumSegments<'_> {
+        let boundary = self.boundary.as_ref().map(|b| b.as_ref() as &dyn Hittable);
+        medium_segments(boundary, r, t_min, t_max)
+    }
+
+    // Scatters by another phase function than Isotropic, e.g. HenyeyGreenstein, which
+    // carries the color of the medium
+    pub fn with_phase_function(mut self, phase_function: Arc<dyn Material + Sync + Send>) -> Self {
+        self.phase_function = phase_function;
+        self
+    }
 }
 
-impl Hittable for ConstantMedium {
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let mut rng = rand::thread_rng();
-        if let Some(mut hit1) = self.boundary.hit(r, f64::MIN, f64::MAX) {
-            if let Some(mut hit2) = self.boundary.hit(r, hit1.t + 0.0001, f64::MAX) {
-                if hit1.t < t_min {
-                    hit1.t = t_min;
-                }
-                if hit2.t > t_max {
-                    hit2.t = t_max;
-                }
+/*
+ *  The stretches of r inside a closed boundary between t_min and t_max, in order. The
+ *  facing of every crossing tells whether r was inside up to it, so the boundary can be
+ *  any closed surface, convex or not, and r can start inside it. Without a boundary all
+ *  of t_min to t_max is inside.
+ */
+pub struct MediumSegments<'a> {
+    boundary: Option<&'a dyn Hittable>,
+    r: Ray,
+    t: f64,
+    t_max: f64,
+}
 
-                if hit1.t >= hit2.t {
-                    return None;
-                }
+pub fn medium_segments(
+    boundary: Option<&dyn Hittable>,
+    r: Ray,
+    t_min: f64,
+    t_max: f64,
+) -> MediumSegments<'_> {
+    MediumSegments {
+        boundary,
+        r,
+        t: t_min,
+        t_max,
+    }
+}
 
-                if hit1.t < 0.0 {
-                    hit1.t = 0.0;
-                }
+impl Iterator for MediumSegments<'_> {
+    type Item = (f64, f64);
 
-                let ray_len = r.dir.length();
-                let distance_inside_boundary = (hit2.t - hit1.t) * ray_len;
-                let hit_distance = self.neg_inv_densiy * rng.gen::<f64>().ln();
+    fn next(&mut self) -> Option<(f64, f64)> {
+        while self.t < self.t_max {
+            let t0 = self.t;
+            let Some(boundary) = self.boundary else {
+                self.t = self.t_max;
+                return Some((t0, self.t_max));
+            };
+            // A crossing past t_max still tells whether r is inside up to t_max
+            let Some(hit) = boundary.hit(self.r, t0, f64::INFINITY) else {
+                self.t = self.t_max;
+                return None;
+            };
+            self.t = hit.t;
+            if !hit.front_face {
+                return Some((t0, f64::min(hit.t, self.t_max)));
+            }
+        }
+        None
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut rng = rand::thread_rng();
+        let ray_len = r.dir.length();
+        // Distance to the collision, used up by the segments inside in turn
+        let mut hit_distance = self.neg_inv_densiy * rng.gen::<f64>().ln();
 
-                if hit_distance > distance_inside_boundary {
-                    return None;
-                }
-                let t = hit1.t + hit_distance / ray_len;
-                Some(HitRecord {
+        for (t0, t1) in self.segments(r, t_min, t_max) {
+            let distance_inside_boundary = (t1 - t0) * ray_len;
+            if hit_distance <= distance_inside_boundary {
+                let t = t0 + hit_distance / ray_len;
+                // Normal is arbitrary and front_face stays true, p is not on any surface
+                return Some(HitRecord::new(
+                    r.at(t),
+                    Vec3::new(1.0, 0.0, 0.0),
                     t,
-                    u: 0.0,
-                    v: 0.0,
-                    p: r.at(t),
-                    normal: Vec3::new(1.0, 0.0, 0.0), // arbitrary
-                    front_face: true,                 // Also arbitrary
-                    mat_ptr: Arc::clone(&self.phase_function),
-                })
-            } else {
-                None
+                    0.0,
+                    0.0,
+                    Arc::clone(&self.phase_function),
+                ));
             }
-        } else {
-            None
+            hit_distance -= distance_inside_boundary;
         }
+        None
+    }
+
+    // None for a global medium, which has no bounds
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb> {
+        self.boundary.as_ref()?.bounding_box(time0, time1)
     }
-    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
-        self.boundary.bounding_box(time0, time1, output_box)
+
+    // Beer-Lambert, exact for a constant density
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        let length: f64 = self.segments(r, t_min, t_max).map(|(t0, t1)| t1 - t0).sum();
+        let transmittance = f64::exp(length * r.dir.length() / self.neg_inv_densiy);
+        Vec3::new(transmittance, transmittance, transmittance)
+    }
+
+    fn has_media(&self) -> bool {
+        true
     }
 }
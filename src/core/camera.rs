@@ -8,6 +8,8 @@ use rayon::prelude::*;
 use crate::core::hittable::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
+use crate::lights::{environment::*, light::*};
+use crate::sampling::*;
 use crate::utils::*;
 
 #[allow(dead_code)]
@@ -51,6 +53,30 @@ impl Camera {
     ) -> Self {
         // Will be mostly this
         let vup = Vec3::new(0.0, 1.0, 0.0);
+        Self::init_with_up(
+            aspect_ratio,
+            image_width,
+            samples_per_pixel,
+            max_depth,
+            vfov,
+            lookfrom,
+            lookat,
+            vup,
+        )
+    }
+
+    // For cameras that are rolled or look straight up/down, where +Y can't be the up vector
+    #[allow(clippy::too_many_arguments)]
+    pub fn init_with_up(
+        aspect_ratio: f64,
+        image_width: u64,
+        samples_per_pixel: u64,
+        max_depth: u64,
+        vfov: f64,
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+    ) -> Self {
         let defocus_angle = 0.0;
         // TODO: Maybe move this as an adjustable param
         let focus_dist = 10.0;
@@ -115,7 +141,7 @@ impl Camera {
         }
     }
 
-    fn get_ray(&self, i: f64, j: f64, s_i: f64, s_j: f64) -> Ray {
+    fn get_ray(&self, i: f64, j: f64, s_i: f64, s_j: f64) -> (Ray, RayDifferential) {
         // Get a randomly-sampled camera ray for the pixel at location i,j, originating from
         // the camera defocus disk.
 
@@ -135,7 +161,17 @@ impl Camera {
 
         let ray_direction = pixel_sample - ray_origin;
 
-        Ray::new(ray_origin, ray_direction)
+        // The neighbouring pixels are closer than a full pixel once there are several
+        // samples per pixel to average, so textures don't come out blurry (as in PBRT)
+        let scale = f64::max(0.125, self.recip_sqrt_spp);
+        let differential = RayDifferential {
+            rx_origin: ray_origin,
+            rx_dir: ray_direction + self.pixel_delta_u * scale,
+            ry_origin: ray_origin,
+            ry_dir: ray_direction + self.pixel_delta_v * scale,
+        };
+
+        (Ray::new(ray_origin, ray_direction), differential)
     }
 
     fn pixel_sample_square(&self, s_i: f64, s_j: f64) -> Vec3 {
@@ -152,37 +188,134 @@ impl Camera {
         self.center + (self.defocus_disk_u * p[0]) + (self.defocus_disk_v * p[1])
     }
 
-    fn ray_color(r: Ray, background: Vec3, world: &dyn Hittable, depth: u64) -> Vec3 {
+    // Next event estimation: light from one explicitly sampled light, if nothing blocks it
+    fn sample_light(r: Ray, hit: &HitRecord, world: &dyn Hittable, lights: &LightList) -> Vec3 {
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        let Some((light, pick_pdf)) = lights.choose(hit.p) else {
+            return black;
+        };
+        let Some(ls) = light.sample(hit.p) else {
+            return black;
+        };
+
+        let shadow_ray = hit.spawn_ray(ls.wi);
+        let f = hit.mat_ptr.eval(r, hit, shadow_ray);
+        if f.max_component() <= 0.0 {
+            return black;
+        }
+        // Area lights are in the world too, the shadow ray stops off their surface
+        let (shadow_ray, unoccluded) = match ls.surface {
+            Some(light) => hit.spawn_ray_to(light.p, light.n, light.p_error),
+            None => (shadow_ray, ls.distance),
+        };
+        let transmittance = world.transmittance(shadow_ray, 0.0, unoccluded);
+        if transmittance.max_component() <= 0.0 {
+            return black;
+        }
+        let light_pdf = ls.pdf * pick_pdf;
+        if light.is_delta() {
+            return f * transmittance * ls.radiance / light_pdf;
+        }
+        // The scattered ray could have found this light too
+        let scattering_pdf = hit.mat_ptr.scattering_pdf(r, hit, shadow_ray);
+        f * transmittance * ls.radiance * power_heuristic(light_pdf, scattering_pdf) / light_pdf
+    }
+
+    /*
+     *  `scattering_pdf` is the density the material sampled `r` with, None for camera
+     *  rays and specular bounces. Lights that are also sampled explicitly get weighted
+     *  by it when a ray finds them. Only camera rays come with a `differential`, the
+     *  textures seen by the other ones are looked up unfiltered.
+     */
+    fn ray_color(
+        r: Ray,
+        differential: Option<RayDifferential>,
+        background: &dyn Background,
+        world: &dyn Hittable,
+        lights: &LightList,
+        depth: u64,
+        scattering_pdf: Option<f64>,
+    ) -> Vec3 {
         // Depth limit reached don't accumulate any more light
         if depth == 0 {
             return Vec3::new(0.0, 0.0, 0.0);
         }
 
-        if let Some(hit) = world.hit(r, 0.001, f64::MAX) {
-            let emitted = hit.mat_ptr.emitted(hit.u, hit.v, hit.p);
-            if let Some((scattered, albedo, pdf)) = hit.mat_ptr.scatter(r, &hit) {
-                let scattering_pdf = hit.mat_ptr.scattering_pdf(r, &hit, scattered);
-                emitted
-                    + (Self::ray_color(scattered, background, world, depth - 1)
-                        * scattering_pdf
-                        * albedo)
-                        / pdf
+        // Scattered rays are spawned off the surface, so no epsilon is needed for t_min
+        let hit = world.hit(r, 0.0, f64::INFINITY);
+        // Media that dim the channels unevenly weigh what is seen through them
+        let medium_weight = if world.has_media() {
+            world.medium_weight(r, 0.0, hit.as_ref().map_or(f64::INFINITY, |hit| hit.t))
+        } else {
+            Vec3::new(1.0, 1.0, 1.0)
+        };
+
+        let radiance = if let Some(mut hit) = hit {
+            if let Some(rd) = differential {
+                hit.compute_footprint(&rd);
+            }
+            let material = hit.mat_ptr.clone();
+            material.perturb_normal(&mut hit);
+            // Lights sampled at the previous bounce were found by both strategies
+            let mut emitted = hit.mat_ptr.emitted(r, &hit);
+            if let Some(pdf) = scattering_pdf {
+                if emitted.max_component() > 0.0 {
+                    emitted = emitted * power_heuristic(pdf, lights.pdf(r, hit.t));
+                }
+            }
+            // Delta lights can't be hit by the scattered rays, so they are only sampled
+            let emitted = emitted + Self::sample_light(r, &hit, world, lights);
+            if let Some(srec) = hit.mat_ptr.scatter(r, &hit) {
+                if srec.skip_pdf {
+                    emitted
+                        + srec.attenuation
+                            * Self::ray_color(
+                                srec.ray,
+                                None,
+                                background,
+                                world,
+                                lights,
+                                depth - 1,
+                                None,
+                            )
+                } else {
+                    let scattering_pdf = hit.mat_ptr.scattering_pdf(r, &hit, srec.ray);
+                    emitted
+                        + (Self::ray_color(
+                            srec.ray,
+                            None,
+                            background,
+                            world,
+                            lights,
+                            depth - 1,
+                            Some(scattering_pdf),
+                        ) * scattering_pdf
+                            * srec.attenuation)
+                            / srec.pdf
+                }
             } else {
                 emitted
             }
         } else {
             // If not hit anything
-            background
-        }
+            let dir = Vec3::unit_vector(r.dir);
+            let radiance = background.radiance(dir);
+            match scattering_pdf {
+                Some(pdf) => radiance * power_heuristic(pdf, lights.pdf(r, f64::INFINITY)),
+                None => radiance,
+            }
+        };
+        radiance * medium_weight
     }
 
-    pub fn render(&self, world: &dyn Hittable, background: Vec3) {
+    pub fn render(&self, world: &dyn Hittable, lights: &LightList, background: &dyn Background) {
         // File
         std::fs::create_dir_all("./outputs")
             .expect("Problem with creation of the outputs/ folder...\n");
         let file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open("./outputs/IMAGE.ppm")
             .expect("Unable to open the file IMAGE.ppm");
 
@@ -205,8 +338,17 @@ impl Camera {
 
                         for s_i in 0..self.sqrt_spp {
                             for s_j in 0..self.sqrt_spp {
-                                let r = self.get_ray(i as f64, j as f64, s_i as f64, s_j as f64);
-                                col += Self::ray_color(r, background, world, self.max_depth);
+                                let (r, rd) =
+                                    self.get_ray(i as f64, j as f64, s_i as f64, s_j as f64);
+                                col += Self::ray_color(
+                                    r,
+                                    Some(rd),
+                                    background,
+                                    world,
+                                    lights,
+                                    self.max_depth,
+                                    None,
+                                );
                             }
                         }
 
@@ -1,18 +1,26 @@
 use crate::accelerators::aabb::*;
+use crate::geometry::onb::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
 use crate::material::*;
+use crate::texture::*;
 
 use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct HitRecord {
     pub p: Vec3,
-    pub normal: Vec3,
+    pub normal: Vec3,           // Shading normal, faces against the incoming ray
+    pub geometric_normal: Vec3, // True surface normal, same side as `normal`
     pub mat_ptr: Arc<dyn Material + Sync + Send>,
     pub u: f64,
     pub v: f64, // U and V for texture values
     pub t: f64,
     pub front_face: bool,
+    pub p_error: Vec3, // Absolute floating point error bound on p
+    pub dpdu: Vec3,    // How p changes along u and v, zero
+    pub dpdv: Vec3,    // when the surface has no parametrization
+    pub footprint: Footprint,
 }
 
 impl HitRecord {
@@ -27,11 +35,16 @@ impl HitRecord {
         Self {
             p: point,
             normal,
+            geometric_normal: normal,
             mat_ptr,
             u,
             v,
             t,
-            front_face: true, // Placeholder
+            front_face: true,                  // Placeholder
+            p_error: Vec3::new(0.0, 0.0, 0.0), // Exact unless the primitive says otherwise
+            dpdu: Vec3::new(0.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, 0.0),
+            footprint: Footprint::default(),
         }
     }
 
@@ -43,11 +56,127 @@ impl HitRecord {
         } else {
             -outward_normal
         };
+        self.geometric_normal = self.normal;
+    }
+
+    // Interpolated normals only affect shading, call after set_face_normal
+    pub fn set_shading_normal(&mut self, shading_normal: Vec3) {
+        self.normal = if shading_normal.dot(self.geometric_normal) < 0.0 {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+    }
+
+    /*
+     *  How far the point, u and v move between this pixel and the next ones, from where
+     *  the offset rays of `rd` cross the tangent plane (PBRT 10.1.1). The least squares
+     *  fit is the one of PBRT's 4th edition, it copes with dpdu and dpdv not being
+     *  orthogonal.
+     */
+    pub fn compute_footprint(&mut self, rd: &RayDifferential) {
+        let n = self.geometric_normal;
+        let d = n.dot(self.p);
+        let on_plane = |origin: Vec3, dir: Vec3| {
+            let t = (d - n.dot(origin)) / n.dot(dir);
+            origin + dir * t
+        };
+        let dpdx = on_plane(rd.rx_origin, rd.rx_dir) - self.p;
+        let dpdy = on_plane(rd.ry_origin, rd.ry_dir) - self.p;
+        if !dpdx.x.is_finite() || !dpdy.x.is_finite() {
+            // Grazing rays, keep the sharpest lookup
+            return;
+        }
+        self.footprint = Footprint {
+            dpdx,
+            dpdy,
+            ..Default::default()
+        };
+
+        let ata00 = self.dpdu.dot(self.dpdu);
+        let ata01 = self.dpdu.dot(self.dpdv);
+        let ata11 = self.dpdv.dot(self.dpdv);
+        let inv_det = 1.0 / (ata00 * ata11 - ata01 * ata01);
+        if !inv_det.is_finite() {
+            // No parametrization to move u and v along
+            return;
+        }
+        let solve = |dp: Vec3| {
+            let (atb0, atb1) = (self.dpdu.dot(dp), self.dpdv.dot(dp));
+            let du = (ata11 * atb0 - ata01 * atb1) * inv_det;
+            let dv = (ata00 * atb1 - ata01 * atb0) * inv_det;
+            (du.clamp(-1e8, 1e8), dv.clamp(-1e8, 1e8))
+        };
+        (self.footprint.dudx, self.footprint.dvdx) = solve(dpdx);
+        (self.footprint.dudy, self.footprint.dvdy) = solve(dpdy);
+    }
+
+    /*
+     *  Tangent frame around the shading normal, u along dpdu and v on the side dpdv
+     *  points to, which is what tangent space normal maps are drawn in. Without dpdu the
+     *  tangents are arbitrary.
+     */
+    pub fn shading_frame(&self) -> Onb {
+        let tangent = if self.dpdu.lenght_squared() > 0.0 {
+            self.dpdu
+        } else {
+            self.dpdv.cross(self.normal)
+        };
+        let mut frame = Onb::build_from_wu(self.normal, tangent);
+        // The normal of a back face turns the frame around
+        if frame.v().dot(self.dpdv) < 0.0 {
+            frame[1] = -frame[1];
+        }
+        frame
+    }
+
+    // Ray leaving the surface in direction `dir` that can't re-intersect it at t ~ 0.
+    // Offset along the true normal, the shading normal can be far from it
+    pub fn spawn_ray(&self, dir: Vec3) -> Ray {
+        Ray::new(
+            offset_ray_origin(self.p, self.p_error, self.geometric_normal, dir),
+            dir,
+        )
+    }
+
+    // Ray from the surface to the point `p` of another surface, with its normal `n` and
+    // error bound `p_error`, and how far it goes. Both ends are moved off their surface
+    // so the ray can't hit either of them
+    pub fn spawn_ray_to(&self, p: Vec3, n: Vec3, p_error: Vec3) -> (Ray, f64) {
+        let from = offset_ray_origin(self.p, self.p_error, self.geometric_normal, p - self.p);
+        let to = offset_ray_origin(p, p_error, n, from - p);
+        let distance = (to - from).length();
+        (Ray::new(from, (to - from) / distance), distance)
     }
 }
 
 pub trait Hittable: Sync + Send {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
-    // TODO: Rustify this part, currently it is C-like
-    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool;
+    // None for objects without a finite bounding box
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb>;
+
+    // Fraction of the light that makes it along r from t_min to t_max, per channel, for
+    // the shadow rays. Surfaces block all of it, media let some through
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        if self.hit(r, t_min, t_max).is_some() {
+            Vec3::new(0.0, 0.0, 0.0)
+        } else {
+            Vec3::new(1.0, 1.0, 1.0)
+        }
+    }
+
+    /*
+     *  Per channel weight of what is seen along r at t_max through the media from t_min,
+     *  given `hit` found nothing in them before. Media only pick where rays scatter by
+     *  the extinction of all channels together, this makes up for the channels that
+     *  are thinner or thicker than that. One for surfaces and gray media.
+     */
+    fn medium_weight(&self, _r: Ray, _t_min: f64, _t_max: f64) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
+
+    // Whether there is a medium in there, the integrator skips the media queries if not
+    fn has_media(&self) -> bool {
+        false
+    }
 }
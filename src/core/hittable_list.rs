@@ -5,6 +5,7 @@ use std::ops::{Index, IndexMut};
 use crate::accelerators::aabb::*;
 use crate::core::hittable::*;
 use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
 
 #[derive(Default, Clone)]
 pub struct HittableList {
@@ -33,28 +34,40 @@ impl Hittable for HittableList {
         }
         hit_anything
     }
-    // TODO: Rustify this part, currently it is C-like
-    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
-        if self.objects.is_empty() {
-            return false;
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        let mut transmittance = Vec3::new(1.0, 1.0, 1.0);
+        for object in self.objects.iter() {
+            transmittance *= object.transmittance(r, t_min, t_max);
+            if transmittance.max_component() <= 0.0 {
+                break;
+            }
         }
+        transmittance
+    }
 
-        let mut temp_box: Aabb = Default::default();
-        let mut first_box = true;
+    fn medium_weight(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        self.objects
+            .iter()
+            .filter(|object| object.has_media())
+            .fold(Vec3::new(1.0, 1.0, 1.0), |weight, object| {
+                weight * object.medium_weight(r, t_min, t_max)
+            })
+    }
 
-        for obj in self.objects.iter() {
-            if !obj.bounding_box(time0, time1, &mut temp_box) {
-                return false;
-            }
-            *output_box = if first_box {
-                temp_box
-            } else {
-                output_box.surrounding_box(temp_box)
-            };
-            first_box = false;
+    fn has_media(&self) -> bool {
+        self.objects.iter().any(|object| object.has_media())
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb> {
+        if self.objects.is_empty() {
+            return None;
         }
 
-        true
+        // Any unbounded object makes the whole list unbounded
+        self.objects.iter().try_fold(Aabb::empty(), |acc, obj| {
+            obj.bounding_box(time0, time1).map(|bbox| acc.union(bbox))
+        })
     }
 }
 
@@ -1,11 +1,16 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::accelerators::aabb::*;
 use crate::core::hittable::*;
 use crate::core::hittable_list::*;
 use crate::geometry::instance::*;
 use crate::geometry::vector3::*;
-use crate::primitives::{mesh::*, rect::*, sphere::*};
-use crate::{constant_medium::*, material::*, texture::*};
+use crate::lights::{directional::*, ies::*, light::*, point::*};
+use crate::loaders::gltf_scene::*;
+use crate::noise::*;
+use crate::primitives::{cutout::*, interior::*, mesh::*, rect::*, sphere::*, triangle_mesh::*};
+use crate::{constant_medium::*, heterogeneous_medium::*, material::*, texture::*};
 pub struct Scene {
     // Can add env related things here
 }
@@ -54,13 +59,10 @@ impl Scene {
 
     pub fn earth_scene() -> HittableList {
         let mut world: HittableList = Default::default();
-        let image = image::open("static/moon.jpg")
-            .expect("image not found")
-            .to_rgb8();
-        let (width, height) = image.dimensions();
-        let data = image.into_raw();
-        let texture: Arc<dyn Texture + Sync + Send> =
-            Arc::new(ImageTexture::new(data, width as u64, height as u64));
+        let texture: Arc<dyn Texture + Sync + Send> = Arc::new(
+            ImageTexture::from_file("static/moon.jpg", ColorEncoding::Srgb)
+                .expect("image not found"),
+        );
         let mat_world: Arc<dyn Material + Sync + Send> =
             Arc::new(Lambertian::from_texture(texture));
         world.add(Arc::new(Sphere::new(
@@ -111,8 +113,12 @@ impl Scene {
         world
     }
 
-    pub fn cornell_box() -> HittableList {
-        let mut world: HittableList = Default::default();
+    // The walls and the ceiling light of the Cornell box, returns the white of the walls
+    // for whatever goes inside
+    fn cornell_walls(
+        world: &mut HittableList,
+        lights: &mut LightList,
+    ) -> Arc<dyn Material + Sync + Send> {
         let red: Arc<dyn Material + Sync + Send> =
             Arc::new(Lambertian::from_color(Vec3::new(0.65, 0.05, 0.05)));
         let white: Arc<dyn Material + Sync + Send> =
@@ -140,7 +146,8 @@ impl Scene {
             0.0,
             Arc::clone(&red),
         )));
-        world.add(Arc::new(AARect::new(
+        // The ceiling light is sampled directly as well as found by the rays
+        let ceiling_light = AARect::new(
             Plane::XZ,
             213.0,
             343.0,
@@ -148,8 +155,10 @@ impl Scene {
             332.0,
             554.0,
             Arc::clone(&light),
-        )));
-        // nope
+        );
+        lights.extend(ceiling_light.area_lights());
+        world.add(Arc::new(ceiling_light));
+        // Floor, ceiling and back wall
         world.add(Arc::new(AARect::new(
             Plane::XZ,
             0.0,
@@ -178,6 +187,14 @@ impl Scene {
             Arc::clone(&white),
         )));
 
+        white
+    }
+
+    pub fn cornell_box() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let mut lights: LightList = Default::default();
+        let white = Self::cornell_walls(&mut world, &mut lights);
+
         // Add boxes to the cornell box
         let mut box1: Arc<dyn Hittable> = Arc::new(Box::new_triangles(
             Vec3::new(0.0, 0.0, 0.0),
@@ -199,75 +216,13 @@ impl Scene {
         box2 = Arc::new(Translate::new(box2, Vec3::new(130.0, 0.0, 65.0)));
         world.add(box2);
 
-        world
+        (world, lights)
     }
 
-    pub fn cornell_with_gas() -> HittableList {
+    pub fn cornell_with_gas() -> (HittableList, LightList) {
         let mut world: HittableList = Default::default();
-        let red: Arc<dyn Material + Sync + Send> =
-            Arc::new(Lambertian::from_color(Vec3::new(0.65, 0.05, 0.05)));
-        let white: Arc<dyn Material + Sync + Send> =
-            Arc::new(Lambertian::from_color(Vec3::new(0.73, 0.73, 0.73)));
-        let green: Arc<dyn Material + Sync + Send> =
-            Arc::new(Lambertian::from_color(Vec3::new(0.12, 0.45, 0.15)));
-        let light: Arc<dyn Material + Sync + Send> =
-            Arc::new(DiffuseLight::from_color(Vec3::new(15.0, 15.0, 15.0)));
-
-        world.add(Arc::new(AARect::new(
-            Plane::YZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            555.0,
-            Arc::clone(&green),
-        )));
-        world.add(Arc::new(AARect::new(
-            Plane::YZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            0.0,
-            Arc::clone(&red),
-        )));
-        world.add(Arc::new(AARect::new(
-            Plane::XZ,
-            213.0,
-            343.0,
-            227.0,
-            332.0,
-            554.0,
-            Arc::clone(&light),
-        )));
-        // nope
-        world.add(Arc::new(AARect::new(
-            Plane::XZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            0.0,
-            Arc::clone(&white),
-        )));
-        world.add(Arc::new(AARect::new(
-            Plane::XZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            555.0,
-            Arc::clone(&white),
-        )));
-        world.add(Arc::new(AARect::new(
-            Plane::XY,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            555.0,
-            Arc::clone(&white),
-        )));
+        let mut lights: LightList = Default::default();
+        let white = Self::cornell_walls(&mut world, &mut lights);
 
         // Add boxes to the cornell box
         let mut box1: Arc<dyn Hittable + Sync + Send> = Arc::new(Box::new(
@@ -291,82 +246,143 @@ impl Scene {
             0.01,
             Vec3::new(0.0, 0.0, 0.0),
         )));
-        world.add(Arc::new(ConstantMedium::from_color(
-            box2,
-            0.01,
-            Vec3::new(1.0, 1.0, 1.0),
-        )));
+        // Scatters mostly forward, like fog, brightest looking towards the light
+        let white_gas = Vec3::new(1.0, 1.0, 1.0);
+        world.add(Arc::new(
+            ConstantMedium::from_color(box2, 0.01, white_gas)
+                .with_phase_function(Arc::new(HenyeyGreenstein::from_color(white_gas, 0.6))),
+        ));
 
-        world
+        (world, lights)
     }
 
-    pub fn cornell_with_mesh(big_mesh: bool) -> HittableList {
+    // A puff of smoke made of noise in the Cornell box, it absorbs more blue than red
+    pub fn cornell_with_smoke() -> (HittableList, LightList) {
         let mut world: HittableList = Default::default();
-        let red: Arc<dyn Material + Sync + Send> =
-            Arc::new(Lambertian::from_color(Vec3::new(0.65, 0.05, 0.05)));
-        let white: Arc<dyn Material + Sync + Send> =
-            Arc::new(Lambertian::from_color(Vec3::new(0.73, 0.73, 0.73)));
-        let green: Arc<dyn Material + Sync + Send> =
-            Arc::new(Lambertian::from_color(Vec3::new(0.12, 0.45, 0.15)));
-        let light: Arc<dyn Material + Sync + Send> =
-            Arc::new(DiffuseLight::from_color(Vec3::new(15.0, 15.0, 15.0)));
+        let mut lights: LightList = Default::default();
+        Self::cornell_walls(&mut world, &mut lights);
+
+        // Noise eating into a ball, sampled on a grid
+        let center = Vec3::new(278.0, 240.0, 278.0);
+        let radius = 190.0;
+        let extent = Vec3::new(radius, radius, radius);
+        let bounds = Aabb::new(center - extent, center + extent);
+        let noise = Fractal::new(Arc::new(Simplex::new(7)), FractalKind::Fbm).with_octaves(4);
+        let grid = DensityGrid::from_fn(64, 64, 64, bounds, |p| {
+            let falloff = 1.0 - (p - center).length() / radius;
+            (falloff + 0.4 * noise.noise(p / 60.0) - 0.15) * 4.0
+        });
+        world.add(Arc::new(
+            HeterogeneousMedium::from_grid(
+                grid,
+                Vec3::new(0.002, 0.006, 0.012),
+                Vec3::new(0.02, 0.02, 0.02),
+            )
+            .with_lobes(0.6, -0.3, 0.2),
+        ));
 
-        world.add(Arc::new(AARect::new(
-            Plane::YZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            555.0,
-            Arc::clone(&green),
-        )));
-        world.add(Arc::new(AARect::new(
-            Plane::YZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            0.0,
-            Arc::clone(&red),
-        )));
-        world.add(Arc::new(AARect::new(
-            Plane::XZ,
-            213.0,
-            343.0,
-            227.0,
-            332.0,
-            554.0,
-            Arc::clone(&light),
+        (world, lights)
+    }
+
+    // A plume of smoke read from a cached .vol grid, lit from the Cornell box's ceiling
+    pub fn cornell_with_plume() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let mut lights: LightList = Default::default();
+        Self::cornell_walls(&mut world, &mut lights);
+
+        let grid = DensityGrid::from_file("static/smoke_plume.vol")
+            .unwrap_or_else(|e| panic!("Failed to load the plume: {e}"));
+        world.add(Arc::new(
+            HeterogeneousMedium::from_grid(
+                grid,
+                Vec3::new(0.004, 0.004, 0.004),
+                Vec3::new(0.03, 0.03, 0.03),
+            )
+            .with_anisotropy(0.4),
+        ));
+
+        (world, lights)
+    }
+
+    // A layer of cloud puffs over a field, the density straight from the clouds texture
+    pub fn cloud_layer() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::from_color(Vec3::new(0.3, 0.45, 0.2))),
         )));
-        // nope
-        world.add(Arc::new(AARect::new(
-            Plane::XZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            0.0,
-            Arc::clone(&white),
+
+        // Nothing where the sky would be clear, dense in the middle of the puffs
+        let clouds = CloudsTexture::new(0.7, 11)
+            .with_coverage(0.45)
+            .with_ramp(ColorRamp::new(&[
+                (0.0, Vec3::new(0.0, 0.0, 0.0)),
+                (1.0, Vec3::new(1.0, 1.0, 1.0)),
+            ]));
+        let boundary = Box::new(
+            Vec3::new(-10.0, 2.5, -10.0),
+            Vec3::new(10.0, 4.5, 3.0),
+            Arc::new(Lambertian::from_color(Vec3::new(0.0, 0.0, 0.0))),
+        );
+        world.add(Arc::new(
+            HeterogeneousMedium::new(
+                Arc::new(boundary),
+                Arc::new(TextureDensity::new(Arc::new(clouds), 1.0)),
+                Vec3::new(0.05, 0.05, 0.05),
+                Vec3::new(15.0, 15.0, 15.0),
+            )
+            .with_anisotropy(0.6),
+        ));
+
+        let mut lights: LightList = Default::default();
+        lights.add(Arc::new(DirectionalLight::new(
+            Vec3::new(-0.6, -1.0, -0.4),
+            Vec3::new(3.0, 2.9, 2.7),
         )));
-        world.add(Arc::new(AARect::new(
-            Plane::XZ,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            555.0,
+        (world, lights)
+    }
+
+    // A glass ball of milky water in the Cornell box, all of it in a thin fog
+    pub fn cornell_with_fog() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let mut lights: LightList = Default::default();
+        let white = Self::cornell_walls(&mut world, &mut lights);
+
+        let mut box1: Arc<dyn Hittable + Sync + Send> = Arc::new(Box::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(165.0, 330.0, 165.0),
             Arc::clone(&white),
+        ));
+        box1 = Arc::new(YRotate::new(box1, 15.0));
+        box1 = Arc::new(Translate::new(box1, Vec3::new(265.0, 0.0, 295.0)));
+        world.add(box1);
+
+        let glass: Arc<dyn Hittable + Sync + Send> = Arc::new(Sphere::new(
+            Vec3::new(190.0, 90.0, 190.0),
+            90.0,
+            Arc::new(Dielectric::new(1.33)),
+        ));
+        world.add(Arc::new(Interior::from_color(
+            glass,
+            0.02,
+            Vec3::new(0.5, 0.75, 0.9),
         )));
-        world.add(Arc::new(AARect::new(
-            Plane::XY,
-            0.0,
-            555.0,
-            0.0,
-            555.0,
-            555.0,
-            Arc::clone(&white),
+
+        // Fills the room and the space up to the camera, unbounded so kept out of the BVH
+        world.add(Arc::new(ConstantMedium::global(
+            0.0005,
+            Vec3::new(1.0, 1.0, 1.0),
         )));
 
+        (world, lights)
+    }
+
+    pub fn cornell_with_mesh(big_mesh: bool) -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let mut lights: LightList = Default::default();
+        let white = Self::cornell_walls(&mut world, &mut lights);
+
         // Hand-waivy location core
         let mut first_loc = (Vec3::new(0.0, 0.0, 0.0) + Vec3::new(165.0, 165.0, 165.0)) / 2.0;
         first_loc += Vec3::new(280.0, 165.0 / 2.0 - 35.0, 220.0);
@@ -375,28 +391,40 @@ impl Scene {
         second_loc += Vec3::new(130.0, 0.0, 65.0);
         second_loc += Vec3::new(-70.0, 30.0, -40.0);
 
-        let mut bunny_mesh: Arc<dyn Hittable> = Arc::new(Mesh::new(
-            "static/stanford_bunny.obj",
-            Arc::clone(&white),
-            Vec3::new(1000.0, 1000.0, 1000.0),
-        ));
+        let mut bunny_mesh: Arc<dyn Hittable> = Arc::new(
+            Mesh::new(
+                "static/stanford_bunny.obj",
+                Arc::clone(&white),
+                Vec3::new(1000.0, 1000.0, 1000.0),
+                Precision::Double,
+            )
+            .expect("Failed to load the bunny mesh"),
+        );
 
         bunny_mesh = Arc::new(YRotate::new(bunny_mesh, 210.0));
         bunny_mesh = Arc::new(Translate::new(bunny_mesh, first_loc));
 
-        let mut second_mesh: Arc<dyn Hittable> = Arc::new(Mesh::new(
-            "static/monkey.obj",
-            Arc::clone(&white),
-            Vec3::new(85.0, 85.0, 85.0),
-        ));
+        let mut second_mesh: Arc<dyn Hittable> = Arc::new(
+            Mesh::new(
+                "static/monkey.obj",
+                Arc::clone(&white),
+                Vec3::new(85.0, 85.0, 85.0),
+                Precision::Double,
+            )
+            .expect("Failed to load the monkey mesh"),
+        );
 
         // Just read the Ajax mesh, although it is quite big...
         if big_mesh {
-            second_mesh = Arc::new(Mesh::new(
-                "static/ajax.obj",
-                Arc::clone(&white),
-                Vec3::new(100.0, 100.0, 100.0),
-            ));
+            second_mesh = Arc::new(
+                Mesh::new(
+                    "static/ajax.obj",
+                    Arc::clone(&white),
+                    Vec3::new(100.0, 100.0, 100.0),
+                    Precision::Single,
+                )
+                .expect("Failed to load the ajax mesh"),
+            );
         }
 
         second_mesh = Arc::new(YRotate::new(second_mesh, 150.0));
@@ -415,6 +443,497 @@ impl Scene {
         box2 = Arc::new(Translate::new(box2, Vec3::new(320.0, 0.0, 240.0)));
         world.add(box2);
 
+        (world, lights)
+    }
+
+    // Boxes lit by a lamp, all of their materials read from the MTL of the OBJ
+    pub fn obj_still_life() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let mut lights: LightList = Default::default();
+        let grey: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(0.5, 0.5, 0.5)));
+        // The floor of the file is swapped for a marble one
+        let marble: Arc<dyn Texture + Sync + Send> = Arc::new(NoiseTexture::new(2.0));
+        let mut overrides: HashMap<String, Arc<dyn Material + Sync + Send>> = HashMap::new();
+        overrides.insert(
+            "floor".to_string(),
+            Arc::new(Lambertian::from_texture(marble)),
+        );
+
+        let still_life = Mesh::with_materials(
+            "static/still_life.obj",
+            grey,
+            &overrides,
+            Vec3::new(1.0, 1.0, 1.0),
+            Precision::Double,
+        )
+        .expect("Failed to load the still life mesh");
+        // The lamp is emissive in the MTL
+        lights.extend(still_life.triangles.area_lights());
+        world.add(Arc::new(still_life));
+
+        (world, lights)
+    }
+
+    // A copper torus of quads, read from a binary PLY
+    pub fn ply_torus() -> HittableList {
+        let mut world: HittableList = Default::default();
+        let checker: Arc<dyn Texture + Sync + Send> = Arc::new(CheckerTexture::from_color(
+            Vec3::new(0.2, 0.3, 0.1),
+            Vec3::new(0.9, 0.9, 0.9),
+        ));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::from_texture(checker)),
+        )));
+
+        let mut torus: Arc<dyn Hittable> = Arc::new(
+            Mesh::from_ply(
+                "static/torus.ply",
+                // Measured copper instead of a Schlick tint
+                Arc::new(MetallicRoughness::conductor(
+                    Vec3::new(0.200, 0.924, 1.102),
+                    Vec3::new(3.912, 2.452, 2.142),
+                    0.3,
+                )),
+                Vec3::new(1.0, 1.0, 1.0),
+                Precision::Double,
+            )
+            .expect("Failed to load the torus mesh"),
+        );
+        torus = Arc::new(YRotate::new(torus, 30.0));
+        torus = Arc::new(Translate::new(torus, Vec3::new(0.0, 0.4, 0.0)));
+        world.add(torus);
+        world
+    }
+
+    // A faceted glass gem from a binary STL, the facets are kept flat
+    pub fn stl_gem() -> HittableList {
+        let mut world: HittableList = Default::default();
+        let checker: Arc<dyn Texture + Sync + Send> = Arc::new(CheckerTexture::from_color(
+            Vec3::new(0.2, 0.3, 0.1),
+            Vec3::new(0.9, 0.9, 0.9),
+        ));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::from_texture(checker)),
+        )));
+
+        let mut gem: Arc<dyn Hittable> = Arc::new(
+            Mesh::from_stl(
+                "static/gem.stl",
+                Arc::new(Dielectric::new(2.4)),
+                Vec3::new(1.0, 1.0, 1.0),
+                Precision::Double,
+            )
+            .expect("Failed to load the gem mesh"),
+        );
+        gem = Arc::new(Translate::new(gem, Vec3::new(0.0, 1.0, 0.0)));
+        world.add(gem);
+        world
+    }
+
+    // Boxes under a lamp, laid out by the node hierarchy of a glTF file with its own camera
+    pub fn gltf_room() -> GltfScene {
+        load_gltf("static/room.gltf", Precision::Double).expect("Failed to load the glTF room")
+    }
+
+    // Alpha cards: a bush of masked leaves in one mesh, a fence of boards, a blended net
+    // curtain and a sphere cut into a lattice by a checker
+    pub fn foliage() -> GltfScene {
+        let mut garden = load_gltf("static/foliage.gltf", Precision::Single)
+            .expect("Failed to load the glTF garden");
+        let lattice = Cutout::new(
+            Arc::new(Sphere::new(
+                Vec3::new(0.2, 0.6, 0.2),
+                0.6,
+                Arc::new(Lambertian::from_color(Vec3::new(0.8, 0.3, 0.2))),
+            )),
+            Arc::new(UvCheckerTexture::from_color(
+                Vec3::new(1.0, 1.0, 1.0),
+                Vec3::new(0.0, 0.0, 0.0),
+                12.0,
+                6.0,
+            )),
+        )
+        .with_mode(AlphaMode::Cutoff(0.5));
+        garden.world.add(Arc::new(lattice));
+        garden
+    }
+
+    // A tiled floor running to the horizon, trilinear filtering on the left half and EWA
+    // on the right, which stays sharp further out
+    pub fn grazing_floor() -> HittableList {
+        let mut world: HittableList = Default::default();
+        let earth = TextureCache::shared()
+            .load("static/earthmap.jpg", ColorEncoding::Srgb)
+            .expect("image not found");
+        let filters = [
+            (-60.0, 0.0, TextureFilter::Trilinear),
+            (0.0, 60.0, TextureFilter::Ewa),
+        ];
+        for (x0, x1, filter) in filters {
+            // Mirrored so the tiles meet without seams
+            let image = earth
+                .clone()
+                .with_filter(filter)
+                .with_wrap(WrapMode::Mirror);
+            let tiled: Arc<dyn Texture + Sync + Send> =
+                Arc::new(UvTransform::new(Arc::new(image)).with_scale(30.0, 120.0));
+            world.add(Arc::new(AARect::new(
+                Plane::XZ,
+                x0,
+                x1,
+                -240.0,
+                0.0,
+                0.0,
+                Arc::new(Lambertian::from_texture(tiled)),
+            )));
+        }
+        world
+    }
+
+    // Bump mapped spheres under a low sun: stucco from granite, hammered steel from
+    // noise and quilted tiles from a uv checker, on a floor of raised tiles
+    pub fn bumpy_spheres() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let tiles = UvTransform::new(Arc::new(UvCheckerTexture::from_color(
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            1.0,
+            1.0,
+        )))
+        .with_scale(24.0, 24.0);
+        world.add(Arc::new(AARect::new(
+            Plane::XZ,
+            -12.0,
+            12.0,
+            -12.0,
+            12.0,
+            0.0,
+            Arc::new(
+                BumpMapped::new(
+                    Arc::new(Lambertian::from_color(Vec3::new(0.6, 0.55, 0.5))),
+                    Arc::new(tiles),
+                )
+                .with_scale(0.01),
+            ),
+        )));
+
+        let stucco = BumpMapped::new(
+            Arc::new(Lambertian::from_color(Vec3::new(0.85, 0.8, 0.7))),
+            Arc::new(GraniteTexture::new(12.0, 3)),
+        )
+        .with_scale(0.03);
+        let hammered = BumpMapped::new(
+            Arc::new(Metal::from_color(Vec3::new(0.8, 0.8, 0.85), 0.05)),
+            Arc::new(NoiseTexture::new(6.0)),
+        )
+        .with_scale(0.02);
+        let quilted = BumpMapped::new(
+            Arc::new(Lambertian::from_color(Vec3::new(0.3, 0.45, 0.7))),
+            Arc::new(UvCheckerTexture::from_color(
+                Vec3::new(1.0, 1.0, 1.0),
+                Vec3::new(0.0, 0.0, 0.0),
+                16.0,
+                8.0,
+            )),
+        )
+        .with_scale(-0.02);
+        let spheres: [(f64, Arc<dyn Material + Sync + Send>); 3] = [
+            (-2.5, Arc::new(stucco)),
+            (0.0, Arc::new(hammered)),
+            (2.5, Arc::new(quilted)),
+        ];
+        for (x, material) in spheres {
+            world.add(Arc::new(Sphere::new(Vec3::new(x, 1.0, 0.0), 1.0, material)));
+        }
+
+        let mut lights: LightList = Default::default();
+        lights.add(Arc::new(DirectionalLight::new(
+            Vec3::new(-1.0, -0.5, -0.6),
+            Vec3::new(3.0, 2.8, 2.5),
+        )));
+        (world, lights)
+    }
+
+    // Three 400 W panels over spheres, their spot cones narrowing from left to right
+    pub fn spot_panels() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let mut lights: LightList = Default::default();
+        let grey: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(0.6, 0.6, 0.6)));
+        world.add(Arc::new(AARect::new(
+            Plane::XZ,
+            -8.0,
+            8.0,
+            -4.0,
+            8.0,
+            0.0,
+            Arc::clone(&grey),
+        )));
+        world.add(Arc::new(AARect::new(
+            Plane::XY,
+            -8.0,
+            8.0,
+            0.0,
+            6.0,
+            -4.0,
+            Arc::clone(&grey),
+        )));
+
+        let warm = Vec3::new(1.0, 0.85, 0.6);
+        for (x, angle, falloff_start) in [(-3.0, 60.0, 40.0), (0.0, 35.0, 20.0), (3.0, 20.0, 5.0)] {
+            let (half, height) = (0.25, 4.0);
+            let panel = AARect::new(
+                Plane::XZ,
+                x - half,
+                x + half,
+                -half,
+                half,
+                height,
+                Arc::new(
+                    DiffuseLight::from_watts(warm, 400.0, 4.0 * half * half, true)
+                        .with_spot(angle, falloff_start),
+                ),
+            );
+            lights.extend(panel.area_lights());
+            world.add(Arc::new(panel));
+            world.add(Arc::new(Sphere::new(
+                Vec3::new(x, 0.6, 0.0),
+                0.6,
+                Arc::new(Lambertian::from_color(Vec3::new(0.8, 0.8, 0.8))),
+            )));
+        }
+        (world, lights)
+    }
+
+    // Textures put together from the operators: a turned checker floor, recolored granite,
+    // wood and marble by a checker mask, a cloudy earth and a triplanar box
+    pub fn texture_operators() -> HittableList {
+        let mut world: HittableList = Default::default();
+        let white = Vec3::new(0.9, 0.9, 0.9);
+        let grey = Vec3::new(0.3, 0.3, 0.3);
+
+        let floor = UvTransform::new(Arc::new(UvCheckerTexture::from_color(
+            white, grey, 1.0, 1.0,
+        )))
+        .with_scale(30.0, 30.0)
+        .with_rotation(30.0)
+        .with_offset(0.5, 0.0);
+        world.add(Arc::new(AARect::new(
+            Plane::XZ,
+            -15.0,
+            15.0,
+            -15.0,
+            15.0,
+            0.0,
+            Arc::new(Lambertian::from_texture(Arc::new(floor))),
+        )));
+
+        let coral = RampTexture::new(
+            Arc::new(GraniteTexture::new(6.0, 5)),
+            ColorRamp::new(&[
+                (0.0, Vec3::new(0.05, 0.2, 0.25)),
+                (0.5, Vec3::new(0.9, 0.4, 0.3)),
+                (1.0, Vec3::new(1.0, 0.9, 0.7)),
+            ]),
+        );
+        // Marble on the white squares, wood on the others
+        let mask = InvertTexture::new(Arc::new(UvCheckerTexture::from_color(
+            white, grey, 8.0, 4.0,
+        )));
+        let inlay = MixTexture::new(
+            Arc::new(WoodTexture::new(4.0, 6)),
+            Arc::new(MarbleTexture::new(2.0, 7)),
+            Arc::new(mask),
+        );
+        // A quarter turn of the globe, in the shade of the clouds
+        let earth = UvTransform::new(Arc::new(
+            ImageTexture::from_file("static/earthmap.jpg", ColorEncoding::Srgb)
+                .expect("image not found"),
+        ))
+        .with_offset(0.25, 0.0);
+        let shade = AddTexture::new(
+            Arc::new(SolidColor::from_rgb(0.4, 0.4, 0.4)),
+            Arc::new(MultiplyTexture::new(
+                Arc::new(CloudsTexture::new(2.0, 8)),
+                Arc::new(SolidColor::from_rgb(0.6, 0.6, 0.6)),
+            )),
+        );
+        let cloudy_earth = MultiplyTexture::new(Arc::new(earth), Arc::new(shade));
+
+        let textures: [Arc<dyn Texture + Sync + Send>; 3] =
+            [Arc::new(coral), Arc::new(inlay), Arc::new(cloudy_earth)];
+        for (i, texture) in textures.into_iter().enumerate() {
+            world.add(Arc::new(Sphere::new(
+                Vec3::new(-3.3 + 2.2 * i as f64, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::from_texture(texture)),
+            )));
+        }
+
+        // A box has no uv that runs across its faces, the checker is projected instead
+        let projected = TriplanarTexture::new(
+            Arc::new(UvCheckerTexture::from_color(
+                Vec3::new(0.8, 0.6, 0.2),
+                Vec3::new(0.2, 0.1, 0.05),
+                1.0,
+                1.0,
+            )),
+            2.0,
+        )
+        .with_sharpness(8.0);
+        let mut cube: Arc<dyn Hittable> = Arc::new(Box::new_triangles(
+            Vec3::new(-0.8, 0.0, -0.8),
+            Vec3::new(0.8, 1.6, 0.8),
+            Arc::new(Lambertian::from_texture(Arc::new(projected))),
+        ));
+        cube = Arc::new(YRotate::new(cube, 35.0));
+        cube = Arc::new(Translate::new(cube, Vec3::new(3.3, 0.0, 0.0)));
+        world.add(cube);
+        world
+    }
+
+    // A row of spheres showing off the lobes of the principled material
+    pub fn principled_spheres() -> HittableList {
+        let mut world: HittableList = Default::default();
+        let checker: Arc<dyn Texture + Sync + Send> = Arc::new(CheckerTexture::from_color(
+            Vec3::new(0.2, 0.2, 0.2),
+            Vec3::new(0.8, 0.8, 0.8),
+        ));
+        let constant =
+            |v: f64| -> Arc<dyn Texture + Sync + Send> { Arc::new(SolidColor::from_rgb(v, v, v)) };
+        // Tiles with patches of rough metal showing through
+        let mut worn = Principled::new(Vec3::new(0.8, 0.8, 0.8));
+        worn.metallic = constant(1.0);
+        worn.roughness = constant(0.4);
+        let mask: Arc<dyn Texture + Sync + Send> = Arc::new(NoiseTexture::new(2.0));
+        let floor = MixMaterial::new(
+            Arc::new(Principled::from_texture(checker)),
+            Arc::new(worn),
+            mask,
+        );
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(floor),
+        )));
+
+        let plastic = Principled::new(Vec3::new(0.1, 0.3, 0.8));
+
+        let mut gold = Principled::new(Vec3::new(1.0, 0.71, 0.29));
+        gold.metallic = constant(1.0);
+        gold.roughness = constant(0.3);
+
+        let mut car_paint = Principled::new(Vec3::new(0.6, 0.02, 0.02));
+        car_paint.clearcoat = constant(1.0);
+
+        let mut glass = Principled::new(Vec3::new(0.9, 1.0, 0.95));
+        glass.transmission = constant(1.0);
+        glass.roughness = constant(0.0);
+
+        let mut skin = Principled::new(Vec3::new(0.9, 0.6, 0.5));
+        skin.subsurface = constant(1.0);
+        skin.roughness = constant(0.7);
+
+        let mut velvet = Principled::new(Vec3::new(0.3, 0.05, 0.3));
+        velvet.sheen = Arc::new(SolidColor::from_rgb(1.0, 0.6, 1.0));
+        velvet.roughness = constant(1.0);
+
+        let materials = [plastic, gold, car_paint, glass, skin, velvet];
+        for (i, mat) in materials.into_iter().enumerate() {
+            world.add(Arc::new(Sphere::new(
+                Vec3::new(-5.0 + 2.0 * i as f64, 0.9, 0.0),
+                0.9,
+                Arc::new(mat),
+            )));
+        }
+        world
+    }
+
+    // Spheres under analytic lights only, a warm point light, a spot, a dim moonlight and
+    // a measured IES fixture
+    pub fn lamp_lit_spheres() -> (HittableList, LightList) {
+        let mut world: HittableList = Default::default();
+        let floor: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(0.6, 0.6, 0.6)));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            floor,
+        )));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(-2.5, 1.0, 0.0),
+            1.0,
+            Arc::new(Lambertian::from_color(Vec3::new(0.8, 0.2, 0.2))),
+        )));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, 1.0, 0.0),
+            1.0,
+            Arc::new(MetallicRoughness::new(Vec3::new(0.9, 0.9, 0.9), 1.0, 0.3)),
+        )));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(2.5, 1.0, 0.0),
+            1.0,
+            Arc::new(Principled::new(Vec3::new(0.2, 0.3, 0.8))),
+        )));
+
+        let mut lights: LightList = Default::default();
+        lights.add(Arc::new(PointLight::new(
+            Vec3::new(-4.0, 4.0, 3.0),
+            Vec3::new(30.0, 24.0, 16.0),
+        )));
+        lights.add(Arc::new(SpotLight::new(
+            Vec3::new(3.0, 6.0, 2.0),
+            Vec3::new(2.5, 0.0, 0.0),
+            Vec3::new(60.0, 60.0, 60.0),
+            25.0,
+            15.0,
+        )));
+        lights.add(Arc::new(DirectionalLight::new(
+            Vec3::new(1.0, -1.0, -0.5),
+            Vec3::new(0.1, 0.12, 0.2),
+        )));
+        // Measured fixture behind the spheres, washing the floor in an uneven pattern
+        lights.add(Arc::new(
+            IesLight::from_file(
+                "static/wall_washer.ies",
+                Vec3::new(0.0, 4.0, -3.0),
+                Vec3::new(0.0, -1.0, 0.0),
+                Vec3::new(0.04, 0.04, 0.03),
+            )
+            .expect("Couldn't load the IES profile"),
+        ));
+        // Only a few lights, picking them by their power alone is enough
+        (world, lights.with_sampling(LightSampling::Power))
+    }
+
+    // The procedural noise textures on a row of spheres, a cloudy dome above them
+    pub fn procedural_textures() -> HittableList {
+        let mut world: HittableList = Default::default();
+        let ground: Arc<dyn Texture + Sync + Send> = Arc::new(WoodTexture::new(0.5, 7));
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::from_texture(ground)),
+        )));
+
+        let textures: [Arc<dyn Texture + Sync + Send>; 4] = [
+            Arc::new(MarbleTexture::new(1.5, 1)),
+            Arc::new(WoodTexture::new(3.0, 2)),
+            Arc::new(GraniteTexture::new(12.0, 3)),
+            Arc::new(CloudsTexture::new(1.5, 4).with_coverage(0.6)),
+        ];
+        for (i, texture) in textures.into_iter().enumerate() {
+            world.add(Arc::new(Sphere::new(
+                Vec3::new(-3.3 + 2.2 * i as f64, 1.0, 0.0),
+                1.0,
+                Arc::new(Lambertian::from_texture(texture)),
+            )));
+        }
         world
     }
 }
@@ -19,31 +19,44 @@ impl Translate {
     pub fn new(obj_ptr: Arc<dyn Hittable>, offset: Vec3) -> Self {
         Self { obj_ptr, offset }
     }
+
+    #[inline]
+    fn to_object(&self, r: Ray) -> Ray {
+        Ray::new(r.orig - self.offset, r.dir)
+    }
 }
 
 impl Hittable for Translate {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let moved_r = Ray::new(r.orig - self.offset, r.dir);
+        let moved_r = self.to_object(r);
 
         if let Some(mut hit) = self.obj_ptr.hit(moved_r, t_min, t_max) {
             hit.p += self.offset;
-            hit.set_face_normal(moved_r, hit.normal);
+            // Adding the offset rounds once more
+            hit.p_error += hit.p.abs() * gamma(1);
+            // Normals are unaffected by a translation
             Some(hit)
         } else {
             None
         }
     }
 
-    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
-        if !self.obj_ptr.bounding_box(time0, time1, output_box) {
-            return false;
-        }
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb> {
+        self.obj_ptr
+            .bounding_box(time0, time1)
+            .map(|bbox| Aabb::new(bbox.minimum + self.offset, bbox.maximum + self.offset))
+    }
+    // Media inside are moved with the rays as well
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        self.obj_ptr.transmittance(self.to_object(r), t_min, t_max)
+    }
+
+    fn medium_weight(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        self.obj_ptr.medium_weight(self.to_object(r), t_min, t_max)
+    }
 
-        *output_box = Aabb {
-            minimum: output_box.minimum + self.offset,
-            maximum: output_box.maximum + self.offset,
-        };
-        true
+    fn has_media(&self) -> bool {
+        self.obj_ptr.has_media()
     }
 }
 
@@ -51,7 +64,7 @@ pub struct YRotate {
     sin_theta: f64,
     cos_theta: f64,
     obj_ptr: Arc<dyn Hittable>,
-    bbox: Aabb,
+    bbox: Option<Aabb>,
 }
 
 impl YRotate {
@@ -59,36 +72,26 @@ impl YRotate {
         let rads = degrees_to_radians(angle);
         let sin_theta = f64::sin(rads);
         let cos_theta = f64::cos(rads);
-        let mut bbox = Default::default();
-        // TODO: Change the API to return Optional<Aabb>
-        let _ = obj_ptr.bounding_box(0.0, 0.0, &mut bbox);
+        let bbox = obj_ptr.bounding_box(0.0, 0.0).map(|bbox| {
+            let mut rotated = Aabb::empty();
 
-        let mut min = Vec3::new(f64::MAX, f64::MAX, f64::MAX);
-        let mut max = Vec3::new(f64::MIN, f64::MIN, f64::MIN);
+            for i in 0..2 {
+                for j in 0..2 {
+                    for k in 0..2 {
+                        let x = i as f64 * bbox.maximum.x + (1 - i) as f64 * bbox.minimum.x;
+                        let y = j as f64 * bbox.maximum.y + (1 - j) as f64 * bbox.minimum.y;
+                        let z = k as f64 * bbox.maximum.z + (1 - k) as f64 * bbox.minimum.z;
 
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
-                    let x = i as f64 * bbox.maximum.x + (1 - i) as f64 * bbox.minimum.x;
-                    let y = j as f64 * bbox.maximum.y + (1 - j) as f64 * bbox.minimum.y;
-                    let z = k as f64 * bbox.maximum.z + (1 - k) as f64 * bbox.minimum.z;
+                        let newx = cos_theta * x + sin_theta * z;
+                        let newz = -sin_theta * x + cos_theta * z;
 
-                    let newx = cos_theta * x + sin_theta * z;
-                    let newz = -sin_theta * x + cos_theta * z;
-
-                    let tester = Vec3::new(newx, y, newz);
-
-                    for c in 0..3 {
-                        min[c] = f64::min(min[c], tester[c]);
-                        max[c] = f64::max(max[c], tester[c]);
+                        let tester = Vec3::new(newx, y, newz);
+                        rotated = rotated.union(Aabb::new(tester, tester));
                     }
                 }
             }
-        }
-        bbox = Aabb {
-            minimum: min,
-            maximum: max,
-        };
+            rotated
+        });
 
         Self {
             sin_theta,
@@ -97,10 +100,9 @@ impl YRotate {
             bbox,
         }
     }
-}
 
-impl Hittable for YRotate {
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    #[inline]
+    fn to_object(&self, r: Ray) -> Ray {
         let mut origin = r.orig;
         let mut direction = r.dir;
 
@@ -110,27 +112,56 @@ impl Hittable for YRotate {
         direction[0] = self.cos_theta * r.dir[0] - self.sin_theta * r.dir[2];
         direction[2] = self.sin_theta * r.dir[0] + self.cos_theta * r.dir[2];
 
-        let rotated_r = Ray::new(origin, direction);
-
-        if let Some(mut hit) = self.obj_ptr.hit(rotated_r, t_min, t_max) {
-            let mut p = hit.p;
-            let mut normal = hit.normal;
-            p[0] = self.cos_theta * hit.p[0] + self.sin_theta * hit.p[2];
-            p[2] = -self.sin_theta * hit.p[0] + self.cos_theta * hit.p[2];
+        Ray::new(origin, direction)
+    }
+}
 
-            normal[0] = self.cos_theta * hit.normal[0] + self.sin_theta * hit.normal[2];
-            normal[2] = -self.sin_theta * hit.normal[0] + self.cos_theta * hit.normal[2];
+impl Hittable for YRotate {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let rotated_r = self.to_object(r);
 
-            hit.p = p;
-            hit.set_face_normal(rotated_r, normal);
+        if let Some(mut hit) = self.obj_ptr.hit(rotated_r, t_min, t_max) {
+            // Rotate back into world space, facing of the normals is preserved
+            let rotate = |v: Vec3| {
+                Vec3::new(
+                    self.cos_theta * v[0] + self.sin_theta * v[2],
+                    v[1],
+                    -self.sin_theta * v[0] + self.cos_theta * v[2],
+                )
+            };
+            // Propagate the error through the rotation (PBRT 3.9.3)
+            let rotate_abs = |v: Vec3| {
+                Vec3::new(
+                    self.cos_theta.abs() * v.x + self.sin_theta.abs() * v.z,
+                    v.y,
+                    self.sin_theta.abs() * v.x + self.cos_theta.abs() * v.z,
+                )
+            };
+            hit.p_error =
+                rotate_abs(hit.p_error) * (1.0 + gamma(3)) + rotate_abs(hit.p.abs()) * gamma(3);
+            hit.p = rotate(hit.p);
+            hit.normal = rotate(hit.normal);
+            hit.geometric_normal = rotate(hit.geometric_normal);
+            hit.dpdu = rotate(hit.dpdu);
+            hit.dpdv = rotate(hit.dpdv);
 
             Some(hit)
         } else {
             None
         }
     }
-    fn bounding_box(&self, _: f64, _: f64, output_box: &mut Aabb) -> bool {
-        *output_box = self.bbox;
-        true
+    fn bounding_box(&self, _: f64, _: f64) -> Option<Aabb> {
+        self.bbox
+    }
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        self.obj_ptr.transmittance(self.to_object(r), t_min, t_max)
+    }
+
+    fn medium_weight(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        self.obj_ptr.medium_weight(self.to_object(r), t_min, t_max)
+    }
+
+    fn has_media(&self) -> bool {
+        self.obj_ptr.has_media()
     }
 }
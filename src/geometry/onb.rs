@@ -24,6 +24,11 @@ impl Onb {
         self.u() * a.x + self.v() * a.y + self.w() * a.z
     }
 
+    // World to basis coordinates, the inverse of `local`
+    pub fn to_local(self, a: Vec3) -> Vec3 {
+        Vec3::new(a.dot(self.u()), a.dot(self.v()), a.dot(self.w()))
+    }
+
     pub fn build_from_w(w: Vec3) -> Self {
         let unit_w = Vec3::unit_vector(w);
         let a = if f64::abs(unit_w.x) > 0.9 {
@@ -38,6 +43,20 @@ impl Onb {
             axis: [u, v, unit_w],
         }
     }
+
+    // Basis with w along `w` and u along the part of `u` perpendicular to it, any u when
+    // `u` has no such part
+    pub fn build_from_wu(w: Vec3, u: Vec3) -> Self {
+        let unit_w = Vec3::unit_vector(w);
+        let perpendicular = u - unit_w * u.dot(unit_w);
+        if perpendicular.lenght_squared() <= 1e-12 * u.lenght_squared() {
+            return Self::build_from_w(w);
+        }
+        let unit_u = Vec3::unit_vector(perpendicular);
+        Self {
+            axis: [unit_u, unit_w.cross(unit_u), unit_w],
+        }
+    }
 }
 
 impl Index<usize> for Onb {
@@ -1,4 +1,5 @@
 use crate::geometry::vector3::*;
+use crate::utils::*;
 
 #[derive(Copy, Clone)]
 pub struct Ray {
@@ -17,3 +18,33 @@ impl Ray {
         self.orig + self.dir * t
     }
 }
+
+// Rays through the neighbouring pixels to the right (x) and below (y), for texture filtering
+#[derive(Copy, Clone)]
+pub struct RayDifferential {
+    pub rx_origin: Vec3,
+    pub rx_dir: Vec3,
+    pub ry_origin: Vec3,
+    pub ry_dir: Vec3,
+}
+
+/*
+ *  Moves a ray origin off the surface along the geometric normal, far enough to be
+ *  outside the floating point error box `p_error` of the intersection point, and
+ *  towards the side the new direction `w` is leaving from. (PBRT 3.9.5)
+ */
+pub fn offset_ray_origin(p: Vec3, p_error: Vec3, n: Vec3, w: Vec3) -> Vec3 {
+    let d = n.abs().dot(p_error);
+    let n = if w.dot(n) < 0.0 { -n } else { n };
+    let mut po = p + n * d;
+
+    // Round away from p so the offset point can't land back inside the error box
+    for i in 0..3 {
+        if n[i] > 0.0 {
+            po[i] = next_float_up(po[i]);
+        } else if n[i] < 0.0 {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+    po
+}
@@ -39,6 +39,33 @@ impl Vec3 {
         v / (v.length())
     }
     #[inline]
+    pub fn abs(self) -> Vec3 {
+        Vec3::new(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+    #[inline]
+    pub fn max_component(self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+    // Index of the largest component
+    #[inline]
+    pub fn max_dimension(self) -> usize {
+        if self.x > self.y {
+            if self.x > self.z {
+                0
+            } else {
+                2
+            }
+        } else if self.y > self.z {
+            1
+        } else {
+            2
+        }
+    }
+    #[inline]
+    pub fn permute(self, x: usize, y: usize, z: usize) -> Vec3 {
+        Vec3::new(self[x], self[y], self[z])
+    }
+    #[inline]
     pub fn near_zero(self) -> bool {
         let s = 1e-8;
         self.x.abs() < s && self.y.abs() < s && self.z.abs() < s
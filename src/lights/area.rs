@@ -0,0 +1,157 @@
+/*
+ *  Emissive triangles as lights, so the integrator samples them instead of waiting for
+ *  the scattered rays to find them. The triangle is still in the world on its own, this
+ *  is only the light side of it and has to use the same vertices.
+ */
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::accelerators::aabb::*;
+use crate::core::hittable::*;
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+use crate::lights::{light::*, light_bvh::*};
+use crate::material::*;
+use crate::microfacet::luminance;
+use crate::primitives::triangle::*;
+
+pub struct TriangleLight {
+    verts: [Vec3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: Option<[(f64, f64); 3]>,
+    mat_ptr: Arc<dyn Material + Sync + Send>,
+    area: f64,
+}
+
+impl TriangleLight {
+    pub fn new(
+        verts: [Vec3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: Option<[(f64, f64); 3]>,
+        mat_ptr: Arc<dyn Material + Sync + Send>,
+    ) -> Self {
+        let [a, b, c] = verts;
+        Self {
+            verts,
+            normals,
+            uvs,
+            mat_ptr,
+            area: (b - a).cross(c - a).length() / 2.0,
+        }
+    }
+
+    // The hit a ray from `origin` would make at the barycentrics, emission included
+    fn hit_from(&self, origin: Vec3, bary: [f64; 3]) -> Option<(Ray, HitRecord)> {
+        let [a, b, c] = self.verts;
+        let point = a * bary[0] + b * bary[1] + c * bary[2];
+        let to_light = point - origin;
+        if to_light.lenght_squared() == 0.0 {
+            return None;
+        }
+        let r = Ray::new(origin, to_light);
+        let hit = triangle_hit_record(
+            r,
+            self.verts,
+            self.normals,
+            self.uvs,
+            1.0,
+            bary,
+            &self.mat_ptr,
+        );
+        Some((r, hit))
+    }
+
+    // Solid angle density of a point picked uniformly on the triangle, seen from r.orig
+    fn solid_angle_pdf(&self, r: Ray, hit: &HitRecord) -> f64 {
+        let distance2 = (hit.p - r.orig).lenght_squared();
+        let cos_light = hit.geometric_normal.dot(Vec3::unit_vector(r.dir)).abs();
+        if cos_light == 0.0 || self.area == 0.0 {
+            return 0.0;
+        }
+        distance2 / (cos_light * self.area)
+    }
+}
+
+impl Light for TriangleLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        // Uniform barycentrics
+        let mut rng = rand::thread_rng();
+        let su = f64::sqrt(rng.gen::<f64>());
+        let b0 = 1.0 - su;
+        let b1 = rng.gen::<f64>() * su;
+        let (r, hit) = self.hit_from(p, [b0, b1, 1.0 - b0 - b1])?;
+
+        let radiance = self.mat_ptr.emitted(r, &hit);
+        let pdf = self.solid_angle_pdf(r, &hit);
+        if pdf == 0.0 || radiance.max_component() <= 0.0 {
+            return None;
+        }
+        let distance = (hit.p - p).length();
+        Some(LightSample {
+            wi: (hit.p - p) / distance,
+            distance,
+            radiance,
+            pdf,
+            surface: Some(LightPoint {
+                p: hit.p,
+                n: hit.geometric_normal,
+                p_error: hit.p_error,
+            }),
+        })
+    }
+
+    fn pdf(&self, r: Ray, t_max: f64) -> f64 {
+        // A little slack for the world's copy of the triangle, which found t_max
+        let Some((_, bary)) = intersect_triangle(r, self.verts, 0.0, t_max * (1.0 + 1e-6)) else {
+            return 0.0;
+        };
+        match self.hit_from(r.orig, bary) {
+            Some((r, hit)) => self.solid_angle_pdf(r, &hit),
+            None => 0.0,
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let [a, b, c] = self.verts;
+        if self.area == 0.0 {
+            // Degenerate, emits nothing and stays out of the BVH
+            return Some(LightBounds {
+                bbox: Aabb::from_points(&self.verts),
+                phi: 0.0,
+                normals: DirectionCone::entire_sphere(),
+                cos_theta_e: 0.0,
+                two_sided: false,
+            });
+        }
+        // Emission at the center, as seen from the front and from the back
+        let center = [1.0 / 3.0; 3];
+        let normal = Vec3::unit_vector((b - a).cross(c - a));
+        let centroid = (a + b + c) / 3.0;
+        let seen_from = |side: f64| {
+            self.hit_from(centroid + normal * side, center)
+                .map_or(0.0, |(r, hit)| luminance(self.mat_ptr.emitted(r, &hit)))
+        };
+        let (front, back) = (seen_from(1.0), seen_from(-1.0));
+        let two_sided = front > 0.0 && back > 0.0;
+        // One-sided lights emit around the side they are seen from
+        let w = if front > 0.0 || two_sided {
+            normal
+        } else {
+            -normal
+        };
+
+        Some(LightBounds {
+            bbox: Aabb::from_points(&self.verts),
+            phi: PI * self.area * (front + back),
+            normals: DirectionCone::new(w, 1.0),
+            cos_theta_e: 0.0,
+            two_sided,
+        })
+    }
+}
@@ -0,0 +1,29 @@
+use crate::geometry::vector3::*;
+use crate::lights::light::*;
+
+// Parallel light from infinitely far away, like the sun
+pub struct DirectionalLight {
+    direction: Vec3,  // Direction the light travels in
+    irradiance: Vec3, // Arriving on a surface facing the light
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Vec3) -> Self {
+        Self {
+            direction: Vec3::unit_vector(direction),
+            irradiance,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _p: Vec3) -> Option<LightSample> {
+        Some(LightSample {
+            wi: -self.direction,
+            distance: f64::INFINITY,
+            radiance: self.irradiance,
+            pdf: 1.0,
+            surface: None,
+        })
+    }
+}
@@ -0,0 +1,227 @@
+/*
+ *  What rays that leave the scene see. A constant color or a gradient only light the
+ *  scene through the rays that happen to escape, an environment map is also a light
+ *  and should be added to the LightList too, so it gets sampled by its brightness.
+ */
+use std::f64::consts::PI;
+
+use image::DynamicImage;
+use rand::Rng;
+
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+use crate::lights::light::*;
+use crate::microfacet::luminance;
+use crate::sampling::*;
+use crate::texture::open_image;
+use crate::utils::srgb_to_linear;
+
+pub trait Background: Sync + Send {
+    // Radiance arriving from infinitely far away along the unit direction `dir`
+    fn radiance(&self, dir: Vec3) -> Vec3;
+}
+
+// The same color in every direction
+impl Background for Vec3 {
+    fn radiance(&self, _dir: Vec3) -> Vec3 {
+        *self
+    }
+}
+
+// Blends from the horizon color up to the zenith, flat below the horizon
+pub struct GradientSky {
+    zenith: Vec3,
+    horizon: Vec3,
+    ground: Vec3,
+}
+
+impl GradientSky {
+    pub fn new(zenith: Vec3, horizon: Vec3) -> Self {
+        Self {
+            zenith,
+            horizon,
+            ground: horizon,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ground(mut self, ground: Vec3) -> Self {
+        self.ground = ground;
+        self
+    }
+}
+
+impl Background for GradientSky {
+    fn radiance(&self, dir: Vec3) -> Vec3 {
+        if dir.y < 0.0 {
+            return self.ground;
+        }
+        self.horizon * (1.0 - dir.y) + self.zenith * dir.y
+    }
+}
+
+/*
+ *  Equirectangular (latitude-longitude) image around the scene, +Y up. The top row is
+ *  straight up and the middle column looks down -X before the rotation about +Y.
+ */
+pub struct EnvironmentMap {
+    pixels: Vec<Vec3>, // Linear radiance, row by row from the top
+    width: usize,
+    height: usize,
+    intensity: f64,
+    sin_rotation: f64,
+    cos_rotation: f64,
+    distribution: Distribution2D, // Over (u, v) of the image
+}
+
+impl EnvironmentMap {
+    // `rotation` in degrees turns the map about +Y, `intensity` scales its radiance
+    pub fn new(
+        pixels: Vec<Vec3>,
+        width: usize,
+        height: usize,
+        rotation: f64,
+        intensity: f64,
+    ) -> Self {
+        // The sine accounts for the rows getting squeezed towards the poles
+        let brightness: Vec<f64> = pixels
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| {
+                let theta = PI * ((i / width) as f64 + 0.5) / height as f64;
+                luminance(c) * theta.sin()
+            })
+            .collect();
+        let rotation = rotation.to_radians();
+        Self {
+            distribution: Distribution2D::new(&brightness, width, height),
+            pixels,
+            width,
+            height,
+            intensity,
+            sin_rotation: rotation.sin(),
+            cos_rotation: rotation.cos(),
+        }
+    }
+
+    // Radiance .hdr and OpenEXR files are linear, anything else is taken as sRGB
+    pub fn from_file(file: &str, rotation: f64, intensity: f64) -> image::ImageResult<Self> {
+        let to_vec3 = |p: &[f32]| Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64);
+
+        let image = open_image(file)?;
+        let linear = matches!(
+            image,
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+        );
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let pixels = image
+            .into_rgb32f()
+            .pixels()
+            .map(|p| {
+                let c = to_vec3(&p.0);
+                if linear {
+                    c
+                } else {
+                    Vec3::new(
+                        srgb_to_linear(c.x),
+                        srgb_to_linear(c.y),
+                        srgb_to_linear(c.z),
+                    )
+                }
+            })
+            .collect();
+        Ok(Self::new(pixels, width, height, rotation, intensity))
+    }
+
+    // Tabulates any background, so analytic ones can reuse the importance sampling
+    pub fn from_background(background: &dyn Background, width: usize, height: usize) -> Self {
+        let pixels = (0..width * height)
+            .map(|i| {
+                let u = ((i % width) as f64 + 0.5) / width as f64;
+                let v = ((i / width) as f64 + 0.5) / height as f64;
+                background.radiance(equirect_direction(u, v).0)
+            })
+            .collect();
+        Self::new(pixels, width, height, 0.0, 1.0)
+    }
+
+    // Image coordinates in [0, 1)^2 of a world direction
+    fn uv_of(&self, dir: Vec3) -> (f64, f64) {
+        // Undo the rotation about +Y
+        let x = self.cos_rotation * dir.x - self.sin_rotation * dir.z;
+        let z = self.sin_rotation * dir.x + self.cos_rotation * dir.z;
+        let phi = f64::atan2(-z, x) + PI;
+        let theta = dir.y.clamp(-1.0, 1.0).acos();
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    // World direction of image coordinates, with the sine of its polar angle
+    fn direction_at(&self, u: f64, v: f64) -> (Vec3, f64) {
+        let (d, sin_theta) = equirect_direction(u, v);
+        let dir = Vec3::new(
+            self.cos_rotation * d.x + self.sin_rotation * d.z,
+            d.y,
+            -self.sin_rotation * d.x + self.cos_rotation * d.z,
+        );
+        (dir, sin_theta)
+    }
+
+    fn lookup(&self, u: f64, v: f64) -> Vec3 {
+        let i = ((u * self.width as f64) as usize).min(self.width - 1);
+        let j = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[j * self.width + i] * self.intensity
+    }
+}
+
+impl Background for EnvironmentMap {
+    fn radiance(&self, dir: Vec3) -> Vec3 {
+        let (u, v) = self.uv_of(dir);
+        self.lookup(u, v)
+    }
+}
+
+impl Light for EnvironmentMap {
+    fn sample(&self, _p: Vec3) -> Option<LightSample> {
+        let mut rng = rand::thread_rng();
+        let (u, v, map_pdf) = self.distribution.sample(rng.gen(), rng.gen());
+        let (wi, sin_theta) = self.direction_at(u, v);
+        if map_pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            wi,
+            distance: f64::INFINITY,
+            radiance: self.lookup(u, v),
+            // From the image square to the sphere of directions
+            pdf: map_pdf / (2.0 * PI * PI * sin_theta),
+            surface: None,
+        })
+    }
+
+    fn pdf(&self, r: Ray, t_max: f64) -> f64 {
+        if t_max < f64::INFINITY {
+            return 0.0;
+        }
+        let (u, v) = self.uv_of(Vec3::unit_vector(r.dir));
+        let sin_theta = (PI * v).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+// Direction of image coordinates before any rotation, with the sine of its polar angle
+fn equirect_direction(u: f64, v: f64) -> (Vec3, f64) {
+    let phi = 2.0 * PI * u;
+    let theta = PI * v;
+    let sin_theta = theta.sin();
+    (
+        Vec3::new(-sin_theta * phi.cos(), theta.cos(), sin_theta * phi.sin()),
+        sin_theta,
+    )
+}
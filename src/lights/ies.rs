@@ -0,0 +1,294 @@
+/*
+ *  Point light with the measured angular distribution of a real luminaire, read from an
+ *  IES LM-63 photometric file. Only type C photometry (vertical angle from the nadir,
+ *  horizontal angle around it) is supported, which is what architectural fixtures use.
+ */
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind};
+
+use crate::accelerators::aabb::*;
+use crate::geometry::onb::*;
+use crate::geometry::vector3::*;
+use crate::lights::{light::*, light_bvh::*};
+use crate::microfacet::luminance;
+
+pub struct IesLight {
+    position: Vec3,
+    frame: Onb,                  // w is the nadir, u the zero horizontal angle
+    color: Vec3,                 // Scales the candela values to radiant intensity
+    vertical_angles: Vec<f64>,   // Degrees from the nadir, ascending
+    horizontal_angles: Vec<f64>, // Degrees around the nadir, ascending
+    candela: Vec<f64>,           // vertical_angles.len() values per horizontal angle
+}
+
+impl IesLight {
+    // `nadir` is the direction the fixture points at, straight down for a ceiling light
+    pub fn from_file(
+        file: &str,
+        position: Vec3,
+        nadir: Vec3,
+        color: Vec3,
+    ) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(file)?;
+        Self::parse(&text, position, nadir, color)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{file}: {e}")))
+    }
+
+    fn parse(text: &str, position: Vec3, nadir: Vec3, color: Vec3) -> Result<Self, String> {
+        // Keywords come first, the numbers start after the TILT line
+        let mut lines = text.lines();
+        let tilt = lines
+            .by_ref()
+            .find(|line| line.trim_start().starts_with("TILT="))
+            .ok_or("missing the TILT line")?;
+        let mut numbers = lines.flat_map(|line| {
+            line.split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|token| !token.is_empty())
+        });
+        let mut next = || -> Result<f64, String> {
+            let token = numbers.next().ok_or("unexpected end of file")?;
+            token
+                .parse::<f64>()
+                .map_err(|_| format!("bad number '{token}'"))
+        };
+
+        if tilt.trim() == "TILT=INCLUDE" {
+            // Lamp to luminaire geometry, then the angle and multiplier pairs, unused here
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..pairs * 2 {
+                next()?;
+            }
+        }
+
+        let _num_lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let num_vertical = next()? as usize;
+        let num_horizontal = next()? as usize;
+        let photometric_type = next()? as u32;
+        // Units, width, length, height, ballast factor, future use and input watts
+        for _ in 0..7 {
+            next()?;
+        }
+        if photometric_type != 1 {
+            return Err(format!("photometric type {photometric_type} isn't type C"));
+        }
+        if num_vertical == 0 || num_horizontal == 0 {
+            return Err("no angles".to_string());
+        }
+
+        let vertical_angles = (0..num_vertical)
+            .map(|_| next())
+            .collect::<Result<_, _>>()?;
+        let horizontal_angles = (0..num_horizontal)
+            .map(|_| next())
+            .collect::<Result<_, _>>()?;
+        let candela = (0..num_vertical * num_horizontal)
+            .map(|_| next().map(|c| c * multiplier))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            position,
+            frame: Onb::build_from_w(nadir),
+            color,
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    // Index of the segment `x` falls in and how far along it, clamped to the ends
+    fn locate(angles: &[f64], x: f64) -> (usize, f64) {
+        if angles.len() == 1 || x <= angles[0] {
+            return (0, 0.0);
+        }
+        let last = angles.len() - 1;
+        if x >= angles[last] {
+            return (last - 1, 1.0);
+        }
+        let i = angles.partition_point(|&a| a <= x) - 1;
+        (i, (x - angles[i]) / (angles[i + 1] - angles[i]))
+    }
+
+    // Bilinearly interpolated candela towards (vertical, horizontal) degrees
+    fn candela(&self, vertical: f64, horizontal: f64) -> f64 {
+        let first = self.vertical_angles[0];
+        let last = self.vertical_angles[self.vertical_angles.len() - 1];
+        if vertical < first || vertical > last {
+            return 0.0;
+        }
+
+        // Fold the horizontal angle into the range the file covers, by its symmetry
+        let h_last = self.horizontal_angles[self.horizontal_angles.len() - 1];
+        let mut h = horizontal.rem_euclid(360.0);
+        if h_last <= 180.0 && h > 180.0 {
+            h = 360.0 - h;
+        }
+        if h_last <= 90.0 && h > 90.0 {
+            h = 180.0 - h;
+        }
+
+        let nv = self.vertical_angles.len();
+        let value = |hi: usize, vi: usize| self.candela[hi * nv + vi];
+        let (vi, vt) = Self::locate(&self.vertical_angles, vertical);
+        let vj = (vi + 1).min(nv - 1);
+        let along_vertical = |hi: usize| value(hi, vi) * (1.0 - vt) + value(hi, vj) * vt;
+
+        if self.horizontal_angles.len() == 1 {
+            return along_vertical(0);
+        }
+        let (hi, ht) = Self::locate(&self.horizontal_angles, h);
+        along_vertical(hi) * (1.0 - ht) + along_vertical(hi + 1) * ht
+    }
+}
+
+impl Light for IesLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance2 = to_light.lenght_squared();
+        if distance2 == 0.0 {
+            return None;
+        }
+        let distance = distance2.sqrt();
+        let wi = to_light / distance;
+
+        // Direction the light leaves the fixture in, in its own frame
+        let local = self.frame.to_local(-wi);
+        let vertical = local.z.clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = f64::atan2(local.y, local.x).to_degrees();
+        let candela = self.candela(vertical, horizontal);
+        if candela <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            wi,
+            distance,
+            radiance: self.color * (candela / distance2),
+            pdf: 1.0,
+            surface: None,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        // Bounded by the brightest direction, the distribution itself is too irregular
+        let max_candela = self.candela.iter().fold(0.0, |m: f64, &c| m.max(c));
+        Some(LightBounds {
+            bbox: Aabb::new(self.position, self.position),
+            phi: 4.0 * PI * max_candela * luminance(self.color),
+            normals: DirectionCone::entire_sphere(),
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Type C file with a multiplier of 2, the candela values are given per horizontal angle
+    fn parse(vertical: &[f64], horizontal: &[f64], candela: &[f64]) -> Result<IesLight, String> {
+        let join = |values: &[f64]| {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let text = format!(
+            "IESNA:LM-63-2002\n[TEST] unit\nTILT=NONE\n1 -1 2.0 {} {} 1 2 0 0 0\n1.0 1.0 10\n{}\n{}\n{}\n",
+            vertical.len(),
+            horizontal.len(),
+            join(vertical),
+            join(horizontal),
+            join(candela),
+        );
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        IesLight::parse(&text, Vec3::default(), down, Vec3::new(1.0, 1.0, 1.0))
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn interpolates_vertical_angles() {
+        let light = parse(&[0.0, 45.0, 90.0], &[0.0], &[50.0, 25.0, 0.0]).unwrap();
+        assert_close(light.candela(0.0, 0.0), 100.0);
+        assert_close(light.candela(22.5, 0.0), 75.0);
+        assert_close(light.candela(67.5, 0.0), 25.0);
+        // Beyond the last vertical angle the fixture is dark
+        assert_close(light.candela(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn single_horizontal_angle_is_rotationally_symmetric() {
+        let light = parse(&[0.0, 90.0], &[0.0], &[50.0, 10.0]).unwrap();
+        for horizontal in [0.0, 45.0, 137.0, 270.0, -30.0] {
+            assert_close(light.candela(45.0, horizontal), 60.0);
+        }
+    }
+
+    #[test]
+    fn quadrant_symmetry() {
+        let light = parse(
+            &[0.0, 90.0],
+            &[0.0, 45.0, 90.0],
+            &[50.0, 50.0, 30.0, 30.0, 10.0, 10.0],
+        )
+        .unwrap();
+        assert_close(light.candela(0.0, 22.5), 80.0);
+        // Mirrored across 90, 180 and 270 degrees
+        assert_close(light.candela(0.0, 135.0), 60.0);
+        assert_close(light.candela(0.0, 180.0), 100.0);
+        assert_close(light.candela(0.0, 270.0), 20.0);
+        assert_close(light.candela(0.0, -45.0), 60.0);
+    }
+
+    #[test]
+    fn bilateral_symmetry() {
+        let light = parse(&[0.0], &[0.0, 90.0, 180.0], &[50.0, 25.0, 0.0]).unwrap();
+        assert_close(light.candela(0.0, 45.0), 75.0);
+        // Mirrored across the 0-180 plane
+        assert_close(light.candela(0.0, 270.0), 50.0);
+        assert_close(light.candela(0.0, 315.0), 75.0);
+    }
+
+    #[test]
+    fn no_symmetry() {
+        let light = parse(&[0.0], &[0.0, 180.0, 360.0], &[50.0, 0.0, 20.0]).unwrap();
+        assert_close(light.candela(0.0, 90.0), 50.0);
+        assert_close(light.candela(0.0, 270.0), 20.0);
+    }
+
+    #[test]
+    fn tilt_include_is_skipped() {
+        let text =
+            "IESNA91\nTILT=INCLUDE\n1\n2\n0 90\n1 1\n1 -1 1 1 1 1 2 0 0 0\n1 1 10\n0\n0\n42\n";
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let light = IesLight::parse(text, Vec3::default(), down, Vec3::new(1.0, 1.0, 1.0)).unwrap();
+        assert_close(light.candela(0.0, 0.0), 42.0);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        // Type B photometry
+        let type_b = "TILT=NONE\n1 -1 1 1 1 2 2 0 0 0\n1 1 10\n0\n0\n42\n";
+        assert!(IesLight::parse(type_b, Vec3::default(), down, white).is_err());
+        // Cut short
+        let short = "TILT=NONE\n1 -1 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n42\n";
+        assert!(IesLight::parse(short, Vec3::default(), down, white).is_err());
+        assert!(IesLight::parse("no tilt", Vec3::default(), down, white).is_err());
+    }
+
+    #[test]
+    fn shines_along_the_nadir() {
+        let light = parse(&[0.0, 90.0], &[0.0], &[50.0, 0.0]).unwrap();
+        let below = light.sample(Vec3::new(0.0, -2.0, 0.0)).unwrap();
+        assert_close(below.radiance.x, 100.0 / 4.0);
+        assert!(light.sample(Vec3::new(0.0, 2.0, 0.0)).is_none());
+    }
+}
@@ -0,0 +1,279 @@
+use std::sync::{Arc, OnceLock};
+
+use rand::Rng;
+
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+use crate::lights::light_bvh::*;
+use crate::sampling::*;
+
+// Light arriving at a point from one sampled direction
+pub struct LightSample {
+    pub wi: Vec3,                    // Unit direction from the point towards the light
+    pub distance: f64, // How far the light is along wi, infinite for directional lights
+    pub radiance: Vec3, // Incident radiance, or irradiance for delta lights
+    pub pdf: f64,      // Solid angle density of wi, 1 for delta lights
+    pub surface: Option<LightPoint>, // Where it was sampled, for the lights with a surface
+}
+
+// A point sampled on the surface of a light, the shadow ray is spawned to it
+#[derive(Clone, Copy)]
+pub struct LightPoint {
+    pub p: Vec3,
+    pub n: Vec3,       // Geometric normal
+    pub p_error: Vec3, // Absolute floating point error bound on p
+}
+
+/*
+ *  Lights that are sampled explicitly by the integrator (next event estimation) instead
+ *  of being found by chance. Emissive geometry is still found by the scattered rays.
+ *  Lights that scattered rays can also reach (not delta) report the density they sample
+ *  directions with, so the two ways of finding them get combined with MIS.
+ */
+pub trait Light: Sync + Send {
+    // Light reaching `p`, None when it can't
+    fn sample(&self, p: Vec3) -> Option<LightSample>;
+
+    // Solid angle density of sample() picking the direction of r from its origin, if r
+    // reaches the light within t_max. Zero for delta lights
+    fn pdf(&self, _r: Ray, _t_max: f64) -> f64 {
+        0.0
+    }
+
+    // Point, spot and directional lights can only ever be reached by sampling them
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    // None for the lights infinitely far away, which can't go in the light BVH
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+}
+
+// How the light to sample is picked for a shading point
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub enum LightSampling {
+    #[allow(dead_code)]
+    Uniform, // Every light alike, the baseline the others are checked against
+    Power, // Proportionally to the emitted power, with an alias table
+    #[default]
+    Bvh, // By power, distance and orientation, through the light BVH
+}
+
+/*
+ *  The lights of a scene, built into the sampling structures the first time they are
+ *  needed. Lights infinitely far away are picked with a fixed probability and the
+ *  others go through the BVH, which also finds the lights a ray hit for the MIS weights.
+ */
+#[derive(Default, Clone)]
+pub struct LightList {
+    pub lights: Vec<Arc<dyn Light>>,
+    pub sampling: LightSampling,
+    sampler: OnceLock<LightSampler>,
+}
+
+#[derive(Clone)]
+struct LightSampler {
+    infinite: Vec<usize>,
+    bvh: LightBvh,
+    power: AliasTable, // Over `bounded`
+    bounded: Vec<usize>,
+    slots: Vec<Option<usize>>, // Position of every light in `bounded`, None if infinite
+}
+
+impl LightList {
+    pub fn add(&mut self, light: Arc<dyn Light>) {
+        self.lights.push(light);
+        self.sampler = OnceLock::new();
+    }
+
+    pub fn extend(&mut self, lights: impl IntoIterator<Item = Arc<dyn Light>>) {
+        self.lights.extend(lights);
+        self.sampler = OnceLock::new();
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn with_sampling(mut self, sampling: LightSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    fn sampler(&self) -> &LightSampler {
+        self.sampler.get_or_init(|| {
+            let mut infinite = vec![];
+            let mut bounded = vec![];
+            let mut bounds = vec![];
+            for (i, light) in self.lights.iter().enumerate() {
+                match light.bounds() {
+                    Some(b) => {
+                        bounded.push(i);
+                        bounds.push((i, b));
+                    }
+                    None => infinite.push(i),
+                }
+            }
+            let mut slots = vec![None; self.lights.len()];
+            for (slot, &i) in bounded.iter().enumerate() {
+                slots[i] = Some(slot);
+            }
+            LightSampler {
+                infinite,
+                bvh: LightBvh::new(&bounds, self.lights.len()),
+                power: AliasTable::new(&bounds.iter().map(|(_, b)| b.phi).collect::<Vec<_>>()),
+                bounded,
+                slots,
+            }
+        })
+    }
+
+    // Chance of picking one of the lights infinitely far away, as a group
+    fn infinite_probability(&self, sampler: &LightSampler) -> f64 {
+        if self.sampling == LightSampling::Uniform {
+            return sampler.infinite.len() as f64 / self.lights.len() as f64;
+        }
+        let has_bounded = if sampler.bounded.is_empty() { 0.0 } else { 1.0 };
+        sampler.infinite.len() as f64 / (sampler.infinite.len() as f64 + has_bounded)
+    }
+
+    // Picks a light to sample for the point p, returns it with the probability it was picked with
+    pub fn choose(&self, p: Vec3) -> Option<(&Arc<dyn Light>, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let sampler = self.sampler();
+        let mut rng = rand::thread_rng();
+
+        let p_infinite = self.infinite_probability(sampler);
+        let u = rng.gen::<f64>();
+        if u < p_infinite {
+            let n = sampler.infinite.len();
+            let i = ((u / p_infinite * n as f64) as usize).min(n - 1);
+            return Some((&self.lights[sampler.infinite[i]], p_infinite / n as f64));
+        }
+
+        let u = rng.gen::<f64>();
+        let (light, pmf) = match self.sampling {
+            LightSampling::Uniform => {
+                let n = sampler.bounded.len();
+                let i = ((u * n as f64) as usize).min(n - 1);
+                (sampler.bounded[i], 1.0 / n as f64)
+            }
+            LightSampling::Power => {
+                let (slot, pmf) = sampler.power.sample(u);
+                (sampler.bounded[slot], pmf)
+            }
+            LightSampling::Bvh => sampler.bvh.sample(p, u)?,
+        };
+        Some((&self.lights[light], pmf * (1.0 - p_infinite)))
+    }
+
+    // Probability of choose() picking the light at index `light` for the point p
+    fn pmf(&self, sampler: &LightSampler, p: Vec3, light: usize) -> f64 {
+        let p_infinite = self.infinite_probability(sampler);
+        let Some(slot) = sampler.slots[light] else {
+            return p_infinite / sampler.infinite.len() as f64;
+        };
+        let pmf = match self.sampling {
+            LightSampling::Uniform => 1.0 / sampler.bounded.len() as f64,
+            LightSampling::Power => sampler.power.pmf(slot),
+            LightSampling::Bvh => sampler.bvh.pmf(p, light),
+        };
+        pmf * (1.0 - p_infinite)
+    }
+
+    // Density of choose() followed by sample() giving the direction of r, for a ray that
+    // got as far as t_max (infinity when it left the scene)
+    pub fn pdf(&self, r: Ray, t_max: f64) -> f64 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let sampler = self.sampler();
+        let mut sum = 0.0;
+        if t_max == f64::INFINITY {
+            for &i in &sampler.infinite {
+                sum += self.pmf(sampler, r.orig, i) * self.lights[i].pdf(r, t_max);
+            }
+        }
+        // Only the lights whose bounds the ray crosses can have been hit
+        sampler.bvh.for_each_along(r, t_max, |i| {
+            let pdf = self.lights[i].pdf(r, t_max);
+            if pdf > 0.0 {
+                sum += self.pmf(sampler, r.orig, i) * pdf;
+            }
+        });
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lights::{directional::*, point::*};
+
+    fn lights(sampling: LightSampling) -> LightList {
+        let mut lights = LightList::default();
+        for (x, power) in [(-2.0, 1.0), (0.0, 8.0), (3.0, 3.0)] {
+            lights.add(Arc::new(PointLight::new(
+                Vec3::new(x, 2.0, 0.0),
+                Vec3::new(power, power, power),
+            )));
+        }
+        lights.add(Arc::new(DirectionalLight::new(
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(1.0, 1.0, 1.0),
+        )));
+        lights.with_sampling(sampling)
+    }
+
+    #[test]
+    fn choose_matches_pmf() {
+        let p = Vec3::new(0.5, 0.0, 0.5);
+        let n = 200_000;
+        for sampling in [
+            LightSampling::Uniform,
+            LightSampling::Power,
+            LightSampling::Bvh,
+        ] {
+            let lights = lights(sampling);
+            let sampler = lights.sampler();
+            let pmfs: Vec<f64> = (0..4).map(|i| lights.pmf(sampler, p, i)).collect();
+            let total: f64 = pmfs.iter().sum();
+            assert!((total - 1.0).abs() < 1e-9, "{sampling:?}: {total}");
+
+            let mut counts = [0usize; 4];
+            for _ in 0..n {
+                let (light, pmf) = lights.choose(p).unwrap();
+                let i = (0..4)
+                    .find(|&i| Arc::ptr_eq(&lights.lights[i], light))
+                    .unwrap();
+                assert!((pmf - pmfs[i]).abs() < 1e-12, "{sampling:?}");
+                counts[i] += 1;
+            }
+            for i in 0..4 {
+                let frequency = counts[i] as f64 / n as f64;
+                assert!(
+                    (frequency - pmfs[i]).abs() < 0.01,
+                    "{sampling:?} light {i}: {frequency} against {}",
+                    pmfs[i]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn power_sampling_follows_the_power() {
+        let lights = lights(LightSampling::Power);
+        let sampler = lights.sampler();
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        // Half for the directional light, the rest split 1 : 8 : 3
+        assert!((lights.pmf(sampler, p, 3) - 0.5).abs() < 1e-12);
+        assert!((lights.pmf(sampler, p, 1) - 0.5 * 8.0 / 12.0).abs() < 1e-12);
+        assert!((lights.pmf(sampler, p, 0) - 0.5 / 12.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,408 @@
+/*
+ *  Bounding volume hierarchy over the lights that have a position (Conty Estevez and
+ *  Kulla 2018, as in PBRT 12.6.3 of the 4th edition). Every node bounds the power, the
+ *  positions and the emission directions of the lights below it, so the traversal can
+ *  favor the lights that are bright, close and facing the shading point.
+ */
+use std::f64::consts::PI;
+
+use crate::accelerators::aabb::*;
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+
+// All the directions within the angle acos(cos_theta) of w
+#[derive(Clone, Copy)]
+pub struct DirectionCone {
+    pub w: Vec3,
+    pub cos_theta: f64,
+}
+
+impl DirectionCone {
+    #[inline]
+    pub fn new(w: Vec3, cos_theta: f64) -> Self {
+        Self {
+            w: Vec3::unit_vector(w),
+            cos_theta,
+        }
+    }
+
+    #[inline]
+    pub fn entire_sphere() -> Self {
+        Self::new(Vec3::new(0.0, 0.0, 1.0), -1.0)
+    }
+
+    // Smallest cone around both, PBRT's Union(DirectionCone, DirectionCone)
+    pub fn union(self, other: DirectionCone) -> Self {
+        let theta_a = self.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = other.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = self.w.dot(other.w).clamp(-1.0, 1.0).acos();
+        // One of them already holds the other
+        if f64::min(theta_d + theta_b, PI) <= theta_a {
+            return self;
+        }
+        if f64::min(theta_d + theta_a, PI) <= theta_b {
+            return other;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= PI {
+            return Self::entire_sphere();
+        }
+        // Turn w towards the other axis, so the new cone just reaches both edges
+        let theta_r = theta_o - theta_a;
+        let axis = self.w.cross(other.w);
+        if axis.lenght_squared() == 0.0 {
+            return Self::entire_sphere();
+        }
+        let w = rotate(self.w, Vec3::unit_vector(axis), theta_r);
+        Self::new(w, theta_o.cos())
+    }
+}
+
+// Rodrigues' rotation of v about the unit axis k
+fn rotate(v: Vec3, k: Vec3, angle: f64) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + k.cross(v) * sin + k * (k.dot(v) * (1.0 - cos))
+}
+
+#[inline]
+fn sin_from_cos(cos: f64) -> f64 {
+    f64::sqrt(f64::max(0.0, 1.0 - cos * cos))
+}
+
+/*
+ *  Where a light (or a group of them) is, how much it emits and towards where. The
+ *  light emits around the normals in `normals`, within theta_e past their edge.
+ */
+#[derive(Clone, Copy)]
+pub struct LightBounds {
+    pub bbox: Aabb,
+    pub phi: f64, // Emitted power, as luminance
+    pub normals: DirectionCone,
+    pub cos_theta_e: f64,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn union(self, other: LightBounds) -> Self {
+        if self.phi == 0.0 {
+            return other;
+        }
+        if other.phi == 0.0 {
+            return self;
+        }
+        Self {
+            bbox: self.bbox.union(other.bbox),
+            phi: self.phi + other.phi,
+            normals: self.normals.union(other.normals),
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    // Conservative guess of how much light reaches p, PBRT's LightBounds::Importance
+    pub fn importance(&self, p: Vec3) -> f64 {
+        if self.phi == 0.0 {
+            return 0.0;
+        }
+        let center = self.bbox.centroid();
+        let radius = (self.bbox.maximum - self.bbox.minimum).length() / 2.0;
+        // Don't let the distance get too small for points inside or near the bounds
+        let d2 = f64::max((p - center).lenght_squared(), radius);
+
+        // cos(max(0, a - b)) and sin(max(0, a - b)) from the sines and cosines
+        let cos_sub = |sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64| {
+            if cos_a > cos_b {
+                1.0
+            } else {
+                cos_a * cos_b + sin_a * sin_b
+            }
+        };
+        let sin_sub = |sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64| {
+            if cos_a > cos_b {
+                0.0
+            } else {
+                sin_a * cos_b - cos_a * sin_b
+            }
+        };
+
+        // Angle between the cone axis and the direction to p
+        let wi = p - center;
+        let mut cos_theta_w = if wi.lenght_squared() > 0.0 {
+            self.normals.w.dot(Vec3::unit_vector(wi))
+        } else {
+            1.0
+        };
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = sin_from_cos(cos_theta_w);
+
+        // Angle the bounds cover seen from p
+        let distance2 = (p - center).lenght_squared();
+        let cos_theta_b = if distance2 < radius * radius {
+            -1.0
+        } else {
+            sin_from_cos(f64::sqrt(radius * radius / distance2))
+        };
+        let sin_theta_b = sin_from_cos(cos_theta_b);
+
+        // Closest the emission cone gets to p, after taking the bounds' extent off
+        let cos_theta_o = self.normals.cos_theta;
+        let sin_theta_o = sin_from_cos(cos_theta_o);
+        let cos_theta_x = cos_sub(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        self.phi * cos_theta_p / d2
+    }
+}
+
+#[derive(Clone)]
+struct Node {
+    bounds: LightBounds,
+    // Index of the light for leaves, of the second child otherwise (the first one follows)
+    index: usize,
+    is_leaf: bool,
+}
+
+/*
+ *  The lights are given by their index in the LightList. Each one remembers the path
+ *  from the root to its leaf, one bit per level, to recompute how likely it was picked.
+ */
+#[derive(Clone, Default)]
+pub struct LightBvh {
+    nodes: Vec<Node>,
+    trails: Vec<Option<u64>>, // By light index, None for the lights not in the tree
+}
+
+impl LightBvh {
+    pub fn new(lights: &[(usize, LightBounds)], num_lights: usize) -> Self {
+        let mut bvh = Self {
+            nodes: vec![],
+            trails: vec![None; num_lights],
+        };
+        let mut lights: Vec<(usize, LightBounds)> = lights
+            .iter()
+            .filter(|(_, bounds)| bounds.phi > 0.0)
+            .copied()
+            .collect();
+        if !lights.is_empty() {
+            bvh.build(&mut lights, 0, 0);
+        }
+        bvh
+    }
+
+    // Median split along the axis where the centroids are spread the most
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        let node_index = self.nodes.len();
+        // The trail only has room for 64 levels, the median split needs far fewer
+        if lights.len() == 1 || depth == 64 {
+            let (light, bounds) = lights[0];
+            self.nodes.push(Node {
+                bounds,
+                index: light,
+                is_leaf: true,
+            });
+            self.trails[light] = Some(trail);
+            return node_index;
+        }
+
+        let axis = lights
+            .iter()
+            .fold(Aabb::empty(), |b, (_, bounds)| {
+                let c = bounds.bbox.centroid();
+                b.union(Aabb::new(c, c))
+            })
+            .longest_axis();
+        let mid = lights.len() / 2;
+        lights.select_nth_unstable_by(mid, |(_, a), (_, b)| {
+            a.bbox.centroid()[axis].total_cmp(&b.bbox.centroid()[axis])
+        });
+
+        let bounds = lights
+            .iter()
+            .skip(1)
+            .fold(lights[0].1, |b, (_, other)| b.union(*other));
+        self.nodes.push(Node {
+            bounds,
+            index: 0,
+            is_leaf: false,
+        });
+        let (left, right) = lights.split_at_mut(mid);
+        self.build(left, trail, depth + 1);
+        let second = self.build(right, trail | (1 << depth), depth + 1);
+        self.nodes[node_index].index = second;
+        node_index
+    }
+
+    // Probabilities of going down the first and the second child of an interior node
+    fn child_probabilities(&self, node_index: usize, p: Vec3) -> Option<(f64, f64)> {
+        let first = self.nodes[node_index + 1].bounds.importance(p);
+        let second = self.nodes[self.nodes[node_index].index]
+            .bounds
+            .importance(p);
+        if first == 0.0 && second == 0.0 {
+            return None;
+        }
+        Some((first / (first + second), second / (first + second)))
+    }
+
+    // A light index for the point p with the probability it was picked with
+    pub fn sample(&self, p: Vec3, mut u: f64) -> Option<(usize, f64)> {
+        if self.nodes.is_empty() || self.nodes[0].bounds.importance(p) == 0.0 {
+            return None;
+        }
+        let mut node_index = 0;
+        let mut pmf = 1.0;
+        loop {
+            let node = &self.nodes[node_index];
+            if node.is_leaf {
+                return Some((node.index, pmf));
+            }
+            let (p_first, p_second) = self.child_probabilities(node_index, p)?;
+            // Reuse u for the next level
+            if u < p_first {
+                u = (u / p_first).min(1.0 - f64::EPSILON);
+                pmf *= p_first;
+                node_index += 1;
+            } else {
+                u = ((u - p_first) / p_second).min(1.0 - f64::EPSILON);
+                pmf *= p_second;
+                node_index = node.index;
+            }
+        }
+    }
+
+    // Probability of sample() picking the light at `light` for the point p
+    pub fn pmf(&self, p: Vec3, light: usize) -> f64 {
+        let Some(mut trail) = self.trails.get(light).copied().flatten() else {
+            return 0.0;
+        };
+        if self.nodes[0].bounds.importance(p) == 0.0 {
+            return 0.0;
+        }
+        let mut node_index = 0;
+        let mut pmf = 1.0;
+        while !self.nodes[node_index].is_leaf {
+            let Some((p_first, p_second)) = self.child_probabilities(node_index, p) else {
+                return 0.0;
+            };
+            if trail & 1 == 0 {
+                pmf *= p_first;
+                node_index += 1;
+            } else {
+                pmf *= p_second;
+                node_index = self.nodes[node_index].index;
+            }
+            trail >>= 1;
+        }
+        pmf
+    }
+
+    // Calls f with every light whose bounds the ray passes through before t_max
+    pub fn for_each_along(&self, r: Ray, t_max: f64, mut f: impl FnMut(usize)) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0usize];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            // Flat lights have flat bounds, pad them or the slab test misses
+            if node.bounds.bbox.pad(0.0002).hit(r, 0.0..t_max).is_none() {
+                continue;
+            }
+            if node.is_leaf {
+                f(node.index);
+            } else {
+                stack.push(node_index + 1);
+                stack.push(node.index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Point-like lights of different powers, some only shining one way
+    fn lights() -> Vec<(usize, LightBounds)> {
+        let light = |p: Vec3, phi: f64, normals: DirectionCone| LightBounds {
+            bbox: Aabb::new(p, p),
+            phi,
+            normals,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        };
+        let down = DirectionCone::new(Vec3::new(0.0, -1.0, 0.0), 0.9);
+        vec![
+            (0, light(Vec3::new(0.0, 4.0, 0.0), 10.0, down)),
+            (
+                1,
+                light(
+                    Vec3::new(3.0, 1.0, -2.0),
+                    2.0,
+                    DirectionCone::entire_sphere(),
+                ),
+            ),
+            (
+                2,
+                light(
+                    Vec3::new(-5.0, 2.0, 1.0),
+                    40.0,
+                    DirectionCone::entire_sphere(),
+                ),
+            ),
+            (3, light(Vec3::new(1.0, -3.0, 0.0), 5.0, down)), // Faces away from p
+            (
+                5,
+                light(
+                    Vec3::new(0.5, 0.5, 6.0),
+                    1.0,
+                    DirectionCone::entire_sphere(),
+                ),
+            ),
+            (
+                6,
+                light(
+                    Vec3::new(2.0, 2.0, 2.0),
+                    0.0,
+                    DirectionCone::entire_sphere(),
+                ),
+            ),
+        ]
+    }
+
+    #[test]
+    fn pmf_sums_to_one() {
+        let bvh = LightBvh::new(&lights(), 7);
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        let total: f64 = (0..7).map(|i| bvh.pmf(p, i)).sum();
+        assert!((total - 1.0).abs() < 1e-9, "{total}");
+        // Lights that aren't in the tree or emit nothing are never picked
+        assert_eq!(bvh.pmf(p, 4), 0.0);
+        assert_eq!(bvh.pmf(p, 6), 0.0);
+        assert_eq!(bvh.pmf(p, 3), 0.0);
+    }
+
+    #[test]
+    fn sample_matches_pmf() {
+        let bvh = LightBvh::new(&lights(), 7);
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        let n = 100_000;
+        let mut counts = [0usize; 7];
+        for i in 0..n {
+            let (light, pmf) = bvh.sample(p, (i as f64 + 0.5) / n as f64).unwrap();
+            assert!((pmf - bvh.pmf(p, light)).abs() < 1e-12);
+            counts[light] += 1;
+        }
+        for (i, &count) in counts.iter().enumerate() {
+            let frequency = count as f64 / n as f64;
+            assert!((frequency - bvh.pmf(p, i)).abs() < 1e-3, "{i}: {frequency}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::f64::consts::PI;
+
+use crate::accelerators::aabb::*;
+use crate::geometry::vector3::*;
+use crate::lights::{light::*, light_bvh::*};
+use crate::microfacet::luminance;
+use crate::utils::*;
+
+// Shines equally in every direction from a single point
+pub struct PointLight {
+    position: Vec3,
+    intensity: Vec3, // Radiant intensity, power per steradian
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Vec3) -> Self {
+        Self {
+            position,
+            intensity,
+        }
+    }
+
+    // Total emitted power, spread over the whole sphere of directions
+    #[allow(dead_code)]
+    pub fn from_power(position: Vec3, power: Vec3) -> Self {
+        Self::new(position, power / (4.0 * PI))
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance2 = to_light.lenght_squared();
+        if distance2 == 0.0 {
+            return None;
+        }
+        let distance = distance2.sqrt();
+        Some(LightSample {
+            wi: to_light / distance,
+            distance,
+            radiance: self.intensity / distance2,
+            pdf: 1.0,
+            surface: None,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds {
+            bbox: Aabb::new(self.position, self.position),
+            phi: 4.0 * PI * luminance(self.intensity),
+            normals: DirectionCone::entire_sphere(),
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+}
+
+/*
+ *  Point light restricted to a cone around `direction`. Full intensity within
+ *  `falloff_start` degrees of the axis, fading out smoothly to zero at `angle` degrees.
+ */
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cos_total: f64,
+    cos_falloff_start: f64,
+}
+
+impl SpotLight {
+    pub fn new(
+        position: Vec3,
+        lookat: Vec3,
+        intensity: Vec3,
+        angle: f64,
+        falloff_start: f64,
+    ) -> Self {
+        Self {
+            position,
+            direction: Vec3::unit_vector(lookat - position),
+            intensity,
+            cos_total: f64::cos(degrees_to_radians(angle)),
+            cos_falloff_start: f64::cos(degrees_to_radians(falloff_start.min(angle))),
+        }
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta < self.cos_total {
+            return 0.0;
+        }
+        if cos_theta >= self.cos_falloff_start {
+            return 1.0;
+        }
+        let t = (cos_theta - self.cos_total) / (self.cos_falloff_start - self.cos_total);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance2 = to_light.lenght_squared();
+        if distance2 == 0.0 {
+            return None;
+        }
+        let distance = distance2.sqrt();
+        let wi = to_light / distance;
+
+        let falloff = self.falloff(-wi.dot(self.direction));
+        if falloff == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            wi,
+            distance,
+            radiance: self.intensity * (falloff / distance2),
+            pdf: 1.0,
+            surface: None,
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        // Full power within the falloff start, the rest of the cone is the extra angle
+        let theta_e = self.cos_total.acos() - self.cos_falloff_start.acos();
+        Some(LightBounds {
+            bbox: Aabb::new(self.position, self.position),
+            phi: 4.0 * PI * luminance(self.intensity),
+            normals: DirectionCone::new(self.direction, self.cos_falloff_start),
+            cos_theta_e: theta_e.cos(),
+            two_sided: false,
+        })
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ *  Preetham, Shirley and Smits' analytic daylight model ("A Practical Analytic Model
+ *  for Daylight", 1999) with a sun disk whose color comes from the same turbidity.
+ *  Luminance is in kcd/m^2 before the intensity scale, +Y is up and nothing is below
+ *  the horizon, scenes bring their own ground.
+ */
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+use crate::geometry::onb::*;
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+use crate::lights::{environment::*, light::*};
+
+// Perez et al. luminance distribution coefficients A to E for Y, x and y
+fn perez_coefficients(t: f64) -> [[f64; 5]; 3] {
+    [
+        [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ],
+        [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ],
+        [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ],
+    ]
+}
+
+// Relative brightness towards zenith angle theta at angle gamma from the sun
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * f64::exp(c[1] / cos_theta))
+        * (1.0 + c[2] * f64::exp(c[3] * gamma) + c[4] * cos_gamma * cos_gamma)
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Vec3 {
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    Vec3::new(
+        3.2406 * cx - 1.5372 * luminance - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * luminance + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * luminance + 1.0570 * cz,
+    )
+}
+
+// The sky dome alone, the part that gets tabulated for sampling
+struct SkyDome {
+    sun_dir: Vec3,
+    zenith: [f64; 3],            // Y, x and y straight up
+    coefficients: [[f64; 5]; 3], // Perez coefficients for Y, x and y
+    normalization: [f64; 3],     // Perez function at the zenith for each
+    intensity: f64,
+}
+
+impl Background for SkyDome {
+    fn radiance(&self, dir: Vec3) -> Vec3 {
+        if dir.y <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let gamma = dir.dot(self.sun_dir).clamp(-1.0, 1.0).acos();
+        let value = |i: usize| {
+            self.zenith[i] * perez(&self.coefficients[i], dir.y, gamma) / self.normalization[i]
+        };
+        let rgb = xyy_to_rgb(value(1), value(2), value(0)) * self.intensity;
+        // Out of gamut colors near the sun go slightly negative
+        Vec3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+    }
+}
+
+pub struct PreethamSky {
+    dome: SkyDome,
+    sampled_dome: EnvironmentMap,
+    sun_frame: Onb,       // w points at the sun
+    cos_sun_radius: f64,  // Cosine of the angular radius of the disk
+    sun_radiance: Vec3,   // Already scaled by the intensity
+    sun_probability: f64, // Chance a light sample goes towards the sun
+}
+
+impl PreethamSky {
+    // Luminance of the sun disk above the atmosphere, in kcd/m^2
+    const SUN_LUMINANCE: f64 = 2.0e6;
+    // Mean angular radius of the sun in degrees
+    const SUN_RADIUS: f64 = 0.265;
+
+    /*
+     *  `elevation` above the horizon and `azimuth` from +X towards +Z, both in degrees.
+     *  `turbidity` goes from about 2 (very clear) to 10 (hazy), `intensity` scales the
+     *  luminance down to the range the scene is lit in.
+     */
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, intensity: f64) -> Self {
+        // The fit is only valid with the sun above the horizon
+        let elevation = elevation.clamp(0.0, 90.0).to_radians();
+        let azimuth = azimuth.to_radians();
+        let sun_dir = Vec3::new(
+            elevation.cos() * azimuth.cos(),
+            elevation.sin(),
+            elevation.cos() * azimuth.sin(),
+        );
+        let t = turbidity.clamp(1.7, 10.0);
+        let theta_s = PI / 2.0 - elevation;
+
+        // Zenith luminance and chromaticity
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+            + t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+            + (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let zenith_yc = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+            + t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+            + (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+
+        let coefficients = perez_coefficients(t);
+        let normalization = [0, 1, 2].map(|i| perez(&coefficients[i], 1.0, theta_s));
+        let dome = SkyDome {
+            sun_dir,
+            zenith: [zenith_y.max(0.0), zenith_x, zenith_yc],
+            coefficients,
+            normalization,
+            intensity,
+        };
+
+        Self {
+            sampled_dome: EnvironmentMap::from_background(&dome, 256, 128),
+            dome,
+            sun_frame: Onb::build_from_w(sun_dir),
+            cos_sun_radius: Self::SUN_RADIUS.to_radians().cos(),
+            sun_radiance: Self::sun_transmittance(theta_s, t) * Self::SUN_LUMINANCE * intensity,
+            sun_probability: if elevation > 0.0 { 0.5 } else { 0.0 },
+        }
+    }
+
+    /*
+     *  Scales the angular radius of the disk, keeping the light it gives the same. A
+     *  bigger sun softens the shadows and is easier to hit with the scattered rays.
+     */
+    #[allow(dead_code)]
+    pub fn with_sun_size(mut self, scale: f64) -> Self {
+        let old_solid_angle = 1.0 - self.cos_sun_radius;
+        self.cos_sun_radius = (Self::SUN_RADIUS * scale)
+            .clamp(0.01, 45.0)
+            .to_radians()
+            .cos();
+        self.sun_radiance = self.sun_radiance * (old_solid_angle / (1.0 - self.cos_sun_radius));
+        self
+    }
+
+    /*
+     *  Sunlight left after Rayleigh and aerosol scattering on the way through the air
+     *  mass towards the zenith angle theta_s (Preetham appendix, ozone and water vapor
+     *  ignored), sampled at red, green and blue wavelengths.
+     */
+    fn sun_transmittance(theta_s: f64, turbidity: f64) -> Vec3 {
+        let air_mass =
+            1.0 / (theta_s.cos() + 0.15 * f64::powf(93.885 - theta_s.to_degrees(), -1.253));
+        let beta = 0.04608 * turbidity - 0.04586;
+        let transmittance = |lambda: f64| {
+            let rayleigh = f64::exp(-0.008735 * lambda.powf(-4.08) * air_mass);
+            let aerosol = f64::exp(-beta * lambda.powf(-1.3) * air_mass);
+            rayleigh * aerosol
+        };
+        // Wavelengths in micrometers
+        Vec3::new(
+            transmittance(0.680),
+            transmittance(0.550),
+            transmittance(0.440),
+        )
+    }
+
+    #[inline]
+    fn in_sun(&self, dir: Vec3) -> bool {
+        self.sun_probability > 0.0 && dir.dot(self.sun_frame.w()) >= self.cos_sun_radius
+    }
+
+    fn sun_pdf(&self) -> f64 {
+        1.0 / (2.0 * PI * (1.0 - self.cos_sun_radius))
+    }
+}
+
+impl Background for PreethamSky {
+    fn radiance(&self, dir: Vec3) -> Vec3 {
+        let sky = self.dome.radiance(dir);
+        if self.in_sun(dir) {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+}
+
+impl Light for PreethamSky {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let mut rng = rand::thread_rng();
+        let wi = if rng.gen::<f64>() < self.sun_probability {
+            // Uniform over the cone the disk covers
+            let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - self.cos_sun_radius);
+            let sin_theta = f64::sqrt(1.0 - cos_theta * cos_theta);
+            let phi = 2.0 * PI * rng.gen::<f64>();
+            self.sun_frame.local(Vec3::new(
+                sin_theta * phi.cos(),
+                sin_theta * phi.sin(),
+                cos_theta,
+            ))
+        } else {
+            self.sampled_dome.sample(p)?.wi
+        };
+
+        let pdf = self.pdf(Ray::new(p, wi), f64::INFINITY);
+        if pdf == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            wi,
+            distance: f64::INFINITY,
+            radiance: self.radiance(wi),
+            pdf,
+            surface: None,
+        })
+    }
+
+    fn pdf(&self, r: Ray, t_max: f64) -> f64 {
+        if t_max < f64::INFINITY {
+            return 0.0;
+        }
+        let sun = if self.in_sun(Vec3::unit_vector(r.dir)) {
+            self.sun_pdf()
+        } else {
+            0.0
+        };
+        self.sun_probability * sun + (1.0 - self.sun_probability) * self.sampled_dome.pdf(r, t_max)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
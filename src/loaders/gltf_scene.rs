@@ -0,0 +1,473 @@
+/*
+ *  glTF 2.0 scenes (.gltf with external or embedded buffers, and .glb). The node hierarchy
+ *  is flattened by baking the world transform into the vertices, every triangle primitive
+ *  becomes its own TriangleMesh and the metallic-roughness materials are mapped onto ours.
+ */
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::core::camera::*;
+use crate::core::hittable_list::*;
+use crate::geometry::vector3::*;
+use crate::lights::light::*;
+use crate::material::*;
+use crate::primitives::{cutout::*, mesh::*, triangle_mesh::*};
+use crate::texture::*;
+use crate::utils::srgb_to_linear;
+
+// Column major like glTF, m[column][row]
+type Mat4 = [[f64; 4]; 4];
+
+const IDENTITY: Mat4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mat_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (col, m_col) in m.iter_mut().enumerate() {
+        for (row, value) in m_col.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[k][row] * b[col][k]).sum();
+        }
+    }
+    m
+}
+
+fn transform_point(m: &Mat4, p: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * p.x + m[1][0] * p.y + m[2][0] * p.z + m[3][0],
+        m[0][1] * p.x + m[1][1] * p.y + m[2][1] * p.z + m[3][1],
+        m[0][2] * p.x + m[1][2] * p.y + m[2][2] * p.z + m[3][2],
+    )
+}
+
+fn transform_vector(m: &Mat4, v: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[1][0] * v.y + m[2][0] * v.z,
+        m[0][1] * v.x + m[1][1] * v.y + m[2][1] * v.z,
+        m[0][2] * v.x + m[1][2] * v.y + m[2][2] * v.z,
+    )
+}
+
+// Normals go through the inverse transpose of the upper 3x3, which is its cofactor
+// matrix over the determinant. Only the direction matters so the sign of the
+// determinant is all that is kept from it.
+fn normal_matrix(m: &Mat4) -> (Mat4, f64) {
+    let c0 = Vec3::new(m[0][0], m[0][1], m[0][2]);
+    let c1 = Vec3::new(m[1][0], m[1][1], m[1][2]);
+    let c2 = Vec3::new(m[2][0], m[2][1], m[2][2]);
+    let det = c0.dot(c1.cross(c2));
+    let s = if det < 0.0 { -1.0 } else { 1.0 };
+    let n0 = c1.cross(c2) * s;
+    let n1 = c2.cross(c0) * s;
+    let n2 = c0.cross(c1) * s;
+    let n = [
+        [n0.x, n1.x, n2.x, 0.0],
+        [n0.y, n1.y, n2.y, 0.0],
+        [n0.z, n1.z, n2.z, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    (n, det)
+}
+
+// A camera of the file, placed where its node puts it
+#[allow(dead_code)]
+pub struct GltfCamera {
+    pub name: String,
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub vfov: f64,                 // Vertical field of view in degrees
+    pub aspect_ratio: Option<f64>, // Not every file pins the aspect ratio
+}
+
+impl GltfCamera {
+    #[allow(dead_code)]
+    pub fn to_camera(
+        &self,
+        default_aspect_ratio: f64,
+        image_width: u64,
+        samples_per_pixel: u64,
+        max_depth: u64,
+    ) -> Camera {
+        Camera::init_with_up(
+            self.aspect_ratio.unwrap_or(default_aspect_ratio),
+            image_width,
+            samples_per_pixel,
+            max_depth,
+            self.vfov,
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+        )
+    }
+}
+
+pub struct GltfScene {
+    pub world: HittableList,
+    pub lights: LightList, // The emissive triangles, for the integrator to sample
+    pub cameras: Vec<GltfCamera>,
+}
+
+type MaterialPtr = Arc<dyn Material + Sync + Send>;
+// Alpha of a masked or blended material and how it cuts the surface
+type Opacity = (Arc<dyn Texture + Sync + Send>, AlphaMode);
+
+struct Loader<'a> {
+    file: &'a str,
+    buffers: Vec<gltf::buffer::Data>,
+    images: Vec<gltf::image::Data>,
+    precision: Precision,
+    materials: HashMap<Option<usize>, (MaterialPtr, Option<Opacity>)>, // None is the default
+    scene: GltfScene,
+}
+
+// Loads the default scene of the file (the first one if there is no default)
+pub fn load_gltf(file: &str, precision: Precision) -> Result<GltfScene, MeshError> {
+    let (document, buffers, images) =
+        gltf::import(file).map_err(|e| MeshError::Gltf(file.to_string(), e))?;
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| MeshError::Empty(file.to_string()))?;
+
+    let mut loader = Loader {
+        file,
+        buffers,
+        images,
+        precision,
+        materials: HashMap::new(),
+        scene: GltfScene {
+            world: HittableList::default(),
+            lights: LightList::default(),
+            cameras: vec![],
+        },
+    };
+    for node in scene.nodes() {
+        loader.visit(&node, &IDENTITY)?;
+    }
+
+    if loader.scene.world.objects.is_empty() {
+        return Err(MeshError::Empty(file.to_string()));
+    }
+    println!("Loaded glTF scene: {}", file);
+    Ok(loader.scene)
+}
+
+impl Loader<'_> {
+    fn visit(&mut self, node: &gltf::Node, parent: &Mat4) -> Result<(), MeshError> {
+        let local = node.transform().matrix().map(|col| col.map(|v| v as f64));
+        let world = mat_mul(parent, &local);
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.add_primitive(&primitive, &world)?;
+            }
+        }
+        if let Some(camera) = node.camera() {
+            self.add_camera(&camera, &world);
+        }
+        for child in node.children() {
+            self.visit(&child, &world)?;
+        }
+        Ok(())
+    }
+
+    fn add_primitive(&mut self, primitive: &gltf::Primitive, m: &Mat4) -> Result<(), MeshError> {
+        // Points and lines have no surface to hit
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            return Ok(());
+        }
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let Some(read_positions) = reader.read_positions() else {
+            return Ok(());
+        };
+
+        let mut positions = VertexBuffer::with_capacity(self.precision, read_positions.len() * 3);
+        for p in read_positions {
+            let p = transform_point(m, Vec3::new(p[0] as f64, p[1] as f64, p[2] as f64));
+            positions.push(p.x);
+            positions.push(p.y);
+            positions.push(p.z);
+        }
+        let num_vertices = positions.len() / 3;
+
+        let (nm, det) = normal_matrix(m);
+        let normals = reader.read_normals().map(|read_normals| {
+            let mut normals = VertexBuffer::with_capacity(self.precision, num_vertices * 3);
+            for n in read_normals {
+                let n = transform_vector(&nm, Vec3::new(n[0] as f64, n[1] as f64, n[2] as f64));
+                let n = if n.lenght_squared() > 0.0 {
+                    Vec3::unit_vector(n)
+                } else {
+                    n
+                };
+                normals.push(n.x);
+                normals.push(n.y);
+                normals.push(n.z);
+            }
+            normals
+        });
+        // glTF puts the origin of the texture at the top left, ours is at the bottom left
+        let uvs = reader.read_tex_coords(0).map(|read_uvs| {
+            let mut uvs = VertexBuffer::with_capacity(self.precision, num_vertices * 2);
+            for [u, v] in read_uvs.into_f32() {
+                uvs.push(u as f64);
+                uvs.push(1.0 - v as f64);
+            }
+            uvs
+        });
+
+        let mut indices: Vec<u32> = match reader.read_indices() {
+            Some(read_indices) => read_indices.into_u32().collect(),
+            None => (0..num_vertices as u32).collect(),
+        };
+        indices.truncate(indices.len() / 3 * 3);
+        if indices.iter().any(|&i| i as usize >= num_vertices) {
+            return Err(MeshError::Parse(
+                self.file.to_string(),
+                "vertex index out of bounds".to_string(),
+            ));
+        }
+        if indices.is_empty() {
+            return Ok(());
+        }
+        // A mirroring transform flips the winding, flip it back so front faces stay front
+        if det < 0.0 {
+            for tri in indices.chunks_exact_mut(3) {
+                tri.swap(1, 2);
+            }
+        }
+
+        let (material, opacity) = self.material(&primitive.material())?;
+        let mut triangles =
+            TriangleMesh::new(positions, normals, uvs, indices, vec![material], vec![]);
+        self.scene.lights.extend(triangles.area_lights());
+        if let Some((alpha, mode)) = opacity {
+            triangles = triangles.with_alpha(alpha, mode);
+        }
+        self.scene.world.add(Arc::new(triangles));
+        Ok(())
+    }
+
+    fn add_camera(&mut self, camera: &gltf::Camera, m: &Mat4) {
+        // Orthographic cameras have no counterpart here
+        let gltf::camera::Projection::Perspective(perspective) = camera.projection() else {
+            return;
+        };
+        // glTF cameras look down their local -Z with +Y up
+        let lookfrom = transform_point(m, Vec3::new(0.0, 0.0, 0.0));
+        let forward = Vec3::unit_vector(transform_vector(m, Vec3::new(0.0, 0.0, -1.0)));
+        let vup = Vec3::unit_vector(transform_vector(m, Vec3::new(0.0, 1.0, 0.0)));
+
+        self.scene.cameras.push(GltfCamera {
+            name: camera.name().unwrap_or("").to_string(),
+            lookfrom,
+            lookat: lookfrom + forward,
+            vup,
+            vfov: (perspective.yfov() as f64).to_degrees(),
+            aspect_ratio: perspective.aspect_ratio().map(|a| a as f64),
+        });
+    }
+
+    /*
+     *  Metallic-roughness to our materials, first match wins:
+     *  emissive            -> DiffuseLight, scaled by KHR_materials_emissive_strength and
+     *                         one-sided unless the material is double sided
+     *  transmission, blend -> RoughDielectric with KHR_materials_ior (1.5 by default) and
+     *                         the KHR_materials_volume absorption, ThinDielectric without
+     *                         a volume
+     *  else                -> MetallicRoughness
+     *  The factors are multiplied into their textures, and the normal map wraps whatever
+     *  isn't a light in a NormalMapped. The alpha of MASK materials cuts holes at the
+     *  alpha cutoff, and the textured alpha of BLEND materials that aren't glass lets
+     *  rays through at random.
+     */
+    fn material(
+        &mut self,
+        material: &gltf::Material,
+    ) -> Result<(MaterialPtr, Option<Opacity>), MeshError> {
+        if let Some((mat, opacity)) = self.materials.get(&material.index()) {
+            return Ok((Arc::clone(mat), opacity.clone()));
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor().map(|c| c as f64);
+        let base_color = Vec3::new(r, g, b);
+        let base_color_image = pbr.base_color_texture().map(|info| {
+            self.image_texture(&info.texture(), ColorEncoding::Srgb, |c| c * base_color)
+        });
+        let albedo: Arc<dyn Texture + Sync + Send> = match &base_color_image {
+            Some(image) => Arc::new(image.clone()),
+            None => Arc::new(SolidColor::from_color(base_color)),
+        };
+
+        // Roughness is in the green and metallic in the blue channel of the shared texture
+        let metallic = pbr.metallic_factor() as f64;
+        let roughness = pbr.roughness_factor() as f64;
+        let (metallic, roughness): (
+            Arc<dyn Texture + Sync + Send>,
+            Arc<dyn Texture + Sync + Send>,
+        ) = match pbr.metallic_roughness_texture() {
+            Some(info) => {
+                let texture = info.texture();
+                let m = self.image_texture(&texture, ColorEncoding::Linear, |c| {
+                    Vec3::new(c.z, c.z, c.z) * metallic
+                });
+                let r = self.image_texture(&texture, ColorEncoding::Linear, |c| {
+                    Vec3::new(c.y, c.y, c.y) * roughness
+                });
+                (Arc::new(m), Arc::new(r))
+            }
+            None => (
+                Arc::new(SolidColor::from_rgb(metallic, metallic, metallic)),
+                Arc::new(SolidColor::from_rgb(roughness, roughness, roughness)),
+            ),
+        };
+
+        let emissive = material.emissive_factor().map(|c| c as f64);
+        let strength = material.emissive_strength().unwrap_or(1.0) as f64;
+        let transmission = material
+            .transmission()
+            .map_or(0.0, |t| t.transmission_factor());
+        let is_blended = material.alpha_mode() == gltf::material::AlphaMode::Blend && alpha < 1.0;
+
+        let mat: MaterialPtr = if emissive.iter().any(|&c| c > 0.0) {
+            let factor = Vec3::new(emissive[0], emissive[1], emissive[2]);
+            let emit: Arc<dyn Texture + Sync + Send> = match material.emissive_texture() {
+                Some(info) => {
+                    Arc::new(
+                        self.image_texture(&info.texture(), ColorEncoding::Srgb, |c| c * factor),
+                    )
+                }
+                None => Arc::new(SolidColor::from_color(factor)),
+            };
+            let light = DiffuseLight::from_texture(emit).with_strength(strength);
+            Arc::new(if material.double_sided() {
+                light
+            } else {
+                light.one_sided()
+            })
+        } else if transmission > 0.5 || is_blended {
+            let ir = material.ior().unwrap_or(1.5) as f64;
+            // Without a volume glTF defines the surface as thin-walled
+            match material.volume() {
+                Some(volume) if transmission > 0.5 => {
+                    let glass = RoughDielectric::new(ir, pbr.roughness_factor() as f64);
+                    let [r, g, b] = volume.attenuation_color().map(|c| c as f64);
+                    let distance = volume.attenuation_distance() as f64;
+                    Arc::new(if distance.is_finite() {
+                        glass.with_absorption(Vec3::new(r, g, b), distance)
+                    } else {
+                        glass
+                    })
+                }
+                _ => Arc::new(ThinDielectric::new(ir)),
+            }
+        } else {
+            Arc::new(MetallicRoughness::from_textures(
+                albedo, metallic, roughness,
+            ))
+        };
+        // Lights have nothing to shade with a normal map
+        let mat: MaterialPtr = match material.normal_texture() {
+            Some(normal) if !mat.is_emissive() => {
+                let map = self.image_texture(&normal.texture(), ColorEncoding::Linear, |c| c);
+                Arc::new(NormalMapped::new(mat, Arc::new(map)).with_strength(normal.scale() as f64))
+            }
+            _ => mat,
+        };
+
+        let mode = match material.alpha_mode() {
+            gltf::material::AlphaMode::Mask => Some(AlphaMode::Cutoff(
+                material.alpha_cutoff().unwrap_or(0.5) as f64,
+            )),
+            gltf::material::AlphaMode::Blend if !is_blended && base_color_image.is_some() => {
+                Some(AlphaMode::Stochastic)
+            }
+            _ => None,
+        };
+        let opacity = mode.map(|mode| {
+            let texture: Arc<dyn Texture + Sync + Send> = match &base_color_image {
+                Some(image) if alpha < 1.0 => Arc::new(MultiplyTexture::new(
+                    Arc::new(image.alpha()),
+                    Arc::new(SolidColor::from_rgb(alpha, alpha, alpha)),
+                )),
+                Some(image) => Arc::new(image.alpha()),
+                None => Arc::new(SolidColor::from_rgb(alpha, alpha, alpha)),
+            };
+            (texture, mode)
+        });
+
+        self.materials
+            .insert(material.index(), (Arc::clone(&mat), opacity.clone()));
+        Ok((mat, opacity))
+    }
+
+    // Decoded glTF image (8/16 bit or float, 1 to 4 channels) to an ImageTexture that
+    // wraps like the sampler of the texture, `map` gets the linear RGB of every pixel.
+    // Alpha is kept, 1 for images without it
+    fn image_texture(
+        &self,
+        texture: &gltf::Texture,
+        encoding: ColorEncoding,
+        map: impl Fn(Vec3) -> Vec3,
+    ) -> ImageTexture {
+        use gltf::image::Format;
+        use gltf::texture::WrappingMode;
+        let wrap_mode = |mode: WrappingMode| match mode {
+            WrappingMode::Repeat => WrapMode::Repeat,
+            WrappingMode::ClampToEdge => WrapMode::Clamp,
+            WrappingMode::MirroredRepeat => WrapMode::Mirror,
+        };
+        let sampler = texture.sampler();
+        let image = &self.images[texture.source().index()];
+        let (channels, bytes) = match image.format {
+            Format::R8 => (1, 1),
+            Format::R8G8 => (2, 1),
+            Format::R8G8B8 => (3, 1),
+            Format::R8G8B8A8 => (4, 1),
+            Format::R16 => (1, 2),
+            Format::R16G16 => (2, 2),
+            Format::R16G16B16 => (3, 2),
+            Format::R16G16B16A16 => (4, 2),
+            Format::R32G32B32FLOAT => (3, 4),
+            Format::R32G32B32A32FLOAT => (4, 4),
+        };
+        let decode = encoding == ColorEncoding::Srgb && bytes < 4;
+        let texel = |offset: usize| -> f64 {
+            let raw = &image.pixels[offset..offset + bytes];
+            match bytes {
+                1 => raw[0] as f64 / 255.0,
+                2 => u16::from_ne_bytes([raw[0], raw[1]]) as f64 / 65535.0,
+                _ => f32::from_ne_bytes([raw[0], raw[1], raw[2], raw[3]]) as f64,
+            }
+        };
+
+        let num_pixels = (image.width * image.height) as usize;
+        let mut texels = Vec::with_capacity(num_pixels);
+        for i in 0..num_pixels {
+            let pixel = i * channels * bytes;
+            // Gray images repeat the one channel, their second one is alpha
+            let channel = |c: usize| {
+                let value = texel(pixel + if channels < 3 { 0 } else { c * bytes });
+                if decode {
+                    srgb_to_linear(value)
+                } else {
+                    value
+                }
+            };
+            let color = map(Vec3::new(channel(0), channel(1), channel(2)));
+            let alpha = match channels {
+                2 => texel(pixel + bytes),
+                4 => texel(pixel + 3 * bytes),
+                _ => 1.0,
+            };
+            texels.push([color.x as f32, color.y as f32, color.z as f32, alpha as f32]);
+        }
+        ImageTexture::from_rgba(texels, image.width as usize, image.height as usize)
+            .with_wrap_uv(wrap_mode(sampler.wrap_s()), wrap_mode(sampler.wrap_t()))
+    }
+}
@@ -0,0 +1,3 @@
+pub mod gltf_scene;
+pub mod ply;
+pub mod volume;
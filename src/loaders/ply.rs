@@ -0,0 +1,455 @@
+/*
+ *  Stanford PLY reader (ASCII, binary little and big endian). Only the vertex and face
+ *  elements are kept, any other element is read and thrown away.
+ */
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::geometry::vector3::*;
+use crate::primitives::{mesh::*, triangle_mesh::*};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    List(String, Scalar, Scalar), // Name, type of the count, type of the items
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// Pulls the values of the body one at a time, whatever the encoding
+trait ValueReader {
+    fn read(&mut self, ty: Scalar) -> Result<f64, String>;
+}
+
+struct AsciiReader<'a> {
+    tokens: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl ValueReader for AsciiReader<'_> {
+    fn read(&mut self, _: Scalar) -> Result<f64, String> {
+        let token = self.tokens.next().ok_or("unexpected end of file")?;
+        token
+            .parse::<f64>()
+            .map_err(|_| format!("bad value '{token}'"))
+    }
+}
+
+struct BinaryReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+}
+
+impl<R: Read> ValueReader for BinaryReader<R> {
+    fn read(&mut self, ty: Scalar) -> Result<f64, String> {
+        let mut buf = [0u8; 8];
+        let bytes = &mut buf[..ty.size()];
+        self.reader
+            .read_exact(bytes)
+            .map_err(|_| "unexpected end of file".to_string())?;
+        if self.big_endian {
+            bytes.reverse();
+        }
+
+        Ok(match ty {
+            Scalar::I8 => i8::from_le_bytes(bytes[..1].try_into().unwrap()) as f64,
+            Scalar::U8 => u8::from_le_bytes(bytes[..1].try_into().unwrap()) as f64,
+            Scalar::I16 => i16::from_le_bytes(bytes[..2].try_into().unwrap()) as f64,
+            Scalar::U16 => u16::from_le_bytes(bytes[..2].try_into().unwrap()) as f64,
+            Scalar::I32 => i32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Scalar::U32 => u32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Scalar::F32 => f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Scalar::F64 => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+        })
+    }
+}
+
+pub struct PlyData {
+    pub positions: VertexBuffer,
+    pub normals: Option<VertexBuffer>,
+    pub uvs: Option<VertexBuffer>,
+    pub indices: Vec<u32>,
+}
+
+pub fn read_ply(file: &str, scaling: Vec3, precision: Precision) -> Result<PlyData, MeshError> {
+    let handle = File::open(file).map_err(|e| MeshError::Io(file.to_string(), e))?;
+    parse_ply(BufReader::new(handle), scaling, precision)
+        .map_err(|msg| MeshError::Parse(file.to_string(), msg))
+}
+
+fn parse_ply(
+    mut reader: impl BufRead,
+    scaling: Vec3,
+    precision: Precision,
+) -> Result<PlyData, String> {
+    let (format, elements) = read_header(&mut reader)?;
+
+    match format {
+        Format::Ascii => {
+            let mut body = String::new();
+            reader
+                .read_to_string(&mut body)
+                .map_err(|e| format!("unreadable body: {e}"))?;
+            let mut values = AsciiReader {
+                tokens: body.split_ascii_whitespace(),
+            };
+            read_body(&mut values, &elements, scaling, precision)
+        }
+        Format::BinaryLittleEndian | Format::BinaryBigEndian => {
+            let mut values = BinaryReader {
+                reader,
+                big_endian: format == Format::BinaryBigEndian,
+            };
+            read_body(&mut values, &elements, scaling, precision)
+        }
+    }
+}
+
+fn read_header(reader: &mut impl BufRead) -> Result<(Format, Vec<Element>), String> {
+    let mut next_line = || -> Result<String, String> {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => Err("header is not terminated".to_string()),
+            Ok(_) => Ok(line.trim().to_string()),
+        }
+    };
+
+    if next_line()? != "ply" {
+        return Err("missing the 'ply' magic number".to_string());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    loop {
+        let line = next_line()?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::BinaryLittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(Format::BinaryBigEndian),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("bad element count '{count}'"))?,
+                properties: vec![],
+            }),
+            ["property", "list", count_ty, item_ty, name] => {
+                let element = elements.last_mut().ok_or("property before any element")?;
+                let count_ty = Scalar::parse(count_ty).ok_or("unknown property type")?;
+                let item_ty = Scalar::parse(item_ty).ok_or("unknown property type")?;
+                element
+                    .properties
+                    .push(Property::List(name.to_string(), count_ty, item_ty));
+            }
+            ["property", ty, name] => {
+                let element = elements.last_mut().ok_or("property before any element")?;
+                let ty = Scalar::parse(ty).ok_or("unknown property type")?;
+                element
+                    .properties
+                    .push(Property::Scalar(name.to_string(), ty));
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(format!("unexpected header line '{line}'")),
+        }
+    }
+
+    let format = format.ok_or("missing the format line")?;
+    Ok((format, elements))
+}
+
+fn read_body(
+    values: &mut impl ValueReader,
+    elements: &[Element],
+    scaling: Vec3,
+    precision: Precision,
+) -> Result<PlyData, String> {
+    let num_vertices = elements
+        .iter()
+        .find(|e| e.name == "vertex")
+        .map_or(0, |e| e.count);
+    let mut data = PlyData {
+        positions: VertexBuffer::with_capacity(precision, num_vertices * 3),
+        normals: None,
+        uvs: None,
+        indices: vec![],
+    };
+
+    for element in elements {
+        match element.name.as_str() {
+            "vertex" => read_vertices(values, element, scaling, precision, &mut data)?,
+            "face" => read_faces(values, element, num_vertices, &mut data.indices)?,
+            _ => {
+                // Skip through it, there is no other way to find where it ends
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        read_property(values, property, |_| {})?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(data)
+}
+
+fn read_property(
+    values: &mut impl ValueReader,
+    property: &Property,
+    mut sink: impl FnMut(f64),
+) -> Result<(), String> {
+    match property {
+        Property::Scalar(_, ty) => sink(values.read(*ty)?),
+        Property::List(_, count_ty, item_ty) => {
+            let count = values.read(*count_ty)? as usize;
+            for _ in 0..count {
+                sink(values.read(*item_ty)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_vertices(
+    values: &mut impl ValueReader,
+    element: &Element,
+    scaling: Vec3,
+    precision: Precision,
+    data: &mut PlyData,
+) -> Result<(), String> {
+    let slot = |names: &[&str]| {
+        element
+            .properties
+            .iter()
+            .position(|p| matches!(p, Property::Scalar(name, _) if names.contains(&name.as_str())))
+    };
+    let position_slots = [slot(&["x"]), slot(&["y"]), slot(&["z"])];
+    let normal_slots = [slot(&["nx"]), slot(&["ny"]), slot(&["nz"])];
+    let uv_slots = [
+        slot(&["u", "s", "texture_u", "texture_s"]),
+        slot(&["v", "t", "texture_v", "texture_t"]),
+    ];
+
+    if position_slots.iter().any(|s| s.is_none()) {
+        return Err("vertices without x, y and z".to_string());
+    }
+    let has_normals = normal_slots.iter().all(|s| s.is_some());
+    let has_uvs = uv_slots.iter().all(|s| s.is_some());
+    if has_normals {
+        data.normals = Some(VertexBuffer::with_capacity(precision, element.count * 3));
+    }
+    if has_uvs {
+        data.uvs = Some(VertexBuffer::with_capacity(precision, element.count * 2));
+    }
+
+    let mut row = vec![0.0; element.properties.len()];
+    for _ in 0..element.count {
+        for (i, property) in element.properties.iter().enumerate() {
+            read_property(values, property, |v| row[i] = v)?;
+        }
+        let get = |s: Option<usize>| row[s.unwrap()];
+
+        let p = Vec3::new(
+            get(position_slots[0]),
+            get(position_slots[1]),
+            get(position_slots[2]),
+        ) * scaling;
+        data.positions.push(p.x);
+        data.positions.push(p.y);
+        data.positions.push(p.z);
+
+        if let Some(normals) = &mut data.normals {
+            // Normals transform with the inverse of the (non-uniform) scaling
+            let n = Vec3::new(
+                get(normal_slots[0]) / scaling.x,
+                get(normal_slots[1]) / scaling.y,
+                get(normal_slots[2]) / scaling.z,
+            );
+            let n = if n.lenght_squared() > 0.0 {
+                Vec3::unit_vector(n)
+            } else {
+                n
+            };
+            normals.push(n.x);
+            normals.push(n.y);
+            normals.push(n.z);
+        }
+        if let Some(uvs) = &mut data.uvs {
+            uvs.push(get(uv_slots[0]));
+            uvs.push(get(uv_slots[1]));
+        }
+    }
+    Ok(())
+}
+
+fn read_faces(
+    values: &mut impl ValueReader,
+    element: &Element,
+    num_vertices: usize,
+    indices: &mut Vec<u32>,
+) -> Result<(), String> {
+    let is_index_list = |name: &str| name == "vertex_indices" || name == "vertex_index";
+    let mut polygon: Vec<u32> = Vec::with_capacity(4);
+
+    for _ in 0..element.count {
+        for property in &element.properties {
+            match property {
+                Property::List(name, _, _) if is_index_list(name) => {
+                    polygon.clear();
+                    read_property(values, property, |v| polygon.push(v as u32))?;
+                    if polygon.iter().any(|&i| i as usize >= num_vertices) {
+                        return Err("face index out of bounds".to_string());
+                    }
+                    // Fan triangulation, fine for the convex polygons scanners write
+                    for k in 1..polygon.len().saturating_sub(1) {
+                        indices.extend_from_slice(&[polygon[0], polygon[k], polygon[k + 1]]);
+                    }
+                }
+                _ => read_property(values, property, |_| {})?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    fn parse(bytes: &[u8]) -> PlyData {
+        parse_ply(bytes, Vec3::new(1.0, 1.0, 1.0), Precision::Double).unwrap()
+    }
+
+    fn binary(format: &str, to_bytes: fn(f32) -> [u8; 4], index: fn(i32) -> [u8; 4]) -> Vec<u8> {
+        let mut bytes = format!("ply\nformat {format} 1.0\n{HEADER}").into_bytes();
+        for value in [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 0.0] {
+            bytes.extend(to_bytes(value));
+        }
+        bytes.push(3);
+        for i in [0, 1, 2] {
+            bytes.extend(index(i));
+        }
+        bytes
+    }
+
+    fn assert_vec(a: Vec3, b: Vec3) {
+        assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z));
+    }
+
+    fn assert_triangle(ply: &PlyData) {
+        assert_eq!(ply.positions.len(), 9);
+        assert_vec(ply.positions.vec3(1), Vec3::new(1.0, 0.0, 0.0));
+        assert_vec(ply.positions.vec3(2), Vec3::new(0.0, 2.0, 0.0));
+        assert_eq!(ply.indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn ascii() {
+        let text = format!(
+            "ply\nformat ascii 1.0\ncomment a triangle\n{HEADER}0 0 0\n1 0 0\n0 2 0\n3 0 1 2\n"
+        );
+        assert_triangle(&parse(text.as_bytes()));
+    }
+
+    #[test]
+    fn binary_little_endian() {
+        let bytes = binary("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes);
+        assert_triangle(&parse(&bytes));
+    }
+
+    #[test]
+    fn binary_big_endian() {
+        let bytes = binary("binary_big_endian", f32::to_be_bytes, i32::to_be_bytes);
+        assert_triangle(&parse(&bytes));
+    }
+
+    #[test]
+    fn quad_is_fan_triangulated() {
+        let text = "ply
+format ascii 1.0
+element vertex 4
+property double x
+property double y
+property double z
+property double nx
+property double ny
+property double nz
+element face 1
+property list uchar uint vertex_indices
+end_header
+0 0 0 0 0 2
+1 0 0 0 0 2
+1 1 0 0 0 2
+0 1 0 0 0 2
+4 0 1 2 3
+";
+        let ply = parse(text.as_bytes());
+        assert_eq!(ply.indices, vec![0, 1, 2, 0, 2, 3]);
+        // Normals come out normalized
+        let normals = ply.normals.unwrap();
+        assert_vec(normals.vec3(3), Vec3::new(0.0, 0.0, 1.0));
+        assert!(ply.uvs.is_none());
+    }
+
+    #[test]
+    fn out_of_bounds_index() {
+        let text = format!("ply\nformat ascii 1.0\n{HEADER}0 0 0\n1 0 0\n0 2 0\n3 0 1 3\n");
+        let result = parse_ply(text.as_bytes(), Vec3::new(1.0, 1.0, 1.0), Precision::Double);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,295 @@
+/*
+ *  Dense volume grids, e.g. density caches exported from a smoke simulation. Two formats
+ *  are read, picked by the extension:
+ *
+ *  .vol   Mitsuba's binary grid: "VOL", version 3, the encoding (1 float32, 2 float16,
+ *         3 uint8 over 0..1), the resolution in x, y and z and the channel count as
+ *         little endian i32, the bounds as six f32 (min then max), then the values with
+ *         x fastest then y then z and the channels of a voxel next to each other.
+ *  other  Text, the resolution "nx ny nz", the bounds "xmin ymin zmin xmax ymax zmax"
+ *         and nx * ny * nz values in the same order, all separated by whitespace. Lines
+ *         starting with '#' are skipped.
+ *
+ *  Only the first channel of a voxel is kept. Sparse grids (OpenVDB) have to be written
+ *  out dense first.
+ */
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::accelerators::aabb::*;
+use crate::geometry::vector3::*;
+
+#[derive(Debug)]
+pub enum VolumeError {
+    Io(String, std::io::Error), // Couldn't read the file
+    Parse(String, String),      // The file is malformed or uses an unsupported feature
+}
+
+impl fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VolumeError::Io(file, e) => write!(f, "failed to read {file}: {e}"),
+            VolumeError::Parse(file, e) => write!(f, "failed to parse {file}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for VolumeError {}
+
+// Resolution, bounds and the values of a grid
+pub struct VolumeData {
+    pub resolution: [usize; 3],
+    pub bounds: Aabb,
+    pub values: Vec<f32>,
+}
+
+pub fn read_volume(file: &str) -> Result<VolumeData, VolumeError> {
+    let bytes = fs::read(file).map_err(|e| VolumeError::Io(file.to_string(), e))?;
+    let is_vol = Path::new(file)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("vol"));
+    parse_volume(bytes, is_vol).map_err(|msg| VolumeError::Parse(file.to_string(), msg))
+}
+
+fn parse_volume(bytes: Vec<u8>, is_vol: bool) -> Result<VolumeData, String> {
+    let data = if is_vol {
+        read_vol(&bytes)?
+    } else {
+        let text = String::from_utf8(bytes).map_err(|_| "not a text grid".to_string())?;
+        read_text(&text)?
+    };
+    let (min, max) = (data.bounds.minimum, data.bounds.maximum);
+    if !(0..3).all(|a| min[a].is_finite() && max[a].is_finite() && min[a] < max[a]) {
+        return Err(format!("bad bounds {min:?} to {max:?}"));
+    }
+    // An infinite density would make the majorant infinite and the tracking never end
+    if let Some(v) = data.values.iter().find(|v| !v.is_finite()) {
+        return Err(format!("non-finite value {v}"));
+    }
+    Ok(data)
+}
+
+// Number of voxels of a resolution read from a file, which may be anything
+fn voxel_count(resolution: [i64; 3]) -> Result<usize, String> {
+    if resolution.iter().any(|&n| n <= 0) {
+        return Err(format!("bad resolution {resolution:?}"));
+    }
+    resolution
+        .iter()
+        .try_fold(1usize, |count, &n| count.checked_mul(n as usize))
+        .ok_or_else(|| format!("resolution {resolution:?} is too large"))
+}
+
+fn read_vol(bytes: &[u8]) -> Result<VolumeData, String> {
+    const HEADER: usize = 48;
+    if bytes.len() < HEADER || &bytes[0..3] != b"VOL" {
+        return Err("not a VOL file".to_string());
+    }
+    if bytes[3] != 3 {
+        return Err(format!("unsupported VOL version {}", bytes[3]));
+    }
+    let word = |i: usize| -> [u8; 4] { bytes[4 + 4 * i..8 + 4 * i].try_into().unwrap() };
+    let int = |i: usize| i32::from_le_bytes(word(i)) as i64;
+    let float = |i: usize| f32::from_le_bytes(word(i)) as f64;
+
+    let encoding = int(0);
+    let resolution = [int(1), int(2), int(3)];
+    let channels = int(4);
+    let bounds = Aabb::new(
+        Vec3::new(float(5), float(6), float(7)),
+        Vec3::new(float(8), float(9), float(10)),
+    );
+    if channels <= 0 {
+        return Err(format!("bad channel count {channels}"));
+    }
+    let channels = channels as usize;
+
+    let value_size = match encoding {
+        1 => 4,
+        2 => 2,
+        3 => 1,
+        _ => return Err(format!("unsupported VOL encoding {encoding}")),
+    };
+    let count = voxel_count(resolution)?;
+    let body = &bytes[HEADER..];
+    if count
+        .checked_mul(channels * value_size)
+        .is_none_or(|size| body.len() < size)
+    {
+        return Err("file is shorter than its resolution".to_string());
+    }
+
+    let values = (0..count)
+        .map(|i| {
+            let at = i * channels * value_size;
+            let v = &body[at..at + value_size];
+            match encoding {
+                1 => f32::from_le_bytes([v[0], v[1], v[2], v[3]]),
+                2 => f16_to_f32(u16::from_le_bytes([v[0], v[1]])),
+                _ => v[0] as f32 / 255.0,
+            }
+        })
+        .collect();
+    Ok(VolumeData {
+        resolution: resolution.map(|n| n as usize),
+        bounds,
+        values,
+    })
+}
+
+// IEEE half precision to single, subnormals, infinities and NaN included
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+fn read_text(text: &str) -> Result<VolumeData, String> {
+    let mut tokens = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(str::split_ascii_whitespace);
+    let mut next = |what: &str| -> Result<f64, String> {
+        let token = tokens.next().ok_or(format!("missing {what}"))?;
+        token
+            .parse::<f64>()
+            .map_err(|_| format!("bad {what} '{token}'"))
+    };
+
+    let mut resolution = [0; 3];
+    for n in resolution.iter_mut() {
+        let value = next("resolution")?;
+        if value.fract() != 0.0 {
+            return Err(format!("bad resolution {value}"));
+        }
+        *n = value as i64;
+    }
+    let count = voxel_count(resolution)?;
+    let mut corners = [0.0; 6];
+    for c in corners.iter_mut() {
+        *c = next("bounds")?;
+    }
+    let bounds = Aabb::new(
+        Vec3::new(corners[0], corners[1], corners[2]),
+        Vec3::new(corners[3], corners[4], corners[5]),
+    );
+    let values = (0..count)
+        .map(|_| next("value").map(|v| v as f32))
+        .collect::<Result<Vec<f32>, String>>()?;
+    Ok(VolumeData {
+        resolution: resolution.map(|n| n as usize),
+        bounds,
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A .vol file over the unit cube, the body given as raw bytes
+    fn vol(encoding: i32, resolution: [i32; 3], channels: i32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VOL\x03".to_vec();
+        for n in [
+            encoding,
+            resolution[0],
+            resolution[1],
+            resolution[2],
+            channels,
+        ] {
+            bytes.extend(n.to_le_bytes());
+        }
+        for c in [0.0f32, 0.0, 0.0, 1.0, 2.0, 3.0] {
+            bytes.extend(c.to_le_bytes());
+        }
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn vol_header() {
+        // Two voxels of two channels, only the first channel is kept
+        let body: Vec<u8> = [0.5f32, 9.0, 0.25, 9.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let data = parse_volume(vol(1, [2, 1, 1], 2, &body), true).unwrap();
+        assert_eq!(data.resolution, [2, 1, 1]);
+        let (min, max) = (data.bounds.minimum, data.bounds.maximum);
+        assert_eq!((min.x, min.y, min.z), (0.0, 0.0, 0.0));
+        assert_eq!((max.x, max.y, max.z), (1.0, 2.0, 3.0));
+        assert_eq!(data.values, vec![0.5, 0.25]);
+
+        let mut wrong_version = vol(1, [2, 1, 1], 2, &body);
+        wrong_version[3] = 2;
+        assert!(parse_volume(wrong_version, true).is_err());
+        assert!(parse_volume(vol(4, [2, 1, 1], 2, &body), true).is_err());
+    }
+
+    #[test]
+    fn f16_decode() {
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x3555), 0.333_251_95);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+
+        let body: Vec<u8> = [0x3800u16, 0x3400]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let data = parse_volume(vol(2, [1, 2, 1], 1, &body), true).unwrap();
+        assert_eq!(data.values, vec![0.5, 0.25]);
+
+        // Infinite and NaN densities are rejected
+        for bad in [0x7c00u16, 0x7e00] {
+            let body: Vec<u8> = [0x3800u16, bad]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            assert!(parse_volume(vol(2, [1, 2, 1], 1, &body), true).is_err());
+        }
+    }
+
+    #[test]
+    fn short_file() {
+        // Eight uint8 voxels promised, seven given
+        let Err(err) = parse_volume(vol(3, [2, 2, 2], 1, &[0; 7]), true) else {
+            panic!("a short file was read");
+        };
+        assert!(err.contains("shorter"), "{err}");
+        assert!(parse_volume(vol(3, [2, 2, 2], 1, &[255; 8]), true).is_ok());
+        assert!(parse_volume(b"VOL\x03".to_vec(), true).is_err());
+    }
+
+    #[test]
+    fn text_format() {
+        let text = "# a comment\n2 1 1\n-1 -1 -1 1 1 1\n  # another\n0.5\n0.25\n";
+        let data = parse_volume(text.as_bytes().to_vec(), false).unwrap();
+        assert_eq!(data.resolution, [2, 1, 1]);
+        assert_eq!(data.bounds.minimum.x, -1.0);
+        assert_eq!(data.values, vec![0.5, 0.25]);
+
+        for bad in [
+            "2 1 1\n0 0 0 1 1 1\n0.5",        // A value short
+            "2 1 1\n0 0 0 1 1 1\n0.5 inf",    // Infinite density
+            "2 1 1\n0 0 0 1 1 1\n0.5 NaN",    // Not a density
+            "2 1 1\n0 0 0 0 1 1\n0.5 0.25",   // Flat bounds
+            "2 1 1\n0 0 0 inf 1 1\n0.5 0.25", // Unbounded
+            "2.5 1 1\n0 0 0 1 1 1\n0.5 0.25", // Fractional resolution
+        ] {
+            assert!(
+                parse_volume(bad.as_bytes().to_vec(), false).is_err(),
+                "{bad}"
+            );
+        }
+    }
+}
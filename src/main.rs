@@ -1,6 +1,9 @@
+use std::sync::Arc;
+
 use crate::accelerators::bvh::*;
 use crate::core::{camera::*, hittable_list::*, scene::*};
 use crate::geometry::vector3::*;
+use crate::lights::{environment::*, light::*, sky::*};
 
 // General Todo's to implement
 // TODO: (1) A struct for meshes with material
@@ -17,9 +20,15 @@ mod accelerators;
 mod constant_medium;
 mod core;
 mod geometry;
+mod heterogeneous_medium;
+mod lights;
+mod loaders;
 mod material;
+mod microfacet;
+mod noise;
 mod perlin;
 mod primitives;
+mod sampling;
 mod texture;
 mod utils;
 
@@ -38,73 +47,266 @@ fn main() {
     const SAMPLES_PER_PIXEL: u64 = 500;
     const DEPTH: u64 = 50;
     // World
-    let world;
+    let mut world;
 
     let lookfrom: Vec3;
     let lookat: Vec3;
     let vfov: f64;
     let mut _aperture = 0.0;
-    let background;
+    let background: Arc<dyn Background>;
 
     // Select World to Render
     // TODO: Can move this to Scene.rs
     let scene_id: u8 = 6;
     let mut items: HittableList;
+    let mut lights = LightList::default();
     match scene_id {
         0 => {
             items = Scene::checker_world();
-            background = Vec3::new(0.70, 0.80, 1.00);
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
             lookfrom = Vec3::new(13.0, 2.0, 3.0);
             lookat = Vec3::new(0.0, 0.0, 0.0);
             vfov = 20.0;
         }
         1 => {
             items = Scene::two_perlin_spheres();
-            background = Vec3::new(0.70, 0.80, 1.00);
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
             lookfrom = Vec3::new(13.0, 2.0, 3.0);
             lookat = Vec3::new(0.0, 0.0, 0.0);
             vfov = 20.0;
         }
         2 => {
             items = Scene::earth_scene();
-            background = Vec3::new(0.70, 0.80, 1.00);
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
             lookfrom = Vec3::new(13.0, 2.0, 3.0);
             lookat = Vec3::new(0.0, 0.0, 0.0);
             vfov = 20.0;
         }
         3 => {
             items = Scene::simple_light();
-            background = Vec3::new(0.0, 0.0, 0.0);
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
             lookfrom = Vec3::new(26.0, 3.0, 6.0);
             lookat = Vec3::new(0.0, 2.0, 0.0);
             vfov = 20.0;
         }
         4 => {
-            items = Scene::cornell_box();
-            background = Vec3::new(0.0, 0.0, 0.0);
+            (items, lights) = Scene::cornell_box();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
             lookfrom = Vec3::new(278.0, 278.0, -800.0);
             lookat = Vec3::new(278.0, 278.0, 0.0);
             vfov = 40.0;
         }
         5 => {
-            items = Scene::cornell_with_gas();
-            background = Vec3::new(0.0, 0.0, 0.0);
+            (items, lights) = Scene::cornell_with_gas();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
             lookfrom = Vec3::new(278.0, 278.0, -800.0);
             lookat = Vec3::new(278.0, 278.0, 0.0);
             vfov = 40.0;
         }
         6 => {
-            items = Scene::cornell_with_mesh(false);
-            background = Vec3::new(0.0, 0.0, 0.0);
+            (items, lights) = Scene::cornell_with_mesh(false);
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = Vec3::new(278.0, 278.0, -800.0);
+            lookat = Vec3::new(278.0, 278.0, 0.0);
+            vfov = 40.0;
+        }
+        7 => {
+            items = Scene::principled_spheres();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 4.0, 20.0);
+            lookat = Vec3::new(0.0, 0.8, 0.0);
+            vfov = 35.0;
+        }
+        8 => {
+            (items, lights) = Scene::lamp_lit_spheres();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = Vec3::new(0.0, 3.0, 12.0);
+            lookat = Vec3::new(0.0, 1.0, 0.0);
+            vfov = 35.0;
+        }
+        9 => {
+            items = Scene::principled_spheres();
+            // Any equirectangular panorama, .hdr or .exr for real lighting
+            match EnvironmentMap::from_file("static/environment.hdr", 0.0, 1.0) {
+                Ok(environment) => {
+                    let environment = Arc::new(environment);
+                    lights.add(environment.clone());
+                    background = environment;
+                }
+                Err(e) => {
+                    eprintln!("Couldn't load the environment map, using a gradient sky: {e}");
+                    background = Arc::new(GradientSky::new(
+                        Vec3::new(0.5, 0.7, 1.0),
+                        Vec3::new(1.0, 1.0, 1.0),
+                    ));
+                }
+            }
+            lookfrom = Vec3::new(0.0, 4.0, 20.0);
+            lookat = Vec3::new(0.0, 0.8, 0.0);
+            vfov = 35.0;
+        }
+        10 => {
+            items = Scene::principled_spheres();
+            // Late afternoon sun over the left shoulder of the camera
+            let sky = Arc::new(PreethamSky::new(30.0, 60.0, 3.0, 0.04));
+            lights.add(sky.clone());
+            background = sky;
+            lookfrom = Vec3::new(0.0, 4.0, 20.0);
+            lookat = Vec3::new(0.0, 0.8, 0.0);
+            vfov = 35.0;
+        }
+        11 => {
+            items = Scene::procedural_textures();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 3.0, 12.0);
+            lookat = Vec3::new(0.0, 0.8, 0.0);
+            vfov = 35.0;
+        }
+        12 => {
+            (items, lights) = Scene::cornell_with_smoke();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = Vec3::new(278.0, 278.0, -800.0);
+            lookat = Vec3::new(278.0, 278.0, 0.0);
+            vfov = 40.0;
+        }
+        13 => {
+            (items, lights) = Scene::cornell_with_fog();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = Vec3::new(278.0, 278.0, -800.0);
+            lookat = Vec3::new(278.0, 278.0, 0.0);
+            vfov = 40.0;
+        }
+        14 => {
+            (items, lights) = Scene::obj_still_life();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = Vec3::new(0.0, 3.0, 9.0);
+            lookat = Vec3::new(0.0, 0.8, 0.0);
+            vfov = 40.0;
+        }
+        15 => {
+            items = Scene::ply_torus();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 3.0, 6.0);
+            lookat = Vec3::new(0.0, 0.4, 0.0);
+            vfov = 35.0;
+        }
+        16 => {
+            items = Scene::stl_gem();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 3.0, 6.0);
+            lookat = Vec3::new(0.0, 1.0, 0.0);
+            vfov = 30.0;
+        }
+        17 => {
+            let room = Scene::gltf_room();
+            items = room.world;
+            lights = room.lights;
+            // Looks through the camera of the file
+            let view = room.cameras.first().expect("The room has no camera");
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = view.lookfrom;
+            lookat = view.lookat;
+            vfov = view.vfov;
+        }
+        18 => {
+            items = Scene::grazing_floor();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 1.5, -1.0);
+            lookat = Vec3::new(0.0, 0.5, -20.0);
+            vfov = 50.0;
+        }
+        19 => {
+            items = Scene::texture_operators();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 3.0, 14.0);
+            lookat = Vec3::new(0.0, 0.8, 0.0);
+            vfov = 35.0;
+        }
+        20 => {
+            let garden = Scene::foliage();
+            items = garden.world;
+            lights = garden.lights;
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 1.6, 5.0);
+            lookat = Vec3::new(0.0, 0.7, 0.0);
+            vfov = 45.0;
+        }
+        21 => {
+            (items, lights) = Scene::cornell_with_plume();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
             lookfrom = Vec3::new(278.0, 278.0, -800.0);
             lookat = Vec3::new(278.0, 278.0, 0.0);
             vfov = 40.0;
         }
+        22 => {
+            (items, lights) = Scene::bumpy_spheres();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 2.5, 11.0);
+            lookat = Vec3::new(0.0, 0.9, 0.0);
+            vfov = 35.0;
+        }
+        23 => {
+            (items, lights) = Scene::cloud_layer();
+            background = Arc::new(GradientSky::new(
+                Vec3::new(0.5, 0.7, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
+            lookfrom = Vec3::new(0.0, 0.5, 8.0);
+            lookat = Vec3::new(0.0, 3.5, -2.0);
+            vfov = 60.0;
+        }
+        24 => {
+            (items, lights) = Scene::spot_panels();
+            background = Arc::new(Vec3::new(0.0, 0.0, 0.0));
+            lookfrom = Vec3::new(0.0, 2.5, 12.0);
+            lookat = Vec3::new(0.0, 1.5, 0.0);
+            vfov = 40.0;
+        }
         _ => panic!["Unimplemented scene code!"],
     }
 
+    // Unbounded objects like a global fog can't go in the BVH, they are tested next to it
+    let (unbounded, bounded) = items
+        .objects
+        .into_iter()
+        .partition(|object| object.bounding_box(0.0, 0.0).is_none());
+    items = HittableList::from(bounded);
     let list_len = items.objects.len();
-    world = Bvh::new(&mut items, 0, list_len, 0.0, 0.0);
+    world = HittableList::from(unbounded);
+    world.add(Arc::new(Bvh::new(&mut items, 0, list_len, 0.0, 0.0)));
     // Initialize the camera
     let camera = Camera::init(
         ASPECT_RATIO,
@@ -116,5 +318,5 @@ fn main() {
         lookat,
     );
 
-    camera.render(&world, background);
+    camera.render(&world, &lights, background.as_ref());
 }
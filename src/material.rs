@@ -1,3 +1,4 @@
+use rand::Rng;
 use std::f64::consts::PI;
 use std::sync::Arc;
 
@@ -5,6 +6,7 @@ use crate::core::hittable::*;
 use crate::geometry::onb::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
+use crate::microfacet::*;
 use crate::{texture::*, utils::*};
 
 // Light reflection/refraction related utilities
@@ -25,18 +27,44 @@ fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
 
+/*
+ *  The integrator weights a scattered ray with attenuation * scattering_pdf / pdf.
+ *  For the diffuse materials attenuation is the albedo and scattering_pdf the cosine
+ *  lobe. Materials whose BSDF doesn't split that way (microfacets) return the whole
+ *  f * cos / pdf weight as attenuation and their sampling density as scattering_pdf.
+ */
+pub struct ScatterRecord {
+    pub ray: Ray,          // Scattered ray
+    pub attenuation: Vec3, // Color
+    pub pdf: f64,          // PDF the scattered direction was sampled with
+    pub skip_pdf: bool,    // Specular, follow the ray without any PDF weighting
+}
+
 pub trait Material {
     // Returns Scattered Ray, Color and PDF
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<(Ray, Vec3, f64)>;
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord>;
 
     // As default objects shouldn't emit light
-    fn emitted(&self, _: f64, _: f64, _: Vec3) -> Vec3 {
+    fn emitted(&self, _r_in: Ray, _hit: &HitRecord) -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
 
+    // Surfaces that are worth turning into lights for the light sampling
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
     fn scattering_pdf(&self, _r_in: Ray, _hit: &HitRecord, _scattered: Ray) -> f64 {
         0.0
     }
+
+    // BSDF times the cosine for any pair of directions, zero for the specular materials
+    fn eval(&self, _r_in: Ray, _hit: &HitRecord, _scattered: Ray) -> Vec3 {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    // Bends the shading normal of a hit before it is shaded, for normal and bump maps
+    fn perturb_normal(&self, _hit: &mut HitRecord) {}
 }
 
 #[derive(Clone)]
@@ -59,109 +87,1089 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _r_in: Ray, hit: &HitRecord) -> Option<(Ray, Vec3, f64)> {
+    fn scatter(&self, _r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
         let uvw = Onb::build_from_w(hit.normal);
         let scatter_dir = uvw.local(random_cosine_direction());
 
-        let scattered = Ray::new(hit.p, Vec3::unit_vector(scatter_dir));
-        let albedo = self.albedo.value(hit.u, hit.v, hit.p);
+        // Leaving through the back of the real surface
+        if scatter_dir.dot(hit.geometric_normal) <= 0.0 {
+            return None;
+        }
+        let scattered = hit.spawn_ray(Vec3::unit_vector(scatter_dir));
+        let albedo = self.albedo.value_at(hit);
         let pdf = uvw.w().dot(scattered.dir) / PI;
-        Some((scattered, albedo, pdf))
+        Some(ScatterRecord {
+            ray: scattered,
+            attenuation: albedo,
+            pdf,
+            skip_pdf: false,
+        })
     }
     fn scattering_pdf(&self, _r_in: Ray, hit: &HitRecord, scattered: Ray) -> f64 {
-        let cos_theta = hit.normal.dot(Vec3::unit_vector(scattered.dir));
-        if cos_theta < 0.0 {
+        let dir = Vec3::unit_vector(scattered.dir);
+        let cos_theta = hit.normal.dot(dir);
+        if cos_theta < 0.0 || dir.dot(hit.geometric_normal) <= 0.0 {
             0.0
         } else {
             cos_theta / PI
         }
     }
+    fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+        self.albedo.value_at(hit) * self.scattering_pdf(r_in, hit, scattered)
+    }
 }
 
-/*
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Metal {
-    pub albedo: Vec3,
+    pub albedo: Arc<dyn Texture + Send + Sync>,
     pub fuzz: f64,
 }
+
 impl Metal {
+    pub fn from_color(c: Vec3, f: f64) -> Self {
+        Self {
+            albedo: Arc::new(SolidColor::from_color(c)),
+            fuzz: if f < 1.0 { f } else { 1.0 },
+        }
+    }
     #[allow(dead_code)]
-    pub fn new(a: Vec3, f: f64) -> Self {
+    pub fn from_texture(tex: Arc<dyn Texture + Send + Sync>, f: f64) -> Self {
         Self {
-            albedo: a,
+            albedo: Arc::clone(&tex),
             fuzz: if f < 1.0 { f } else { 1.0 },
         }
     }
 }
 
 impl Material for Metal {
-    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<(Ray, Vec3)> {
-        let reflected =
-            reflect(Vec3::unit_vector(r_in.dir), hit.normal) + random_in_unit_sphere() * self.fuzz;
-        let scattered = Ray::new(hit.p, reflected);
-        let attenuation = self.albedo;
-
-        if reflected.dot(hit.normal) > 0.0 {
-            Some((scattered, attenuation))
-        } else {
-            None
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let reflected = Vec3::unit_vector(reflect(Vec3::unit_vector(r_in.dir), hit.normal))
+            + random_in_unit_sphere() * self.fuzz;
+
+        // Fuzzed below the surface, absorb it
+        if reflected.dot(hit.normal) <= 0.0 {
+            return None;
         }
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(reflected),
+            attenuation: self.albedo.value_at(hit),
+            pdf: 1.0,
+            skip_pdf: true,
+        })
     }
 }
 
 #[derive(Clone, Copy)]
 pub struct Dielectric {
-    pub ir: f64,
+    pub ir: f64, // Index of refraction
+}
+
+impl Dielectric {
+    pub fn new(ir: f64) -> Self {
+        Self { ir }
+    }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray: Ray, hit: &HitRecord) -> Option<(Ray, Vec3)> {
-        let attenuation = Vec3::new(1.0, 1.0, 1.0);
-        let (outward_normal, refraction_ratio, cos_theta) = if ray.dir.dot(hit.normal) > 0.0 {
-            let cos_theta = self.ir * ray.dir.dot(hit.normal) / ray.dir.length();
-            (-hit.normal, self.ir, cos_theta)
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let refraction_ratio = if hit.front_face {
+            1.0 / self.ir
         } else {
-            let cos_theta = -ray.dir.dot(hit.normal) / ray.dir.length();
-            (hit.normal, 1.0 / self.ir, cos_theta)
+            self.ir
         };
-        let unit_dir = Vec3::unit_vector(ray.dir);
+
+        let unit_dir = Vec3::unit_vector(r_in.dir);
+        let cos_theta = f64::min(-unit_dir.dot(hit.normal), 1.0);
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
 
-        let direction = if cannot_refract
-            || reflectance(cos_theta, refraction_ratio) > rand::thread_rng().gen::<f64>()
-        {
-            reflect(unit_dir, outward_normal)
+        let direction =
+            if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f64>() {
+                reflect(unit_dir, hit.normal)
+            } else {
+                refract(unit_dir, hit.normal, refraction_ratio)
+            };
+
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(direction),
+            attenuation: Vec3::new(1.0, 1.0, 1.0),
+            pdf: 1.0,
+            skip_pdf: true,
+        })
+    }
+}
+
+/*
+ *  glTF style metallic-roughness surface: a GGX specular lobe over a Lambertian base.
+ *  Metallic blends from a dielectric (F0 = 0.04, the base color is the diffuse) to a
+ *  conductor (the base color is F0, no diffuse at all). Metallic and roughness are read
+ *  from the first channel of their textures.
+ */
+pub struct MetallicRoughness {
+    pub base_color: Arc<dyn Texture + Send + Sync>,
+    pub metallic: Arc<dyn Texture + Send + Sync>,
+    pub roughness: Arc<dyn Texture + Send + Sync>,
+    pub conductor: Option<(Vec3, Vec3)>, // Complex IOR (eta, k), replaces Schlick for the metal
+}
+
+// The surface at one hit point, directions are in the shading frame
+struct SurfaceLobes {
+    uvw: Onb,
+    wo: Vec3,
+    base_color: Vec3,
+    metallic: f64,
+    distribution: TrowbridgeReitz,
+}
+
+impl MetallicRoughness {
+    pub fn new(base_color: Vec3, metallic: f64, roughness: f64) -> Self {
+        Self::from_textures(
+            Arc::new(SolidColor::from_color(base_color)),
+            Arc::new(SolidColor::from_rgb(metallic, metallic, metallic)),
+            Arc::new(SolidColor::from_rgb(roughness, roughness, roughness)),
+        )
+    }
+
+    pub fn from_textures(
+        base_color: Arc<dyn Texture + Send + Sync>,
+        metallic: Arc<dyn Texture + Send + Sync>,
+        roughness: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        Self {
+            base_color,
+            metallic,
+            roughness,
+            conductor: None,
+        }
+    }
+
+    // Measured metal, e.g. gold is eta (0.143, 0.374, 1.442) and k (3.983, 2.385, 1.603)
+    pub fn conductor(eta: Vec3, k: Vec3, roughness: f64) -> Self {
+        let mut mat = Self::new(Vec3::new(1.0, 1.0, 1.0), 1.0, roughness);
+        mat.conductor = Some((eta, k));
+        mat
+    }
+
+    fn lobes(&self, r_in: Ray, hit: &HitRecord) -> Option<SurfaceLobes> {
+        let uvw = Onb::build_from_w(hit.normal);
+        let wo = uvw.to_local(-Vec3::unit_vector(r_in.dir));
+        // Interpolated normals can face away from the viewer at grazing angles
+        if wo.z <= 0.0 {
+            return None;
+        }
+        Some(SurfaceLobes {
+            uvw,
+            wo,
+            base_color: self.base_color.value_at(hit),
+            metallic: self.metallic.value_at(hit).x.clamp(0.0, 1.0),
+            distribution: TrowbridgeReitz::from_roughness(self.roughness.value_at(hit).x),
+        })
+    }
+
+    fn fresnel(&self, lobes: &SurfaceLobes, cos_theta: f64) -> Vec3 {
+        let dielectric = fresnel_schlick(Vec3::new(0.04, 0.04, 0.04), cos_theta);
+        let metal = match self.conductor {
+            Some((eta, k)) => fresnel_conductor(cos_theta, eta, k),
+            None => fresnel_schlick(lobes.base_color, cos_theta),
+        };
+        dielectric * (1.0 - lobes.metallic) + metal * lobes.metallic
+    }
+
+    // Chance of sampling the specular lobe, by how much each lobe reflects towards wo
+    fn specular_probability(&self, lobes: &SurfaceLobes) -> f64 {
+        let specular = luminance(self.fresnel(lobes, lobes.wo.z));
+        let diffuse = (1.0 - lobes.metallic)
+            * luminance(lobes.base_color)
+            * (1.0 - fresnel_schlick(Vec3::new(0.04, 0.04, 0.04), lobes.wo.z).x);
+        if specular + diffuse <= 0.0 {
+            1.0
+        } else {
+            specular / (specular + diffuse)
+        }
+    }
+
+    // f * cos for the direction wi
+    fn eval_local(&self, lobes: &SurfaceLobes, wi: Vec3) -> Vec3 {
+        let wo = lobes.wo;
+        let wm = wo + wi;
+        if wi.z <= 0.0 || wm.near_zero() {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let wm = Vec3::unit_vector(wm);
+        let cos_h = wo.dot(wm);
+
+        let d = lobes.distribution.d(wm);
+        let g = lobes.distribution.g(wo, wi);
+        let specular = self.fresnel(lobes, cos_h) * (d * g / (4.0 * wo.z * wi.z));
+        // Whatever the dielectric coat doesn't reflect reaches the diffuse base
+        let transmitted = 1.0 - fresnel_schlick(Vec3::new(0.04, 0.04, 0.04), cos_h).x;
+        let diffuse = lobes.base_color * ((1.0 - lobes.metallic) * transmitted / PI);
+
+        (specular + diffuse) * wi.z
+    }
+
+    fn pdf_local(&self, lobes: &SurfaceLobes, wi: Vec3) -> f64 {
+        let wo = lobes.wo;
+        let wm = wo + wi;
+        if wi.z <= 0.0 || wm.near_zero() {
+            return 0.0;
+        }
+        let wm = Vec3::unit_vector(wm);
+        let cos_h = wo.dot(wm);
+        let specular = if cos_h > 0.0 {
+            lobes.distribution.visible_d(wo, wm) / (4.0 * cos_h)
+        } else {
+            0.0
+        };
+        let p = self.specular_probability(lobes);
+        p * specular + (1.0 - p) * wi.z / PI
+    }
+}
+
+impl Material for MetallicRoughness {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let lobes = self.lobes(r_in, hit)?;
+
+        let wi = if rng.gen::<f64>() < self.specular_probability(&lobes) {
+            let wm = lobes
+                .distribution
+                .sample_wm(lobes.wo, rng.gen::<f64>(), rng.gen::<f64>());
+            reflect(-lobes.wo, wm)
+        } else {
+            random_cosine_direction()
+        };
+        let direction = lobes.uvw.local(wi);
+        // Below the shading hemisphere or leaving through the back of the real surface
+        if wi.z <= 0.0 || direction.dot(hit.geometric_normal) <= 0.0 {
+            return None;
+        }
+
+        let pdf = self.pdf_local(&lobes, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(Vec3::unit_vector(direction)),
+            attenuation: self.eval_local(&lobes, wi) / pdf,
+            pdf,
+            skip_pdf: false,
+        })
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> f64 {
+        match self.lobes(r_in, hit) {
+            Some(lobes) => {
+                self.pdf_local(&lobes, lobes.uvw.to_local(Vec3::unit_vector(scattered.dir)))
+            }
+            None => 0.0,
+        }
+    }
+
+    fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+        let dir = Vec3::unit_vector(scattered.dir);
+        match self.lobes(r_in, hit) {
+            Some(lobes) if dir.dot(hit.geometric_normal) > 0.0 => {
+                self.eval_local(&lobes, lobes.uvw.to_local(dir))
+            }
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/*
+ *  Frosted glass, a rough dielectric boundary. Light travelling inside is absorbed
+ *  following Beer-Lambert, applied when the ray reaches the inside of the surface (the
+ *  distance it travelled inside is then the hit distance).
+ */
+pub struct RoughDielectric {
+    pub ir: f64,          // Index of refraction
+    pub roughness: f64,   // Perceptual roughness, 0 is clear glass
+    pub absorption: Vec3, // Absorption coefficient inside, per unit length
+}
+
+impl RoughDielectric {
+    pub fn new(ir: f64, roughness: f64) -> Self {
+        Self {
+            ir,
+            roughness,
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Light travelling `distance` inside keeps `color` of itself
+    pub fn with_absorption(mut self, color: Vec3, distance: f64) -> Self {
+        let sigma = |c: f64| -f64::ln(c.clamp(1e-6, 1.0)) / distance;
+        self.absorption = Vec3::new(sigma(color.x), sigma(color.y), sigma(color.z));
+        self
+    }
+
+    // IOR of the far side over the near side
+    fn eta(&self, hit: &HitRecord) -> f64 {
+        if hit.front_face {
+            self.ir
+        } else {
+            1.0 / self.ir
+        }
+    }
+
+    // What is left of the light after crossing the inside up to this hit
+    fn transmittance(&self, r_in: Ray, hit: &HitRecord) -> Vec3 {
+        if hit.front_face {
+            return Vec3::new(1.0, 1.0, 1.0);
+        }
+        let distance = hit.t * r_in.dir.length();
+        let a = self.absorption;
+        Vec3::new(
+            f64::exp(-a.x * distance),
+            f64::exp(-a.y * distance),
+            f64::exp(-a.z * distance),
+        )
+    }
+
+    // Shading frame and wo in it, None when the shading normal faces away from the ray
+    fn frame(r_in: Ray, hit: &HitRecord) -> Option<(Onb, Vec3)> {
+        let uvw = Onb::build_from_w(hit.normal);
+        let wo = uvw.to_local(-Vec3::unit_vector(r_in.dir));
+        (wo.z > 0.0).then_some((uvw, wo))
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let (uvw, wo) = Self::frame(r_in, hit)?;
+        let distribution = TrowbridgeReitz::from_roughness(self.roughness);
+
+        let sample = sample_dielectric(
+            &distribution,
+            self.eta(hit),
+            wo,
+            rng.gen::<f64>(),
+            rng.gen::<f64>(),
+            rng.gen::<f64>(),
+        )?;
+        let direction = uvw.local(sample.wi);
+        // Reflections have to stay on the side of the ray and refractions cross the surface
+        if (sample.wi.z > 0.0) != (direction.dot(hit.geometric_normal) > 0.0) {
+            return None;
+        }
+
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(Vec3::unit_vector(direction)),
+            attenuation: self.transmittance(r_in, hit) * sample.weight,
+            pdf: sample.pdf,
+            skip_pdf: sample.specular,
+        })
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> f64 {
+        let Some((uvw, wo)) = Self::frame(r_in, hit) else {
+            return 0.0;
+        };
+        let wi = uvw.to_local(Vec3::unit_vector(scattered.dir));
+        let distribution = TrowbridgeReitz::from_roughness(self.roughness);
+        eval_dielectric(&distribution, self.eta(hit), wo, wi).1
+    }
+
+    fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+        let Some((uvw, wo)) = Self::frame(r_in, hit) else {
+            return Vec3::new(0.0, 0.0, 0.0);
+        };
+        let wi = uvw.to_local(Vec3::unit_vector(scattered.dir));
+        let distribution = TrowbridgeReitz::from_roughness(self.roughness);
+        self.transmittance(r_in, hit) * eval_dielectric(&distribution, self.eta(hit), wo, wi).0
+    }
+}
+
+/*
+ *  Thin glass sheet for windows and bubbles. Both interfaces are handled at once, light
+ *  goes through without bending and the bounces between the two sides add up to the
+ *  reflectance R + T^2 R / (1 - R^2).
+ */
+#[derive(Clone, Copy)]
+pub struct ThinDielectric {
+    pub ir: f64, // Index of refraction
+}
+
+impl ThinDielectric {
+    pub fn new(ir: f64) -> Self {
+        Self { ir }
+    }
+}
+
+impl Material for ThinDielectric {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let unit_dir = Vec3::unit_vector(r_in.dir);
+
+        let mut r = fresnel_dielectric(-unit_dir.dot(hit.normal), self.ir);
+        if r < 1.0 {
+            r += (1.0 - r).powi(2) * r / (1.0 - r * r);
+        }
+        let direction = if rng.gen::<f64>() < r {
+            reflect(unit_dir, hit.normal)
+        } else {
+            unit_dir
+        };
+
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(direction),
+            attenuation: Vec3::new(1.0, 1.0, 1.0),
+            pdf: 1.0,
+            skip_pdf: true,
+        })
+    }
+}
+
+/*
+ *  Principled (Disney style) uber-material. Every parameter is a texture, scalars are
+ *  read from the first channel and default to a plain white dielectric:
+ *
+ *      let mut paint = Principled::new(Vec3::new(0.8, 0.1, 0.1));
+ *      paint.clearcoat = Arc::new(SolidColor::from_rgb(1.0, 1.0, 1.0));
+ *
+ *  Lobes, from the top:
+ *  clearcoat     -> GGX coat with F0 = 0.04, dims everything below by its reflectance
+ *  metallic      -> GGX with the base color as F0
+ *  specular      -> GGX on the dielectric part, F0 = 0.08 * specular
+ *  transmission  -> Rough glass tinted by the base color
+ *  diffuse       -> Disney diffuse, subsurface blends in its flattened Hanrahan-Krueger
+ *                   approximation, sheen adds the grazing retro-reflection of cloth
+ *  emission      -> Emitted on top of whatever is reflected
+ */
+pub struct Principled {
+    pub base_color: Arc<dyn Texture + Send + Sync>,
+    pub metallic: Arc<dyn Texture + Send + Sync>,
+    pub roughness: Arc<dyn Texture + Send + Sync>,
+    pub specular: Arc<dyn Texture + Send + Sync>,
+    pub sheen: Arc<dyn Texture + Send + Sync>, // Color of the sheen, black for none
+    pub clearcoat: Arc<dyn Texture + Send + Sync>,
+    pub clearcoat_roughness: Arc<dyn Texture + Send + Sync>,
+    pub transmission: Arc<dyn Texture + Send + Sync>,
+    pub subsurface: Arc<dyn Texture + Send + Sync>,
+    pub emission: Arc<dyn Texture + Send + Sync>,
+    pub ir: f64, // Index of refraction of the transmission
+}
+
+// Principled parameters at one hit point, directions are in the shading frame
+struct PrincipledLobes {
+    uvw: Onb,
+    wo: Vec3,
+    base_color: Vec3,
+    sheen: Vec3,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    clearcoat: f64,
+    transmission: f64,
+    subsurface: f64,
+    distribution: TrowbridgeReitz,
+    coat_distribution: TrowbridgeReitz,
+    eta: f64,
+    inside: bool, // Hit from inside the object, through the transmission
+    // Lobe selection probabilities: diffuse, specular, clearcoat, transmission
+    probabilities: [f64; 4],
+}
+
+impl PrincipledLobes {
+    // Share of the light that gets past the clearcoat
+    fn coat_transmittance(&self) -> f64 {
+        1.0 - self.clearcoat * fresnel_schlick(Vec3::new(0.04, 0.04, 0.04), self.wo.z).x
+    }
+
+    fn specular_color(&self, cos_theta: f64) -> Vec3 {
+        let f0 = 0.08 * self.specular;
+        let dielectric = fresnel_schlick(Vec3::new(f0, f0, f0), cos_theta);
+        let metal = fresnel_schlick(self.base_color, cos_theta);
+        dielectric * ((1.0 - self.metallic) * (1.0 - self.transmission)) + metal * self.metallic
+    }
+}
+
+impl Principled {
+    pub fn new(base_color: Vec3) -> Self {
+        Self::from_texture(Arc::new(SolidColor::from_color(base_color)))
+    }
+
+    pub fn from_texture(base_color: Arc<dyn Texture + Send + Sync>) -> Self {
+        let constant =
+            |v: f64| -> Arc<dyn Texture + Send + Sync> { Arc::new(SolidColor::from_rgb(v, v, v)) };
+        Self {
+            base_color,
+            metallic: constant(0.0),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            sheen: constant(0.0),
+            clearcoat: constant(0.0),
+            clearcoat_roughness: constant(0.03),
+            transmission: constant(0.0),
+            subsurface: constant(0.0),
+            emission: constant(0.0),
+            ir: 1.5,
+        }
+    }
+
+    fn lobes(&self, r_in: Ray, hit: &HitRecord) -> Option<PrincipledLobes> {
+        let uvw = Onb::build_from_w(hit.normal);
+        let wo = uvw.to_local(-Vec3::unit_vector(r_in.dir));
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let scalar = |tex: &Arc<dyn Texture + Send + Sync>| tex.value_at(hit).x.clamp(0.0, 1.0);
+
+        let roughness = scalar(&self.roughness);
+        let mut lobes = PrincipledLobes {
+            uvw,
+            wo,
+            base_color: self.base_color.value_at(hit),
+            sheen: self.sheen.value_at(hit),
+            metallic: scalar(&self.metallic),
+            roughness,
+            specular: scalar(&self.specular),
+            clearcoat: scalar(&self.clearcoat),
+            transmission: scalar(&self.transmission),
+            subsurface: scalar(&self.subsurface),
+            distribution: TrowbridgeReitz::from_roughness(roughness),
+            coat_distribution: TrowbridgeReitz::from_roughness(scalar(&self.clearcoat_roughness)),
+            eta: if hit.front_face {
+                self.ir
+            } else {
+                1.0 / self.ir
+            },
+            inside: !hit.front_face,
+            probabilities: [0.0; 4],
+        };
+
+        // Only transmission could have brought the ray inside, so only glass is left there
+        let weights = if !lobes.inside {
+            let opaque = (1.0 - lobes.metallic) * (1.0 - lobes.transmission);
+            [
+                opaque * (luminance(lobes.base_color) + luminance(lobes.sheen)),
+                luminance(lobes.specular_color(wo.z)),
+                lobes.clearcoat * fresnel_schlick(Vec3::new(0.04, 0.04, 0.04), wo.z).x,
+                (1.0 - lobes.metallic) * lobes.transmission,
+            ]
+        } else {
+            [0.0, 0.0, 0.0, 1.0]
+        };
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        lobes.probabilities = weights.map(|w| w / total);
+        Some(lobes)
+    }
+
+    // f * cos for the direction wi, without the delta part of smooth glass
+    fn eval_local(&self, l: &PrincipledLobes, wi: Vec3) -> Vec3 {
+        let wo = l.wo;
+        let (glass, _) = eval_dielectric(&l.distribution, l.eta, wo, wi);
+        if l.inside {
+            return l.base_color * glass;
+        }
+        let mut f = l.base_color * (glass * (1.0 - l.metallic) * l.transmission);
+        if wi.z <= 0.0 {
+            return f * l.coat_transmittance();
+        }
+
+        let wm = wo + wi;
+        if wm.near_zero() {
+            return f * l.coat_transmittance();
+        }
+        let wm = Vec3::unit_vector(wm);
+        let cos_d = wi.dot(wm);
+        let (cos_i, cos_o) = (wi.z, wo.z);
+
+        // Disney diffuse with the subsurface flattening and sheen
+        let fl = (1.0 - cos_i).powi(5);
+        let fv = (1.0 - cos_o).powi(5);
+        let fd90 = 0.5 + 2.0 * l.roughness * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let fss90 = l.roughness * cos_d * cos_d;
+        let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+        let ss = 1.25 * (fss * (1.0 / (cos_i + cos_o) - 0.5) + 0.5);
+        let diffuse =
+            l.base_color * ((fd + (ss - fd) * l.subsurface) / PI) + l.sheen * (1.0 - cos_d).powi(5);
+        f += diffuse * ((1.0 - l.metallic) * (1.0 - l.transmission) * cos_i);
+
+        let d = l.distribution.d(wm);
+        let g = l.distribution.g(wo, wi);
+        f += l.specular_color(cos_d) * (d * g / (4.0 * cos_o));
+
+        let mut f = f * l.coat_transmittance();
+        let d = l.coat_distribution.d(wm);
+        let g = l.coat_distribution.g(wo, wi);
+        let fc = fresnel_schlick(Vec3::new(0.04, 0.04, 0.04), cos_d).x;
+        f += Vec3::new(1.0, 1.0, 1.0) * (l.clearcoat * fc * d * g / (4.0 * cos_o));
+        f
+    }
+
+    fn pdf_local(&self, l: &PrincipledLobes, wi: Vec3) -> f64 {
+        let wo = l.wo;
+        let [p_diffuse, p_specular, p_coat, p_glass] = l.probabilities;
+        let mut pdf = 0.0;
+        if p_glass > 0.0 {
+            pdf += p_glass * eval_dielectric(&l.distribution, l.eta, wo, wi).1;
+        }
+        let wm = wo + wi;
+        if wi.z <= 0.0 || wm.near_zero() {
+            return pdf;
+        }
+        let wm = Vec3::unit_vector(wm);
+        let cos_h = wo.dot(wm);
+        if cos_h <= 0.0 {
+            return pdf;
+        }
+        pdf += p_diffuse * wi.z / PI;
+        pdf += p_specular * l.distribution.visible_d(wo, wm) / (4.0 * cos_h);
+        pdf += p_coat * l.coat_distribution.visible_d(wo, wm) / (4.0 * cos_h);
+        pdf
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let lobes = self.lobes(r_in, hit)?;
+        let [p_diffuse, p_specular, p_coat, p_glass] = lobes.probabilities;
+
+        let u = rng.gen::<f64>();
+        let wi = if u < p_diffuse {
+            random_cosine_direction()
+        } else if u < p_diffuse + p_specular {
+            let wm = lobes
+                .distribution
+                .sample_wm(lobes.wo, rng.gen::<f64>(), rng.gen::<f64>());
+            reflect(-lobes.wo, wm)
+        } else if u < p_diffuse + p_specular + p_coat {
+            let wm =
+                lobes
+                    .coat_distribution
+                    .sample_wm(lobes.wo, rng.gen::<f64>(), rng.gen::<f64>());
+            reflect(-lobes.wo, wm)
         } else {
-            refract(unit_dir, outward_normal, refraction_ratio)
+            let sample = sample_dielectric(
+                &lobes.distribution,
+                lobes.eta,
+                lobes.wo,
+                rng.gen::<f64>(),
+                rng.gen::<f64>(),
+                rng.gen::<f64>(),
+            )?;
+            if sample.specular {
+                // Smooth glass is a delta, none of the other lobes can pick the same direction
+                let direction = lobes.uvw.local(sample.wi);
+                if (sample.wi.z > 0.0) != (direction.dot(hit.geometric_normal) > 0.0) {
+                    return None;
+                }
+                let tint = if lobes.inside {
+                    1.0
+                } else {
+                    (1.0 - lobes.metallic) * lobes.transmission * lobes.coat_transmittance()
+                };
+                return Some(ScatterRecord {
+                    ray: hit.spawn_ray(direction),
+                    attenuation: lobes.base_color * (tint * sample.weight / p_glass),
+                    pdf: 1.0,
+                    skip_pdf: true,
+                });
+            }
+            sample.wi
         };
 
-        let scattered = Ray::new(hit.p, direction);
-        Some((scattered, attenuation))
+        let direction = lobes.uvw.local(wi);
+        if wi.z == 0.0 || (wi.z > 0.0) != (direction.dot(hit.geometric_normal) > 0.0) {
+            return None;
+        }
+        let pdf = self.pdf_local(&lobes, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(Vec3::unit_vector(direction)),
+            attenuation: self.eval_local(&lobes, wi) / pdf,
+            pdf,
+            skip_pdf: false,
+        })
+    }
+
+    fn emitted(&self, _r_in: Ray, hit: &HitRecord) -> Vec3 {
+        self.emission.value_at(hit)
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> f64 {
+        match self.lobes(r_in, hit) {
+            Some(lobes) => {
+                self.pdf_local(&lobes, lobes.uvw.to_local(Vec3::unit_vector(scattered.dir)))
+            }
+            None => 0.0,
+        }
+    }
+
+    fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+        let dir = Vec3::unit_vector(scattered.dir);
+        match self.lobes(r_in, hit) {
+            Some(lobes) => {
+                let wi = lobes.uvw.to_local(dir);
+                if (wi.z > 0.0) != (dir.dot(hit.geometric_normal) > 0.0) {
+                    return Vec3::new(0.0, 0.0, 0.0);
+                }
+                self.eval_local(&lobes, wi)
+            }
+            None => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/*
+ *  Blend of two materials by a scalar mask texture (first channel, 0 is all `first` and
+ *  1 all `second`), e.g. rust patches on a metal or dirt on plaster. One of the two is
+ *  picked at random by the mask to sample the direction, the direction is then weighted
+ *  with the blend of both BSDFs over the blend of both pdfs so neither lobe is lost.
+ *  Specular samples only exist in the material that made them and are passed on as is.
+ *  Normal and bump maps of the two bend the normal of their own copy of the hit only.
+ */
+pub struct MixMaterial {
+    pub first: Arc<dyn Material + Sync + Send>,
+    pub second: Arc<dyn Material + Sync + Send>,
+    pub mask: Arc<dyn Texture + Send + Sync>,
+}
+
+impl MixMaterial {
+    pub fn new(
+        first: Arc<dyn Material + Sync + Send>,
+        second: Arc<dyn Material + Sync + Send>,
+        mask: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        Self {
+            first,
+            second,
+            mask,
+        }
+    }
+
+    fn amount(&self, hit: &HitRecord) -> f64 {
+        self.mask.value_at(hit).x.clamp(0.0, 1.0)
+    }
+
+    // The hit as each of the two shades it, with their own normal or bump map applied.
+    // The mix has no single shading normal to give the camera
+    fn child_hits(&self, hit: &HitRecord) -> (HitRecord, HitRecord) {
+        let (mut first, mut second) = (hit.clone(), hit.clone());
+        self.first.perturb_normal(&mut first);
+        self.second.perturb_normal(&mut second);
+        (first, second)
+    }
+
+    fn pdf_of(&self, t: f64, hits: &(HitRecord, HitRecord), r_in: Ray, scattered: Ray) -> f64 {
+        self.first.scattering_pdf(r_in, &hits.0, scattered) * (1.0 - t)
+            + self.second.scattering_pdf(r_in, &hits.1, scattered) * t
+    }
+
+    fn eval_of(&self, t: f64, hits: &(HitRecord, HitRecord), r_in: Ray, scattered: Ray) -> Vec3 {
+        self.first.eval(r_in, &hits.0, scattered) * (1.0 - t)
+            + self.second.eval(r_in, &hits.1, scattered) * t
+    }
+}
+
+impl Material for MixMaterial {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let t = self.amount(hit);
+        let hits = self.child_hits(hit);
+        let srec = if rng.gen::<f64>() < t {
+            self.second.scatter(r_in, &hits.1)?
+        } else {
+            self.first.scatter(r_in, &hits.0)?
+        };
+        if srec.skip_pdf {
+            return Some(srec);
+        }
+        let pdf = self.pdf_of(t, &hits, r_in, srec.ray);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(ScatterRecord {
+            attenuation: self.eval_of(t, &hits, r_in, srec.ray) / pdf,
+            pdf,
+            ..srec
+        })
+    }
+
+    fn emitted(&self, r_in: Ray, hit: &HitRecord) -> Vec3 {
+        let t = self.amount(hit);
+        self.first.emitted(r_in, hit) * (1.0 - t) + self.second.emitted(r_in, hit) * t
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> f64 {
+        self.pdf_of(self.amount(hit), &self.child_hits(hit), r_in, scattered)
+    }
+
+    fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+        self.eval_of(self.amount(hit), &self.child_hits(hit), r_in, scattered)
+    }
+}
+
+/*
+ *  Tangent space normal map over another material. The map's RGB in [0, 1] is the
+ *  normal in the shading frame of the hit (red along dpdu, green along dpdv, blue out
+ *  of the surface), so a flat map is (0.5, 0.5, 1). Should be a linear texture.
+ *  `strength` scales the tilt, 0 is the surface unchanged.
+ */
+pub struct NormalMapped {
+    pub material: Arc<dyn Material + Sync + Send>,
+    pub normal_map: Arc<dyn Texture + Send + Sync>,
+    pub strength: f64,
+}
+
+impl NormalMapped {
+    pub fn new(
+        material: Arc<dyn Material + Sync + Send>,
+        normal_map: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        Self {
+            material,
+            normal_map,
+            strength: 1.0,
+        }
+    }
+
+    pub fn with_strength(mut self, strength: f64) -> Self {
+        self.strength = strength;
+        self
+    }
+}
+
+/*
+ *  Bump map over another material (Blinn 1978, PBRT 10.5.2). The first channel of
+ *  `height` lifts the surface along its normal by `scale` times the value, and the
+ *  shading normal is the one of the lifted surface. Works with solid textures too, on
+ *  surfaces without uv the height is looked up at points moved along the tangents.
+ */
+pub struct BumpMapped {
+    pub material: Arc<dyn Material + Sync + Send>,
+    pub height: Arc<dyn Texture + Send + Sync>,
+    pub scale: f64,
+}
+
+impl BumpMapped {
+    pub fn new(
+        material: Arc<dyn Material + Sync + Send>,
+        height: Arc<dyn Texture + Send + Sync>,
+    ) -> Self {
+        Self {
+            material,
+            height,
+            scale: 1.0,
+        }
+    }
+
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+}
+
+// Both only change the hit the wrapped material is shaded with
+macro_rules! forward_material {
+    ($type:ty) => {
+        impl Material for $type {
+            fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+                self.material.scatter(r_in, hit)
+            }
+
+            fn emitted(&self, r_in: Ray, hit: &HitRecord) -> Vec3 {
+                self.material.emitted(r_in, hit)
+            }
+
+            fn is_emissive(&self) -> bool {
+                self.material.is_emissive()
+            }
+
+            fn scattering_pdf(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> f64 {
+                self.material.scattering_pdf(r_in, hit, scattered)
+            }
+
+            fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+                self.material.eval(r_in, hit, scattered)
+            }
+
+            fn perturb_normal(&self, hit: &mut HitRecord) {
+                self.perturb(hit);
+                self.material.perturb_normal(hit);
+            }
+        }
+    };
+}
+
+forward_material!(NormalMapped);
+forward_material!(BumpMapped);
+
+// A perturbed normal that ended up under the surface would shade it from inside, those
+// are left alone
+fn set_perturbed_normal(hit: &mut HitRecord, normal: Vec3) {
+    if normal.dot(hit.geometric_normal) > 0.0 && normal.x.is_finite() {
+        hit.normal = normal;
+    }
+}
+
+impl NormalMapped {
+    fn perturb(&self, hit: &mut HitRecord) {
+        let c = self.normal_map.value_at(hit) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
+        let local = Vec3::new(c.x * self.strength, c.y * self.strength, c.z.max(0.0));
+        if local.near_zero() {
+            return;
+        }
+        let normal = hit.shading_frame().local(Vec3::unit_vector(local));
+        set_perturbed_normal(hit, normal);
+    }
+}
+
+impl BumpMapped {
+    fn perturb(&self, hit: &mut HitRecord) {
+        let n = hit.normal;
+        // The tangents in the plane of the shading normal, so smoothed normals stay smooth
+        let flatten = |d: Vec3| d - n * d.dot(n);
+        let (mut dpdu, mut dpdv) = (flatten(hit.dpdu), flatten(hit.dpdv));
+        let ctx = TextureContext::from_hit(hit);
+        // How far to look for the slope, about the footprint of a pixel
+        let (mut du, mut dv) = (
+            0.5 * (ctx.footprint.dudx.abs() + ctx.footprint.dudy.abs()),
+            0.5 * (ctx.footprint.dvdx.abs() + ctx.footprint.dvdy.abs()),
+        );
+        let has_uv = dpdu.cross(dpdv).lenght_squared() > 0.0;
+        if !has_uv {
+            // Unit tangents, then du and dv are world distances and u, v stay put
+            let frame = hit.shading_frame();
+            (dpdu, dpdv) = (frame.u(), frame.v());
+            let reach = 0.5 * (ctx.footprint.dpdx.length() + ctx.footprint.dpdy.length());
+            (du, dv) = (reach, reach);
+        }
+        if du == 0.0 {
+            du = 0.0005;
+        }
+        if dv == 0.0 {
+            dv = 0.0005;
+        }
+
+        let height = |ctx: &TextureContext| self.height.evaluate(ctx).x * self.scale;
+        let displace = height(&ctx);
+        let u_displace = height(&TextureContext {
+            p: ctx.p + dpdu * du,
+            u: if has_uv { ctx.u + du } else { ctx.u },
+            ..ctx
+        });
+        let v_displace = height(&TextureContext {
+            p: ctx.p + dpdv * dv,
+            v: if has_uv { ctx.v + dv } else { ctx.v },
+            ..ctx
+        });
+
+        let dpdu = dpdu + n * ((u_displace - displace) / du);
+        let dpdv = dpdv + n * ((v_displace - displace) / dv);
+        let normal = Vec3::unit_vector(dpdu.cross(dpdv));
+        // The tangents don't know which side the hit is on
+        let normal = if normal.dot(n) < 0.0 { -normal } else { normal };
+        set_perturbed_normal(hit, normal);
     }
 }
-*/
 
+/*
+ *  Area light, emits `emit` times `strength` as radiance. Emits from both faces unless
+ *  made one-sided, then only the front face (the side the outward normal points to)
+ *  is lit. The optional spot falloff narrows the emission around the normal.
+ */
 pub struct DiffuseLight {
-    emit: Arc<dyn Texture>,
+    emit: Arc<dyn Texture + Sync + Send>,
+    pub strength: f64,
+    pub two_sided: bool,
+    spot: Option<(f64, f64)>, // Cosines of the cone angle and of where the falloff starts
 }
 
 impl DiffuseLight {
     pub fn from_color(color: Vec3) -> Self {
+        Self::from_texture(Arc::new(SolidColor::from_color(color)))
+    }
+
+    pub fn from_texture(emit: Arc<dyn Texture + Sync + Send>) -> Self {
         Self {
-            emit: Arc::new(SolidColor::from_color(color)),
+            emit,
+            strength: 1.0,
+            two_sided: true,
+            spot: None,
         }
     }
+
+    // Radiance with the luminance of `nits` (cd/m^2) in the hue of `color`
+    pub fn from_nits(color: Vec3, nits: f64) -> Self {
+        let mut light = Self::from_color(color / luminance(color).max(1e-12));
+        light.strength = nits;
+        light
+    }
+
+    /*
+     *  Total emitted power in watts spread evenly over a light of `area`. A lambertian
+     *  emitter sends out pi * area * radiance per side, the color only gives the hue and is
+     *  normalized by its luminance. Doesn't account for the spot falloff.
+     */
+    pub fn from_watts(color: Vec3, watts: f64, area: f64, two_sided: bool) -> Self {
+        let sides = if two_sided { 2.0 } else { 1.0 };
+        let mut light = Self::from_nits(color, watts / (sides * PI * area));
+        light.two_sided = two_sided;
+        light
+    }
+
+    pub fn with_strength(mut self, strength: f64) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    pub fn one_sided(mut self) -> Self {
+        self.two_sided = false;
+        self
+    }
+
+    // Only emits within `angle` degrees of the normal, fading out from `falloff_start`
+    pub fn with_spot(mut self, angle: f64, falloff_start: f64) -> Self {
+        let cos_total = f64::cos(degrees_to_radians(angle));
+        let cos_start = f64::cos(degrees_to_radians(falloff_start.min(angle)));
+        self.spot = Some((cos_total, cos_start));
+        self
+    }
 }
 
 impl Material for DiffuseLight {
-    fn scatter(&self, _: Ray, _: &HitRecord) -> Option<(Ray, Vec3, f64)> {
+    fn scatter(&self, _: Ray, _: &HitRecord) -> Option<ScatterRecord> {
         // No reflection is done through the light
         None
     }
-    fn emitted(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
-        self.emit.value(u, v, point)
+    fn emitted(&self, r_in: Ray, hit: &HitRecord) -> Vec3 {
+        if !self.two_sided && !hit.front_face {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+
+        let falloff = match self.spot {
+            Some((cos_total, cos_start)) => {
+                let cos_theta = -Vec3::unit_vector(r_in.dir).dot(hit.geometric_normal);
+                if cos_theta < cos_total {
+                    0.0
+                } else if cos_theta >= cos_start {
+                    1.0
+                } else {
+                    // Smoothstep between the edge of the cone and the start of the falloff
+                    let t = (cos_theta - cos_total) / (cos_start - cos_total);
+                    t * t * (3.0 - 2.0 * t)
+                }
+            }
+            None => 1.0,
+        };
+        self.emit.value_at(hit) * (self.strength * falloff)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.strength > 0.0
     }
 }
 
@@ -184,14 +1192,321 @@ impl Isotropic {
 }
 
 impl Material for Isotropic {
-    fn scatter(&self, _r_in: Ray, hit: &HitRecord) -> Option<(Ray, Vec3, f64)> {
-        let scattered = Ray::new(hit.p, random_in_unit_sphere());
-        let attenuation = self.albedo.value(hit.u, hit.v, hit.p);
+    fn scatter(&self, _r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let scattered = hit.spawn_ray(random_in_unit_sphere());
+        let attenuation = self.albedo.value_at(hit);
         let pdf = 1.0 / (4.0 * PI);
-        Some((scattered, attenuation, pdf))
+        Some(ScatterRecord {
+            ray: scattered,
+            attenuation,
+            pdf,
+            skip_pdf: false,
+        })
     }
 
     fn scattering_pdf(&self, _r_in: Ray, _hit: &HitRecord, _scattered: Ray) -> f64 {
         1.0 / (4.0 * PI)
     }
+
+    fn eval(&self, _r_in: Ray, hit: &HitRecord, _scattered: Ray) -> Vec3 {
+        self.albedo.value_at(hit) / (4.0 * PI)
+    }
+}
+
+/*
+ *  Phase function of Henyey and Greenstein (1941), scattering bunched around the
+ *  direction of travel for an asymmetry g > 0 (fog, clouds) and back towards where the
+ *  light came from for g < 0, isotropic at zero. A second lobe can be blended in, e.g. a
+ *  strong forward lobe with a weak backward one for the silver linings and glow of clouds.
+ */
+pub struct HenyeyGreenstein {
+    albedo: Arc<dyn Texture + Sync + Send>,
+    g: f64,
+    second_g: f64,
+    second_weight: f64, // Share of the second lobe
+}
+
+impl HenyeyGreenstein {
+    pub fn from_color(c: Vec3, g: f64) -> Self {
+        Self::from_tex(Arc::new(SolidColor::from_color(c)), g)
+    }
+
+    pub fn from_tex(texture: Arc<dyn Texture + Sync + Send>, g: f64) -> Self {
+        // |g| = 1 is a delta, kept just short of it
+        let g = g.clamp(-0.999, 0.999);
+        Self {
+            albedo: texture,
+            g,
+            second_g: g,
+            second_weight: 0.0,
+        }
+    }
+
+    pub fn with_second_lobe(mut self, g: f64, weight: f64) -> Self {
+        self.second_g = g.clamp(-0.999, 0.999);
+        self.second_weight = weight.clamp(0.0, 1.0);
+        self
+    }
+
+    // Density over the sphere of scattering by an angle with cosine `cos_theta` from the
+    // direction of travel
+    fn phase(&self, cos_theta: f64) -> f64 {
+        let lobe = |g: f64| {
+            let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+            (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+        };
+        (1.0 - self.second_weight) * lobe(self.g) + self.second_weight * lobe(self.second_g)
+    }
+
+    fn cos_theta(r_in: Ray, scattered: Ray) -> f64 {
+        Vec3::unit_vector(r_in.dir).dot(Vec3::unit_vector(scattered.dir))
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(&self, r_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut rng = rand::thread_rng();
+        let g = if rng.gen::<f64>() < self.second_weight {
+            self.second_g
+        } else {
+            self.g
+        };
+        // Inverse of the lobe's distribution of the cosine, the phase is exactly the pdf
+        let xi = rng.gen::<f64>();
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * xi
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = f64::sqrt(1.0 - cos_theta * cos_theta);
+        let phi = 2.0 * PI * rng.gen::<f64>();
+
+        let uvw = Onb::build_from_w(r_in.dir);
+        let direction = uvw.local(Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ));
+        Some(ScatterRecord {
+            ray: hit.spawn_ray(direction),
+            attenuation: self.albedo.value_at(hit),
+            pdf: self.phase(cos_theta),
+            skip_pdf: false,
+        })
+    }
+
+    fn scattering_pdf(&self, r_in: Ray, _hit: &HitRecord, scattered: Ray) -> f64 {
+        self.phase(Self::cos_theta(r_in, scattered))
+    }
+
+    fn eval(&self, r_in: Ray, hit: &HitRecord, scattered: Ray) -> Vec3 {
+        self.albedo.value_at(hit) * self.phase(Self::cos_theta(r_in, scattered))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flat surface facing +z, u along +x
+    fn flat_hit(mat: Arc<dyn Material + Sync + Send>) -> HitRecord {
+        let mut hit = HitRecord::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            0.5,
+            0.5,
+            mat,
+        );
+        hit.dpdu = Vec3::new(1.0, 0.0, 0.0);
+        hit.dpdv = Vec3::new(0.0, 1.0, 0.0);
+        hit
+    }
+
+    // Height rising by `slope_u` per unit of u and `slope_v` per unit of v
+    struct HeightRamp {
+        slope_u: f64,
+        slope_v: f64,
+    }
+
+    impl Texture for HeightRamp {
+        fn value(&self, u: f64, v: f64, _: Vec3) -> Vec3 {
+            let h = self.slope_u * u + self.slope_v * v;
+            Vec3::new(h, h, h)
+        }
+    }
+
+    #[test]
+    fn bump_tilts_the_normal_down_the_slope() {
+        let plain: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(1.0, 1.0, 1.0)));
+        let assert_normal = |slope_u: f64, slope_v: f64, scale: f64, expected: Vec3| {
+            let ramp = Arc::new(HeightRamp { slope_u, slope_v });
+            let bumped = BumpMapped::new(Arc::clone(&plain), ramp).with_scale(scale);
+            let mut hit = flat_hit(Arc::clone(&plain));
+            bumped.perturb_normal(&mut hit);
+            let expected = Vec3::unit_vector(expected);
+            let n = hit.normal;
+            assert!(
+                (n - expected).length() < 1e-6,
+                "({}, {}, {}) for a slope of ({slope_u}, {slope_v}) scaled by {scale}",
+                n.x,
+                n.y,
+                n.z
+            );
+        };
+        // Rising along +x as fast as the surface runs, the normal leans 45 degrees to -x
+        assert_normal(2.0, 0.0, 0.5, Vec3::new(-1.0, 0.0, 1.0));
+        assert_normal(0.0, 1.0, 1.0, Vec3::new(0.0, -1.0, 1.0));
+        assert_normal(0.0, 1.0, -1.0, Vec3::new(0.0, 1.0, 1.0));
+        assert_normal(1.0, 2.0, 0.5, Vec3::new(-0.5, -1.0, 1.0));
+        // A flat height leaves it alone
+        assert_normal(0.0, 0.0, 1.0, Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn lambertian_stays_above_the_geometric_surface() {
+        let white: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(1.0, 1.0, 1.0)));
+        let mut hit = flat_hit(Arc::clone(&white));
+        // Shading normal tilted 45 degrees towards +x, the real surface still faces +z
+        hit.normal = Vec3::unit_vector(Vec3::new(1.0, 0.0, 1.0));
+        let r_in = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let towards = |d: Vec3| Ray::new(hit.p, d);
+
+        // Above the shading hemisphere but behind the real surface
+        let below = towards(Vec3::new(1.0, 0.0, -0.3));
+        assert_eq!(white.scattering_pdf(r_in, &hit, below), 0.0);
+        assert_eq!(white.eval(r_in, &hit, below).x, 0.0);
+
+        let above = towards(Vec3::new(1.0, 0.0, 0.3));
+        assert!(white.scattering_pdf(r_in, &hit, above) > 0.0);
+        assert!(white.eval(r_in, &hit, above).x > 0.0);
+
+        for _ in 0..1000 {
+            if let Some(scatter) = white.scatter(r_in, &hit) {
+                assert!(scatter.ray.dir.dot(hit.geometric_normal) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn light_units() {
+        let color = Vec3::new(2.0, 1.0, 0.5);
+        let r_in = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let radiance = |light: DiffuseLight| {
+            let light: Arc<dyn Material + Sync + Send> = Arc::new(light);
+            light.emitted(r_in, &flat_hit(Arc::clone(&light)))
+        };
+
+        let nits = radiance(DiffuseLight::from_nits(color, 100.0));
+        assert!((luminance(nits) - 100.0).abs() < 1e-9);
+        // The hue is kept
+        assert!((nits.x / nits.y - 2.0).abs() < 1e-9);
+        assert!((nits.z / nits.y - 0.5).abs() < 1e-9);
+
+        // A lambertian emitter gives off pi * area * radiance per side
+        let one_sided = radiance(DiffuseLight::from_watts(color, 100.0, 2.0, false));
+        assert!((luminance(one_sided) * PI * 2.0 - 100.0).abs() < 1e-9);
+        let two_sided = radiance(DiffuseLight::from_watts(color, 100.0, 2.0, true));
+        assert!((luminance(two_sided) * PI * 2.0 * 2.0 - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spot_falloff() {
+        let light: Arc<dyn Material + Sync + Send> =
+            Arc::new(DiffuseLight::from_color(Vec3::new(1.0, 1.0, 1.0)).with_spot(40.0, 20.0));
+        let hit = flat_hit(Arc::clone(&light));
+        // Seen from `degrees` off the normal
+        let falloff = |degrees: f64| {
+            let theta = degrees_to_radians(degrees);
+            let r_in = Ray::new(
+                Vec3::new(theta.sin(), 0.0, theta.cos()),
+                Vec3::new(-theta.sin(), 0.0, -theta.cos()),
+            );
+            light.emitted(r_in, &hit).x
+        };
+        assert!((falloff(0.0) - 1.0).abs() < 1e-9);
+        assert!((falloff(19.9) - 1.0).abs() < 1e-9);
+        // Full up to the inner angle, nothing past the outer one
+        assert!((falloff(20.0) - 1.0).abs() < 1e-6);
+        assert!(falloff(40.0).abs() < 1e-6);
+        assert_eq!(falloff(41.0), 0.0);
+        assert_eq!(falloff(90.0), 0.0);
+        // Smoothstep in the cosine in between
+        let cos = |d: f64| degrees_to_radians(d).cos();
+        let t = (cos(30.0) - cos(40.0)) / (cos(20.0) - cos(40.0));
+        assert!((falloff(30.0) - t * t * (3.0 - 2.0 * t)).abs() < 1e-9);
+        assert!(falloff(25.0) > falloff(30.0) && falloff(30.0) > falloff(35.0));
+    }
+
+    #[test]
+    fn conductor_reflectance_at_normal_incidence() {
+        // F0 of gold and copper from Real-Time Rendering 4th ed., table 9.2 (linear)
+        let metals = [
+            (
+                Vec3::new(0.143, 0.374, 1.442),
+                Vec3::new(3.983, 2.385, 1.603),
+                Vec3::new(1.0, 0.766, 0.336),
+            ),
+            (
+                Vec3::new(0.200, 0.924, 1.102),
+                Vec3::new(3.912, 2.452, 2.142),
+                Vec3::new(0.955, 0.638, 0.538),
+            ),
+        ];
+        let r_in = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        for (eta, k, published) in metals {
+            let metal = MetallicRoughness::conductor(eta, k, 0.3);
+            let hit = flat_hit(Arc::new(Lambertian::from_color(Vec3::default())));
+            let lobes = metal.lobes(r_in, &hit).unwrap();
+            let f0 = metal.fresnel(&lobes, 1.0);
+            for a in 0..3 {
+                assert!(
+                    (f0[a] - published[a]).abs() < 0.04,
+                    "channel {a}: {} against {}",
+                    f0[a],
+                    published[a]
+                );
+            }
+            // Everything is reflected at grazing angles
+            let grazing = metal.fresnel(&lobes, 0.0);
+            assert!((0..3).all(|a| grazing[a] > 0.999));
+        }
+    }
+
+    #[test]
+    fn mix_keeps_the_normal_maps_of_its_materials() {
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let plain: Arc<dyn Material + Sync + Send> = Arc::new(Lambertian::from_color(white));
+        // Tilts the normal 45 degrees towards +x
+        let tilt: Arc<dyn Texture + Send + Sync> = Arc::new(SolidColor::from_rgb(0.85, 0.5, 0.85));
+        let mapped: Arc<dyn Material + Sync + Send> =
+            Arc::new(NormalMapped::new(Arc::clone(&plain), tilt));
+        let all_mapped = SolidColor::from_rgb(1.0, 1.0, 1.0);
+        let mix: Arc<dyn Material + Sync + Send> = Arc::new(MixMaterial::new(
+            Arc::clone(&plain),
+            Arc::clone(&mapped),
+            Arc::new(all_mapped),
+        ));
+
+        let r_in = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let scattered = Ray::new(Vec3::default(), Vec3::new(1.0, 0.0, 1.0));
+
+        let mut mapped_hit = flat_hit(Arc::clone(&mapped));
+        mapped.perturb_normal(&mut mapped_hit);
+        let expected = mapped.eval(r_in, &mapped_hit, scattered);
+
+        // The camera perturbs the hit by the mix, which leaves it to the two materials
+        let mut mix_hit = flat_hit(Arc::clone(&mix));
+        mix.perturb_normal(&mut mix_hit);
+        let got = mix.eval(r_in, &mix_hit, scattered);
+        assert!((got.x - expected.x).abs() < 1e-9);
+        assert!((mix.scattering_pdf(r_in, &mix_hit, scattered) - 1.0 / PI).abs() < 1e-9);
+
+        // Unmapped it would be a cosine of 45 degrees
+        let flat = plain.eval(r_in, &flat_hit(Arc::clone(&plain)), scattered);
+        assert!(got.x > flat.x * 1.3);
+    }
 }
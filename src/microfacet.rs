@@ -0,0 +1,263 @@
+/*
+ *  Trowbridge-Reitz (GGX) microfacet distribution with the height-correlated Smith
+ *  masking-shadowing term and sampling of the visible normals (Heitz 2018), plus the
+ *  Fresnel equations the microfacet materials need.
+ *  Everything works in the local shading frame where the normal is +Z.
+ */
+use std::f64::consts::PI;
+
+use crate::geometry::vector3::*;
+
+#[derive(Clone, Copy)]
+pub struct TrowbridgeReitz {
+    alpha: f64,
+}
+
+impl TrowbridgeReitz {
+    // Perceptual roughness in [0, 1], squared like glTF and Disney do
+    pub fn from_roughness(roughness: f64) -> Self {
+        // A perfectly smooth lobe would be a delta, keep it a very sharp one instead
+        let r = roughness.clamp(0.0, 1.0);
+        Self {
+            alpha: f64::max(r * r, 1e-4),
+        }
+    }
+
+    // Below this the lobe is better handled as a perfect mirror/refraction
+    #[inline]
+    pub fn effectively_smooth(&self) -> bool {
+        self.alpha < 1e-3
+    }
+
+    // Distribution of the microfacet normals
+    pub fn d(&self, wm: Vec3) -> f64 {
+        let cos2 = wm.z * wm.z;
+        if cos2 <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        let denom = cos2 * (a2 - 1.0) + 1.0;
+        a2 / (PI * denom * denom)
+    }
+
+    fn lambda(&self, w: Vec3) -> f64 {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0.0 {
+            return 0.0;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        (f64::sqrt(1.0 + self.alpha * self.alpha * tan2) - 1.0) / 2.0
+    }
+
+    // Masking of one direction
+    pub fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Masking and shadowing together, height-correlated form
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the visible normals seen from wo
+    pub fn visible_d(&self, wo: Vec3, wm: Vec3) -> f64 {
+        if wo.z == 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * f64::max(0.0, wo.dot(wm)) * self.d(wm) / wo.z.abs()
+    }
+
+    // Samples a microfacet normal visible from wo (wo.z > 0), u1 and u2 uniform in [0, 1)
+    pub fn sample_wm(&self, wo: Vec3, u1: f64, u2: f64) -> Vec3 {
+        // Stretch the view direction to the hemisphere configuration
+        let vh = Vec3::unit_vector(Vec3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z));
+
+        // Orthonormal basis around it
+        let len2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len2 > 0.0 {
+            Vec3::new(-vh.y, vh.x, 0.0) / len2.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(t1);
+
+        // Uniform point on the disk, warped to the projected visible hemisphere
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * f64::sqrt(1.0 - p1 * p1) + s * r * phi.sin();
+
+        // Back onto the hemisphere and unstretch
+        let nh = t1 * p1 + t2 * p2 + vh * f64::sqrt(f64::max(0.0, 1.0 - p1 * p1 - p2 * p2));
+        Vec3::unit_vector(Vec3::new(
+            self.alpha * nh.x,
+            self.alpha * nh.y,
+            f64::max(1e-6, nh.z),
+        ))
+    }
+}
+
+// Schlick's approximation with the reflectance at normal incidence per channel
+#[inline]
+pub fn fresnel_schlick(f0: Vec3, cos_theta: f64) -> Vec3 {
+    let m = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0 + (Vec3::new(1.0, 1.0, 1.0) - f0) * m
+}
+
+// Unpolarized Fresnel reflectance of a conductor with complex IOR eta + i k (PBRT's FrConductor)
+pub fn fresnel_conductor(cos_theta: f64, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+
+    eta.zip_with(k, |eta, k| {
+        let eta2 = eta * eta;
+        let k2 = k * k;
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = f64::sqrt(t0 * t0 + 4.0 * eta2 * k2);
+        let t1 = a2_plus_b2 + cos2;
+        let a = f64::sqrt(f64::max(0.0, 0.5 * (a2_plus_b2 + t0)));
+        let t2 = 2.0 * cos_i * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    })
+}
+
+// Unpolarized Fresnel reflectance of a dielectric, eta is the IOR of the far side over the
+// near side and cos_theta is taken on the near side
+pub fn fresnel_dielectric(cos_theta: f64, eta: f64) -> f64 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0; // Total internal reflection
+    }
+    let cos_t = f64::sqrt(1.0 - sin2_t);
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+// Refracts w about n (both on the same side), eta as in fresnel_dielectric.
+// None on total internal reflection
+pub fn refract_local(w: Vec3, n: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = n.dot(w);
+    let sin2_t = f64::max(0.0, 1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = f64::sqrt(1.0 - sin2_t);
+    Some(-w / eta + n * (cos_i / eta - cos_t))
+}
+
+pub struct DielectricSample {
+    pub wi: Vec3,
+    pub weight: f64, // f * cos / pdf
+    pub pdf: f64,
+    pub specular: bool, // Delta lobe, pdf is meaningless
+}
+
+/*
+ *  Rough dielectric boundary (Walter et al. 2007) in the frame where wo is on the +Z
+ *  side, eta is the IOR below the surface over the one above it. The transmitted part
+ *  is divided by eta^2 since radiance gets compressed into the smaller solid angle.
+ *  A smooth distribution falls back to the perfect specular boundary.
+ */
+pub fn sample_dielectric(
+    distribution: &TrowbridgeReitz,
+    eta: f64,
+    wo: Vec3,
+    uc: f64,
+    u1: f64,
+    u2: f64,
+) -> Option<DielectricSample> {
+    if distribution.effectively_smooth() {
+        let r = fresnel_dielectric(wo.z, eta);
+        return if uc < r {
+            Some(DielectricSample {
+                wi: Vec3::new(-wo.x, -wo.y, wo.z),
+                weight: 1.0,
+                pdf: r,
+                specular: true,
+            })
+        } else {
+            let wi = refract_local(wo, Vec3::new(0.0, 0.0, 1.0), eta)?;
+            Some(DielectricSample {
+                wi,
+                weight: 1.0 / (eta * eta),
+                pdf: 1.0 - r,
+                specular: true,
+            })
+        };
+    }
+
+    let wm = distribution.sample_wm(wo, u1, u2);
+    // Total internal reflection makes r one, so refraction is never picked then
+    let r = fresnel_dielectric(wo.dot(wm), eta);
+    let wi = if uc < r {
+        -wo + wm * (2.0 * wo.dot(wm))
+    } else {
+        refract_local(wo, wm, eta)?
+    };
+
+    let (f, pdf) = eval_dielectric(distribution, eta, wo, wi);
+    if pdf <= 0.0 {
+        return None;
+    }
+    Some(DielectricSample {
+        wi,
+        weight: f / pdf,
+        pdf,
+        specular: false,
+    })
+}
+
+// f * |cos| and the sampling pdf of the rough boundary for the pair of directions
+pub fn eval_dielectric(distribution: &TrowbridgeReitz, eta: f64, wo: Vec3, wi: Vec3) -> (f64, f64) {
+    let (cos_o, cos_i) = (wo.z, wi.z);
+    if distribution.effectively_smooth() || cos_o <= 0.0 || cos_i == 0.0 {
+        return (0.0, 0.0);
+    }
+    let reflected = cos_i > 0.0;
+    let etap = if reflected { 1.0 } else { eta };
+
+    // Generalized half vector, facing up
+    let wm = wi * etap + wo;
+    if wm.near_zero() {
+        return (0.0, 0.0);
+    }
+    let mut wm = Vec3::unit_vector(wm);
+    if wm.z < 0.0 {
+        wm = -wm;
+    }
+    // Microfacets seen from behind don't contribute
+    if wm.dot(wi) * cos_i < 0.0 || wm.dot(wo) * cos_o < 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let r = fresnel_dielectric(wo.dot(wm), eta);
+    let t = 1.0 - r;
+    let d = distribution.d(wm);
+    let g = distribution.g(wo, wi);
+    let visible = distribution.visible_d(wo, wm);
+
+    if reflected {
+        let f = d * g * r / (4.0 * cos_o);
+        let pdf = visible / (4.0 * wo.dot(wm)) * r;
+        (f, pdf)
+    } else {
+        let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2);
+        let dwm_dwi = wi.dot(wm).abs() / denom;
+        let f = t * d * g * (wi.dot(wm) * wo.dot(wm) / (cos_o * denom)).abs() / (etap * etap);
+        let pdf = visible * dwm_dwi * t;
+        (f, pdf)
+    }
+}
+
+#[inline]
+pub fn luminance(c: Vec3) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
@@ -0,0 +1,333 @@
+/*
+ *  Smooth pseudo-random functions of space for the procedural textures. Everything is
+ *  built from a seed so a scene comes out the same on every render, and the lattice
+ *  based noises repeat every 256 units.
+ */
+use std::sync::Arc;
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::geometry::vector3::*;
+
+pub trait Noise: Sync + Send {
+    // Value at p, in about [-1, 1] (Worley gives distances instead, see WorleyFeature)
+    fn noise(&self, p: Vec3) -> f64;
+}
+
+// A shuffle of 0..256 repeated twice, so chained lookups never need to wrap
+struct Permutation([u8; 512]);
+
+impl Permutation {
+    fn new(rng: &mut StdRng) -> Self {
+        let mut perm: Vec<u8> = (0..=255).collect();
+        perm.shuffle(rng);
+        let mut table = [0u8; 512];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = perm[i & 255];
+        }
+        Self(table)
+    }
+
+    // Pseudo-random index in 0..256 for a lattice point
+    #[inline]
+    fn hash(&self, i: i64, j: i64, k: i64) -> usize {
+        let p = &self.0;
+        let h = p[(k & 255) as usize] as usize;
+        let h = p[h + (j & 255) as usize] as usize;
+        p[h + (i & 255) as usize] as usize
+    }
+}
+
+// Lattice cell of p and where p is inside it
+#[inline]
+fn cell(p: Vec3) -> ([i64; 3], [f64; 3]) {
+    let floor = [p.x.floor(), p.y.floor(), p.z.floor()];
+    (
+        floor.map(|f| f as i64),
+        [p.x - floor[0], p.y - floor[1], p.z - floor[2]],
+    )
+}
+
+// 6t^5 - 15t^4 + 10t^3, no creases where the cells meet (Perlin 2002)
+#[inline]
+fn quintic(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+#[inline]
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+// Random values on the lattice, blended smoothly in between. Blobby next to Perlin
+pub struct ValueNoise {
+    perm: Permutation,
+    values: [f64; 256],
+}
+
+impl ValueNoise {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut values = [0.0; 256];
+        for value in values.iter_mut() {
+            *value = rng.gen_range(-1.0..1.0);
+        }
+        Self {
+            perm: Permutation::new(&mut rng),
+            values,
+        }
+    }
+}
+
+impl Noise for ValueNoise {
+    fn noise(&self, p: Vec3) -> f64 {
+        let ([i, j, k], [x, y, z]) = cell(p);
+        let value = |di: i64, dj: i64, dk: i64| self.values[self.perm.hash(i + di, j + dj, k + dk)];
+        let (u, v, w) = (quintic(x), quintic(y), quintic(z));
+        let bottom = lerp(
+            lerp(value(0, 0, 0), value(1, 0, 0), u),
+            lerp(value(0, 1, 0), value(1, 1, 0), u),
+            v,
+        );
+        let top = lerp(
+            lerp(value(0, 0, 1), value(1, 0, 1), u),
+            lerp(value(0, 1, 1), value(1, 1, 1), u),
+            v,
+        );
+        lerp(bottom, top, w)
+    }
+}
+
+/*
+ *  Simplex noise (Perlin 2001), after Gustavson's "Simplex noise demystified". Sums the
+ *  four corners of the tetrahedron around p instead of the eight of a cube, and has no
+ *  visible grid directions.
+ */
+pub struct Simplex {
+    perm: Permutation,
+}
+
+impl Simplex {
+    // Midpoints of the edges of a cube
+    const GRADIENTS: [[f64; 3]; 12] = [
+        [1.0, 1.0, 0.0],
+        [-1.0, 1.0, 0.0],
+        [1.0, -1.0, 0.0],
+        [-1.0, -1.0, 0.0],
+        [1.0, 0.0, 1.0],
+        [-1.0, 0.0, 1.0],
+        [1.0, 0.0, -1.0],
+        [-1.0, 0.0, -1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, -1.0, 1.0],
+        [0.0, 1.0, -1.0],
+        [0.0, -1.0, -1.0],
+    ];
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            perm: Permutation::new(&mut StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl Noise for Simplex {
+    fn noise(&self, p: Vec3) -> f64 {
+        const F3: f64 = 1.0 / 3.0; // Skews space so the simplices line up with a grid
+        const G3: f64 = 1.0 / 6.0; // And back
+
+        let s = (p.x + p.y + p.z) * F3;
+        let [i, j, k] = [p.x + s, p.y + s, p.z + s].map(|c| c.floor() as i64);
+        let t = (i + j + k) as f64 * G3;
+        let x0 = [
+            p.x - (i as f64 - t),
+            p.y - (j as f64 - t),
+            p.z - (k as f64 - t),
+        ];
+
+        // Which of the six tetrahedra of the cube p is in
+        let (second, third) = if x0[0] >= x0[1] {
+            if x0[1] >= x0[2] {
+                ([1, 0, 0], [1, 1, 0])
+            } else if x0[0] >= x0[2] {
+                ([1, 0, 0], [1, 0, 1])
+            } else {
+                ([0, 0, 1], [1, 0, 1])
+            }
+        } else if x0[1] < x0[2] {
+            ([0, 0, 1], [0, 1, 1])
+        } else if x0[0] < x0[2] {
+            ([0, 1, 0], [0, 1, 1])
+        } else {
+            ([0, 1, 0], [1, 1, 0])
+        };
+
+        let corners = [[0, 0, 0], second, third, [1, 1, 1]];
+        corners
+            .iter()
+            .enumerate()
+            .map(|(n, offset)| {
+                let d = [0, 1, 2].map(|a| x0[a] - offset[a] as f64 + n as f64 * G3);
+                let falloff = 0.6 - d[0] * d[0] - d[1] * d[1] - d[2] * d[2];
+                if falloff <= 0.0 {
+                    return 0.0;
+                }
+                let gradient = Self::GRADIENTS
+                    [self.perm.hash(i + offset[0], j + offset[1], k + offset[2]) % 12];
+                let falloff2 = falloff * falloff;
+                falloff2 * falloff2 * (gradient[0] * d[0] + gradient[1] * d[1] + gradient[2] * d[2])
+            })
+            .sum::<f64>()
+            // Stretches the sum to about [-1, 1]
+            * 32.0
+    }
+}
+
+// What Worley noise returns about the feature points around p
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum WorleyFeature {
+    F1,        // Distance to the closest one, round cells
+    F2,        // Distance to the second closest one
+    F2MinusF1, // Zero on the borders between cells, a network of cracks
+    CellValue, // Random value in [-1, 1] shared by the whole cell, flat patches
+}
+
+// Cellular noise (Worley 1996), one feature point jittered inside every lattice cell
+pub struct Worley {
+    perm: Permutation,
+    jitter: [Vec3; 256],
+    feature: WorleyFeature,
+}
+
+impl Worley {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut jitter = [Vec3::new(0.0, 0.0, 0.0); 256];
+        for j in jitter.iter_mut() {
+            *j = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+        }
+        Self {
+            perm: Permutation::new(&mut rng),
+            jitter,
+            feature: WorleyFeature::F1,
+        }
+    }
+
+    pub fn with_feature(mut self, feature: WorleyFeature) -> Self {
+        self.feature = feature;
+        self
+    }
+}
+
+impl Noise for Worley {
+    fn noise(&self, p: Vec3) -> f64 {
+        let ([i, j, k], _) = cell(p);
+        let (mut f1, mut f2) = (f64::INFINITY, f64::INFINITY);
+        let mut closest = 0;
+        // A feature point further than the neighbouring cells can't be the closest
+        for dk in -1..=1 {
+            for dj in -1..=1 {
+                for di in -1..=1 {
+                    let (ci, cj, ck) = (i + di, j + dj, k + dk);
+                    let h = self.perm.hash(ci, cj, ck);
+                    let feature = Vec3::new(ci as f64, cj as f64, ck as f64) + self.jitter[h];
+                    let d = (feature - p).length();
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                        closest = h;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+        match self.feature {
+            WorleyFeature::F1 => f1,
+            WorleyFeature::F2 => f2,
+            WorleyFeature::F2MinusF1 => f2 - f1,
+            WorleyFeature::CellValue => closest as f64 / 127.5 - 1.0,
+        }
+    }
+}
+
+// How the octaves of a Fractal are combined
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum FractalKind {
+    Fbm,    // Plain sum, soft and cloudy
+    Ridged, // Sharp crests where the noise crosses zero, mountain ridges and veins
+    Billow, // Rounded bumps from the absolute value, puffy clouds and rocks
+}
+
+/*
+ *  Octaves of a noise summed at growing frequencies and shrinking amplitudes
+ *  (Musgrave's fractals in "Texturing and Modeling"). Every octave is `lacunarity`
+ *  times the frequency and `gain` times the amplitude of the one before.
+ */
+pub struct Fractal {
+    base: Arc<dyn Noise>,
+    kind: FractalKind,
+    octaves: u32,
+    lacunarity: f64,
+    gain: f64,
+}
+
+impl Fractal {
+    pub fn new(base: Arc<dyn Noise>, kind: FractalKind) -> Self {
+        Self {
+            base,
+            kind,
+            octaves: 6,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    pub fn with_lacunarity(mut self, lacunarity: f64) -> Self {
+        self.lacunarity = lacunarity;
+        self
+    }
+
+    pub fn with_gain(mut self, gain: f64) -> Self {
+        self.gain = gain;
+        self
+    }
+}
+
+impl Noise for Fractal {
+    fn noise(&self, p: Vec3) -> f64 {
+        // Moves every octave off the lattice of the one before, or they all meet at zero
+        // on the lattice points
+        let shift = Vec3::new(19.1, 33.4, 47.2);
+        let (mut sum, mut total) = (0.0, 0.0);
+        let (mut amplitude, mut frequency) = (1.0, 1.0);
+        for octave in 0..self.octaves {
+            let n = self.base.noise(p * frequency + shift * octave as f64);
+            sum += amplitude
+                * match self.kind {
+                    FractalKind::Fbm => n,
+                    FractalKind::Ridged => {
+                        let ridge = 1.0 - n.abs();
+                        2.0 * ridge * ridge - 1.0
+                    }
+                    FractalKind::Billow => 2.0 * n.abs() - 1.0,
+                };
+            total += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        // Normalized by the amplitudes so adding octaves doesn't change the range
+        if total > 0.0 {
+            sum / total
+        } else {
+            0.0
+        }
+    }
+}
@@ -2,9 +2,9 @@
  *   Struct and necessary methods to create Perlin noise
  */
 use crate::geometry::vector3::*;
+use crate::noise::*;
 
-use crate::utils::random_vec;
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 pub struct Perlin {
     ran_vec: Vec<Vec3>,
@@ -20,18 +20,28 @@ impl Perlin {
         256
     }
 
+    // Different every run
     pub fn new() -> Self {
-        let _rng = rand::thread_rng();
+        Self::with_seed(rand::thread_rng().gen())
+    }
+
+    // The same noise for the same seed
+    pub fn with_seed(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
 
         let mut ran_vec: Vec<Vec3> = vec![Vec3::new(0.0, 0.0, 0.0); Perlin::point_count()];
 
         for rvec in ran_vec.iter_mut().take(Perlin::point_count()) {
-            *rvec = Vec3::unit_vector(random_vec(-1.0, 1.0));
+            *rvec = Vec3::unit_vector(Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            ));
         }
 
-        let perm_x = Perlin::perlin_generate_perm();
-        let perm_y = Perlin::perlin_generate_perm();
-        let perm_z = Perlin::perlin_generate_perm();
+        let perm_x = Perlin::perlin_generate_perm(&mut rng);
+        let perm_y = Perlin::perlin_generate_perm(&mut rng);
+        let perm_z = Perlin::perlin_generate_perm(&mut rng);
 
         Self {
             ran_vec,
@@ -100,8 +110,7 @@ impl Perlin {
     }
 
     // TODO: Rustify the loop? This one would be difficult actually
-    fn permute(p: &mut [i64], n: usize) {
-        let mut rng = rand::thread_rng();
+    fn permute(p: &mut [i64], n: usize, rng: &mut StdRng) {
         for i in (1..n).rev() {
             let target: usize = rng.gen_range(0..i);
             // Todo: Not sure of this operation
@@ -109,15 +118,21 @@ impl Perlin {
         }
     }
 
-    fn perlin_generate_perm() -> Vec<i64> {
+    fn perlin_generate_perm(rng: &mut StdRng) -> Vec<i64> {
         let mut p: Vec<i64> = vec![0; Perlin::point_count()];
 
         for (i, point) in p.iter_mut().enumerate().take(Perlin::point_count()) {
             *point = i as i64;
         }
 
-        Perlin::permute(&mut p, Perlin::point_count());
+        Perlin::permute(&mut p, Perlin::point_count(), rng);
 
         p
     }
 }
+
+impl Noise for Perlin {
+    fn noise(&self, p: Vec3) -> f64 {
+        Perlin::noise(self, p)
+    }
+}
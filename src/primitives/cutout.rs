@@ -0,0 +1,69 @@
+use rand::Rng;
+use std::sync::Arc;
+
+use crate::accelerators::aabb::*;
+use crate::core::hittable::*;
+use crate::geometry::ray::*;
+use crate::texture::*;
+
+// How the alpha of a Cutout or a TriangleMesh decides which hits are kept
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AlphaMode {
+    Cutoff(f64), // Holes where alpha is below the value, the hard edges of leaves and fences
+    Stochastic,  // A hit is kept with probability alpha, see-through on average
+}
+
+impl AlphaMode {
+    pub fn is_opaque(self, alpha: f64) -> bool {
+        match self {
+            AlphaMode::Cutoff(cutoff) => alpha >= cutoff,
+            AlphaMode::Stochastic => alpha >= 1.0 || rand::thread_rng().gen::<f64>() < alpha,
+        }
+    }
+}
+
+/*
+ *  Cuts holes in an object by the first channel of an alpha texture, so a quad with a
+ *  leaf image looks like the leaf. Rays go through the holes as if the surface wasn't
+ *  there, shadow rays included, and the next surface behind them is found instead.
+ *  Every hole restarts the search in the object, meshes should use
+ *  TriangleMesh::with_alpha which skips them in a single traversal.
+ */
+pub struct Cutout {
+    object: Arc<dyn Hittable>,
+    alpha: Arc<dyn Texture + Sync + Send>,
+    mode: AlphaMode,
+}
+
+impl Cutout {
+    pub fn new(object: Arc<dyn Hittable>, alpha: Arc<dyn Texture + Sync + Send>) -> Self {
+        Self {
+            object,
+            alpha,
+            mode: AlphaMode::Cutoff(0.5),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: AlphaMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Hittable for Cutout {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        // Hits are only found past t_min, so every miss moves on to a later one
+        let mut t_min = t_min;
+        loop {
+            let hit = self.object.hit(r, t_min, t_max)?;
+            if self.mode.is_opaque(self.alpha.value_at(&hit).x) {
+                return Some(hit);
+            }
+            t_min = hit.t;
+        }
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb> {
+        self.object.bounding_box(time0, time1)
+    }
+}
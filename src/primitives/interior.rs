@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use crate::accelerators::aabb::*;
+use crate::constant_medium::*;
+use crate::core::hittable::*;
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+
+/*
+ *  A closed surface filled with a medium, like a glass of murky water or a gem tinted
+ *  from within. The medium has to be bounded by the surface itself, rays refracted in
+ *  travel through it, and scatter in it, until they leave through the surface again.
+ */
+pub struct Interior {
+    surface: Arc<dyn Hittable + Sync + Send>,
+    medium: Arc<dyn Hittable + Sync + Send>,
+}
+
+impl Interior {
+    pub fn new(
+        surface: Arc<dyn Hittable + Sync + Send>,
+        medium: Arc<dyn Hittable + Sync + Send>,
+    ) -> Self {
+        Self { surface, medium }
+    }
+
+    // Filled with a ConstantMedium of density `d` and albedo `c`
+    pub fn from_color(surface: Arc<dyn Hittable + Sync + Send>, d: f64, c: Vec3) -> Self {
+        let medium = ConstantMedium::from_color(Arc::clone(&surface), d, c);
+        Self::new(surface, Arc::new(medium))
+    }
+}
+
+impl Hittable for Interior {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let surface_hit = self.surface.hit(r, t_min, t_max);
+        let closest = surface_hit.as_ref().map_or(t_max, |hit| hit.t);
+        self.medium.hit(r, t_min, closest).or(surface_hit)
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb> {
+        self.surface.bounding_box(time0, time1)
+    }
+
+    fn transmittance(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        let transmittance = self.surface.transmittance(r, t_min, t_max);
+        if transmittance.max_component() <= 0.0 {
+            return transmittance;
+        }
+        transmittance * self.medium.transmittance(r, t_min, t_max)
+    }
+
+    fn medium_weight(&self, r: Ray, t_min: f64, t_max: f64) -> Vec3 {
+        self.medium.medium_weight(r, t_min, t_max)
+    }
+
+    fn has_media(&self) -> bool {
+        self.medium.has_media()
+    }
+}
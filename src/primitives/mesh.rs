@@ -1,62 +1,332 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
 use std::sync::Arc;
 
-use crate::accelerators::{aabb::*, bvh::*};
+use crate::accelerators::aabb::*;
 use crate::core::hittable::*;
-use crate::core::hittable_list::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
+use crate::loaders::ply::*;
 use crate::material::*;
+use crate::texture::*;
 
-use crate::primitives::triangle::*;
+use crate::primitives::triangle_mesh::*;
 
+#[derive(Debug)]
+pub enum MeshError {
+    Io(String, std::io::Error),         // Couldn't read the file
+    Parse(String, String),              // The file is malformed or uses an unsupported feature
+    Obj(String, tobj::LoadError),       // Reading the OBJ file itself failed
+    Mtl(String, tobj::LoadError),       // Reading the MTL library of the OBJ failed
+    Gltf(String, gltf::Error),          // Reading the glTF file or its buffers failed
+    Texture(String, image::ImageError), // A texture referenced by the MTL couldn't be read
+    Empty(String),                      // Nothing to build a BVH from
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::Io(file, e) => write!(f, "failed to read {file}: {e}"),
+            MeshError::Parse(file, e) => write!(f, "failed to parse {file}: {e}"),
+            MeshError::Obj(file, e) => write!(f, "failed to load OBJ {file}: {e}"),
+            MeshError::Mtl(file, e) => write!(f, "failed to load materials of {file}: {e}"),
+            MeshError::Gltf(file, e) => write!(f, "failed to load glTF {file}: {e}"),
+            MeshError::Texture(file, e) => write!(f, "failed to load texture {file}: {e}"),
+            MeshError::Empty(file) => write!(f, "no triangles in {file}"),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+// Models of the OBJ and its MTL materials, which may fail on their own
+type ObjData = (
+    Vec<tobj::Model>,
+    Result<Vec<tobj::Material>, tobj::LoadError>,
+);
+
+#[allow(dead_code)]
 pub struct Mesh {
-    pub triangles: Bvh,
+    pub triangles: TriangleMesh,
     pub num_triangles: usize,
     pub name: String,
+    pub objects: Vec<String>, // Names of the OBJ objects/groups in the mesh
 }
 
 impl Mesh {
-    pub fn new(mesh_file: &str, mesh_mat: Arc<dyn Material + Sync + Send>, scaling: Vec3) -> Self {
-        let mut list = HittableList::default();
-        let (models, _) = tobj::load_obj(mesh_file, &tobj::LoadOptions::default())
-            .expect("Failed to OBJ load file");
-
-        let model = &models[0].mesh;
-        let pos_ind = &model.indices;
-
-        // TODO: Can read the OBJ Model materials here too...
-        for chunk_verts in pos_ind.chunks(3) {
-            let a = Vec3::new(
-                model.positions[chunk_verts[0] as usize * 3] as f64 * scaling.x,
-                model.positions[chunk_verts[0] as usize * 3 + 1] as f64 * scaling.y,
-                model.positions[chunk_verts[0] as usize * 3 + 2] as f64 * scaling.z,
-            );
-            let b = Vec3::new(
-                model.positions[chunk_verts[1] as usize * 3] as f64 * scaling.x,
-                model.positions[chunk_verts[1] as usize * 3 + 1] as f64 * scaling.y,
-                model.positions[chunk_verts[1] as usize * 3 + 2] as f64 * scaling.z,
-            );
-            let c = Vec3::new(
-                model.positions[chunk_verts[2] as usize * 3] as f64 * scaling.x,
-                model.positions[chunk_verts[2] as usize * 3 + 1] as f64 * scaling.y,
-                model.positions[chunk_verts[2] as usize * 3 + 2] as f64 * scaling.z,
-            );
+    // Every object in the file gets the same material
+    pub fn new(
+        mesh_file: &str,
+        mesh_mat: Arc<dyn Material + Sync + Send>,
+        scaling: Vec3,
+        precision: Precision,
+    ) -> Result<Self, MeshError> {
+        let (models, _) = Mesh::load_obj(mesh_file)?;
+        Mesh::build(mesh_file, &models, scaling, precision, |_| {
+            Arc::clone(&mesh_mat)
+        })
+    }
+
+    /*
+     *  Materials come from the MTL library of the file. An object listed in `overrides`
+     *  (by its OBJ object/group name) gets that material instead, objects without any
+     *  material fall back to `default_mat`.
+     */
+    pub fn with_materials(
+        mesh_file: &str,
+        default_mat: Arc<dyn Material + Sync + Send>,
+        overrides: &HashMap<String, Arc<dyn Material + Sync + Send>>,
+        scaling: Vec3,
+        precision: Precision,
+    ) -> Result<Self, MeshError> {
+        let (models, materials) = Mesh::load_obj(mesh_file)?;
+        let materials = materials.map_err(|e| MeshError::Mtl(mesh_file.to_string(), e))?;
+
+        // Textures are relative to the OBJ file
+        let base_dir = Path::new(mesh_file).parent().unwrap_or(Path::new(""));
+        let converted = materials
+            .iter()
+            .map(|m| Mesh::convert_material(m, base_dir))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Mesh::build(mesh_file, &models, scaling, precision, |model| {
+            if let Some(mat) = overrides.get(&model.name) {
+                Arc::clone(mat)
+            } else if let Some(id) = model.mesh.material_id {
+                Arc::clone(converted.get(id).unwrap_or(&default_mat))
+            } else {
+                Arc::clone(&default_mat)
+            }
+        })
+    }
 
-            list.add(Arc::new(Triangle::from_points(
-                a,
-                b,
-                c,
-                Arc::clone(&mesh_mat),
-            )));
+    // Stanford PLY, ASCII or binary
+    pub fn from_ply(
+        mesh_file: &str,
+        mesh_mat: Arc<dyn Material + Sync + Send>,
+        scaling: Vec3,
+        precision: Precision,
+    ) -> Result<Self, MeshError> {
+        let ply = read_ply(mesh_file, scaling, precision)?;
+        if ply.indices.is_empty() {
+            return Err(MeshError::Empty(mesh_file.to_string()));
         }
+        Mesh::check_finite(mesh_file, &ply.positions)?;
+        let triangles = TriangleMesh::new(
+            ply.positions,
+            ply.normals,
+            ply.uvs,
+            ply.indices,
+            vec![mesh_mat],
+            vec![],
+        );
+        Ok(Mesh::from_triangles(mesh_file, triangles))
+    }
+
+    // STL, ASCII or binary. Only has face normals so it is always flat shaded
+    pub fn from_stl(
+        mesh_file: &str,
+        mesh_mat: Arc<dyn Material + Sync + Send>,
+        scaling: Vec3,
+        precision: Precision,
+    ) -> Result<Self, MeshError> {
+        let mut file =
+            std::fs::File::open(mesh_file).map_err(|e| MeshError::Io(mesh_file.to_string(), e))?;
+        let stl =
+            stl_io::read_stl(&mut file).map_err(|e| MeshError::Io(mesh_file.to_string(), e))?;
+        if stl.faces.is_empty() {
+            return Err(MeshError::Empty(mesh_file.to_string()));
+        }
+
+        let mut positions = VertexBuffer::with_capacity(precision, stl.vertices.len() * 3);
+        for v in stl.vertices.iter() {
+            positions.push(v[0] as f64 * scaling.x);
+            positions.push(v[1] as f64 * scaling.y);
+            positions.push(v[2] as f64 * scaling.z);
+        }
+        let indices = stl
+            .faces
+            .iter()
+            .flat_map(|f| f.vertices.map(|i| i as u32))
+            .collect();
+        Mesh::check_finite(mesh_file, &positions)?;
+
+        let triangles = TriangleMesh::new(positions, None, None, indices, vec![mesh_mat], vec![]);
+        Ok(Mesh::from_triangles(mesh_file, triangles))
+    }
+
+    // A NaN or infinite vertex would be hit everywhere along its rays
+    fn check_finite(mesh_file: &str, positions: &VertexBuffer) -> Result<(), MeshError> {
+        if positions.is_finite() {
+            Ok(())
+        } else {
+            Err(MeshError::Parse(
+                mesh_file.to_string(),
+                "non-finite vertex position".to_string(),
+            ))
+        }
+    }
 
-        let list_len = list.objects.len();
-        let triangles = Bvh::new(&mut list, 0, list_len, 0.0, 0.0);
+    fn from_triangles(mesh_file: &str, triangles: TriangleMesh) -> Self {
         println!("Loaded mesh: {}", mesh_file);
         Self {
+            num_triangles: triangles.num_triangles(),
             triangles,
-            num_triangles: list_len,
             name: mesh_file.to_string(),
+            objects: vec![],
+        }
+    }
+
+    fn load_obj(mesh_file: &str) -> Result<ObjData, MeshError> {
+        // Single index so positions, normals and texcoords line up per vertex
+        let load_options = tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        };
+        tobj::load_obj(mesh_file, &load_options)
+            .map_err(|e| MeshError::Obj(mesh_file.to_string(), e))
+    }
+
+    fn build(
+        mesh_file: &str,
+        models: &[tobj::Model],
+        scaling: Vec3,
+        precision: Precision,
+        material_for: impl Fn(&tobj::Model) -> Arc<dyn Material + Sync + Send>,
+    ) -> Result<Self, MeshError> {
+        let num_positions = models.iter().map(|m| m.mesh.positions.len()).sum();
+        // Objects without normals get zeros, which leaves them flat. Objects without texcoords
+        // get (0, 0) on every vertex, only a file with no texcoords at all falls back to barycentrics
+        let has_normals = models.iter().any(|m| !m.mesh.normals.is_empty());
+        let has_uvs = models.iter().any(|m| !m.mesh.texcoords.is_empty());
+
+        let mut positions = VertexBuffer::with_capacity(precision, num_positions);
+        let mut normals = VertexBuffer::with_capacity(precision, num_positions);
+        let mut uvs = VertexBuffer::with_capacity(precision, num_positions / 3 * 2);
+        let mut indices = vec![];
+        let mut material_ids = vec![];
+        let mut materials = vec![];
+
+        for (obj_id, obj) in models.iter().enumerate() {
+            let model = &obj.mesh;
+            let first_vertex = (positions.len() / 3) as u32;
+            let num_vertices = model.positions.len() / 3;
+
+            for i in 0..num_vertices {
+                positions.push(model.positions[i * 3] as f64 * scaling.x);
+                positions.push(model.positions[i * 3 + 1] as f64 * scaling.y);
+                positions.push(model.positions[i * 3 + 2] as f64 * scaling.z);
+
+                if has_normals {
+                    // Normals transform with the inverse of the (non-uniform) scaling
+                    let n = if model.normals.is_empty() {
+                        Vec3::new(0.0, 0.0, 0.0)
+                    } else {
+                        Vec3::unit_vector(Vec3::new(
+                            model.normals[i * 3] as f64 / scaling.x,
+                            model.normals[i * 3 + 1] as f64 / scaling.y,
+                            model.normals[i * 3 + 2] as f64 / scaling.z,
+                        ))
+                    };
+                    normals.push(n.x);
+                    normals.push(n.y);
+                    normals.push(n.z);
+                }
+                if has_uvs {
+                    let (u, v) = if model.texcoords.is_empty() {
+                        (0.0, 0.0)
+                    } else {
+                        (model.texcoords[i * 2], model.texcoords[i * 2 + 1])
+                    };
+                    uvs.push(u as f64);
+                    uvs.push(v as f64);
+                }
+            }
+
+            indices.extend(model.indices.iter().map(|i| first_vertex + i));
+            material_ids.extend(std::iter::repeat_n(obj_id as u32, model.indices.len() / 3));
+            materials.push(material_for(obj));
+        }
+
+        if indices.is_empty() {
+            return Err(MeshError::Empty(mesh_file.to_string()));
+        }
+        Mesh::check_finite(mesh_file, &positions)?;
+        // Skip the per triangle ids when there's nothing to tell apart
+        if materials.iter().all(|m| Arc::ptr_eq(m, &materials[0])) {
+            materials.truncate(1);
+            material_ids.clear();
+        }
+
+        let triangles = TriangleMesh::new(
+            positions,
+            has_normals.then_some(normals),
+            has_uvs.then_some(uvs),
+            indices,
+            materials,
+            material_ids,
+        );
+        let mut mesh = Mesh::from_triangles(mesh_file, triangles);
+        mesh.objects = models.iter().map(|m| m.name.clone()).collect();
+        Ok(mesh)
+    }
+
+    /*
+     *  MTL to our materials, first match wins:
+     *  Ke      -> DiffuseLight
+     *  d < 1   -> Dielectric with Ni as the index of refraction
+     *  Ks > Kd -> Metal, fuzz from the Ns specular exponent
+     *  else    -> Lambertian with Kd or the map_Kd image
+     */
+    fn convert_material(
+        mat: &tobj::Material,
+        base_dir: &Path,
+    ) -> Result<Arc<dyn Material + Sync + Send>, MeshError> {
+        let to_vec = |c: [f32; 3]| Vec3::new(c[0] as f64, c[1] as f64, c[2] as f64);
+        let luminance = |c: Vec3| 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+
+        // tobj reads Ke into emissive, older releases left it in the unknown parameters
+        let emission = mat.emissive.map(to_vec).or_else(|| {
+            mat.unknown_param.get("Ke").and_then(|ke| {
+                let values: Vec<f64> = ke
+                    .split_whitespace()
+                    .filter_map(|v| v.parse().ok())
+                    .collect();
+                (values.len() == 3).then(|| Vec3::new(values[0], values[1], values[2]))
+            })
+        });
+        if let Some(ke) = emission {
+            if luminance(ke) > 0.0 {
+                return Ok(Arc::new(DiffuseLight::from_color(ke)));
+            }
+        }
+
+        if mat.dissolve.unwrap_or(1.0) < 1.0 {
+            let ir = mat.optical_density.unwrap_or(1.5) as f64;
+            return Ok(Arc::new(Dielectric::new(ir)));
+        }
+
+        let diffuse = to_vec(mat.diffuse.unwrap_or([0.8, 0.8, 0.8]));
+        let specular = to_vec(mat.specular.unwrap_or([0.0, 0.0, 0.0]));
+        if mat.diffuse_texture.is_none() && luminance(specular) > luminance(diffuse) {
+            // Phong exponent to an equivalent roughness
+            let ns = mat.shininess.unwrap_or(0.0) as f64;
+            let fuzz = f64::sqrt(2.0 / (ns + 2.0));
+            return Ok(Arc::new(Metal::from_color(specular, fuzz)));
+        }
+
+        match &mat.diffuse_texture {
+            Some(tex_file) => {
+                let path = base_dir.join(tex_file);
+                let texture = TextureCache::shared()
+                    .load(&path, ColorEncoding::Srgb)
+                    .map_err(|e| MeshError::Texture(path.display().to_string(), e))?;
+                let texture: Arc<dyn Texture + Sync + Send> = Arc::new(texture);
+                Ok(Arc::new(Lambertian::from_texture(texture)))
+            }
+            None => Ok(Arc::new(Lambertian::from_color(diffuse))),
         }
     }
 }
@@ -67,8 +337,72 @@ impl Hittable for Mesh {
         self.triangles.hit(r, t_min, t_max)
     }
 
-    fn bounding_box(&self, _: f64, _: f64, output_box: &mut Aabb) -> bool {
-        *output_box = self.triangles.bbox;
-        true
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<Aabb> {
+        self.triangles.bounding_box(time0, time1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_vertices_are_rejected() {
+        let white: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(1.0, 1.0, 1.0)));
+        let dir = std::env::temp_dir();
+        for (name, bad) in [("nan", "nan"), ("inf", "inf")] {
+            let file = dir.join(format!("rustracer_{name}_vertex.ply"));
+            let ply = format!(
+                "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\n\
+                 property float y\nproperty float z\nelement face 1\n\
+                 property list uchar int vertex_indices\nend_header\n\
+                 0 0 0\n1 0 0\n0 {bad} 0\n3 0 1 2\n"
+            );
+            std::fs::write(&file, ply).unwrap();
+            let mesh = Mesh::from_ply(
+                file.to_str().unwrap(),
+                Arc::clone(&white),
+                Vec3::new(1.0, 1.0, 1.0),
+                Precision::Double,
+            );
+            std::fs::remove_file(&file).unwrap();
+            let Err(MeshError::Parse(_, msg)) = mesh else {
+                panic!("a {name} vertex was loaded");
+            };
+            assert!(msg.contains("non-finite"), "{msg}");
+        }
+    }
+
+    #[test]
+    fn mtl_emission_becomes_area_lights() {
+        let grey: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(0.5, 0.5, 0.5)));
+        let still_life = Mesh::with_materials(
+            "static/still_life.obj",
+            grey,
+            &HashMap::new(),
+            Vec3::new(1.0, 1.0, 1.0),
+            Precision::Double,
+        )
+        .unwrap();
+        let triangles = &still_life.triangles;
+
+        // The lamp is the quad at y = 4, its two triangles and only them glow
+        let emissive: Vec<usize> = (0..triangles.num_triangles())
+            .filter(|&tri| triangles.material(tri).is_emissive())
+            .collect();
+        assert_eq!(emissive.len(), 2);
+        for tri in emissive {
+            assert!(triangles.vertices(tri).iter().all(|v| v.y == 4.0));
+        }
+
+        let lights = triangles.area_lights();
+        assert_eq!(lights.len(), 2);
+        for light in lights {
+            let ls = light.sample(Vec3::new(0.0, 0.0, 0.0)).unwrap();
+            assert!(ls.wi.y > 0.0);
+            assert!(ls.radiance.x > 0.0);
+        }
     }
 }
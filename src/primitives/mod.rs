@@ -1,4 +1,7 @@
+pub mod cutout;
+pub mod interior;
 pub mod mesh;
 pub mod rect;
 pub mod sphere;
 pub mod triangle;
+pub mod triangle_mesh;
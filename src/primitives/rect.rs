@@ -5,7 +5,9 @@ use crate::core::hittable::*;
 use crate::core::hittable_list::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
+use crate::lights::{area::*, light::*};
 use crate::material::*;
+use crate::utils::*;
 
 use crate::primitives::triangle::*;
 #[derive(Clone)]
@@ -47,6 +49,49 @@ impl AARect {
             mp,
         }
     }
+
+    // Two triangle lights covering the rectangle when its material emits, same u and v
+    pub fn area_lights(&self) -> Vec<Arc<dyn Light>> {
+        if !self.mp.is_emissive() {
+            return vec![];
+        }
+        let (k_axis, first_axis, second_axis) = match &self.plane {
+            Plane::YZ => (0usize, 1usize, 2usize),
+            Plane::XZ => (1usize, 0usize, 2usize),
+            Plane::XY => (2usize, 0usize, 1usize),
+        };
+        let corner = |u: f64, v: f64| {
+            let mut p = Vec3::new(0.0, 0.0, 0.0);
+            p[k_axis] = self.k;
+            p[first_axis] = self.a0 + u * (self.a1 - self.a0);
+            p[second_axis] = self.b0 + v * (self.b1 - self.b0);
+            (p, (u, v))
+        };
+        let mut corners = [
+            corner(0.0, 0.0),
+            corner(1.0, 0.0),
+            corner(1.0, 1.0),
+            corner(0.0, 1.0),
+        ];
+        // Wind them so the triangles face +k like the rectangle's normal
+        let normal = (corners[1].0 - corners[0].0).cross(corners[2].0 - corners[0].0);
+        if normal[k_axis] < 0.0 {
+            corners.reverse();
+        }
+
+        [[0, 1, 2], [0, 2, 3]]
+            .iter()
+            .map(|tri| {
+                let light: Arc<dyn Light> = Arc::new(TriangleLight::new(
+                    tri.map(|i| corners[i].0),
+                    None,
+                    Some(tri.map(|i| corners[i].1)),
+                    Arc::clone(&self.mp),
+                ));
+                light
+            })
+            .collect()
+    }
 }
 
 impl Hittable for AARect {
@@ -58,7 +103,7 @@ impl Hittable for AARect {
         };
         let t = (self.k - r.orig[k_axis]) / r.dir[k_axis];
 
-        if t < t_min || t > t_max {
+        if t <= t_min || t > t_max {
             return None;
         }
 
@@ -71,39 +116,40 @@ impl Hittable for AARect {
 
         let u = (a - self.a0) / (self.a1 - self.a0);
         let v = (b - self.b0) / (self.b1 - self.b0);
-        let p = r.at(t);
+        // The point is exactly on the plane along k, the in-plane coordinates carry the error of t
+        let mut p = r.at(t);
+        p[k_axis] = self.k;
+        let mut p_error = p.abs() * gamma(7);
+        p_error[k_axis] = 0.0;
+
         let mut normal = Vec3::new(0.0, 0.0, 0.0);
         normal[k_axis] = 1.0;
         let mut hit_rect = HitRecord::new(p, normal, t, u, v, Arc::clone(&self.mp));
+        hit_rect.p_error = p_error;
+        hit_rect.dpdu[first_axis] = self.a1 - self.a0;
+        hit_rect.dpdv[second_axis] = self.b1 - self.b0;
         hit_rect.set_face_normal(r, normal);
 
         Some(hit_rect)
     }
 
-    fn bounding_box(&self, _: f64, _: f64, output_box: &mut Aabb) -> bool {
+    fn bounding_box(&self, _: f64, _: f64) -> Option<Aabb> {
+        let bbox = match self.plane {
+            Plane::YZ => Aabb::new(
+                Vec3::new(self.k, self.a0, self.b0),
+                Vec3::new(self.k, self.a1, self.b1),
+            ),
+            Plane::XZ => Aabb::new(
+                Vec3::new(self.a0, self.k, self.b0),
+                Vec3::new(self.a1, self.k, self.b1),
+            ),
+            Plane::XY => Aabb::new(
+                Vec3::new(self.a0, self.b0, self.k),
+                Vec3::new(self.a1, self.b1, self.k),
+            ),
+        };
         // Add a little bit of padding
-        match self.plane {
-            Plane::YZ => {
-                *output_box = Aabb {
-                    minimum: Vec3::new(self.k - 0.0001, self.a0, self.b0),
-                    maximum: Vec3::new(self.k + 0.0001, self.a1, self.b1),
-                };
-            }
-            Plane::XZ => {
-                *output_box = Aabb {
-                    minimum: Vec3::new(self.a0, self.k - 0.0001, self.b0),
-                    maximum: Vec3::new(self.a1, self.k + 0.0001, self.b1),
-                };
-            }
-            Plane::XY => {
-                *output_box = Aabb {
-                    minimum: Vec3::new(self.a0, self.b0, self.k - 0.0001),
-                    maximum: Vec3::new(self.a1, self.b1, self.k + 0.0001),
-                };
-            }
-        }
-
-        true
+        Some(bbox.pad(0.0002))
     }
 }
 
@@ -371,15 +417,28 @@ impl Box {
 }
 impl Hittable for Box {
     fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        // Just relay it to the Hittable list sides
-        self.sides.hit(r, t_min, t_max)
+        // The sides all face up their axis, the ones at box_min are turned to face out so
+        // front_face tells the inside from the outside
+        let mut hit = self.sides.hit(r, t_min, t_max)?;
+        let n = hit.geometric_normal.abs();
+        let axis = if n.x >= n.y && n.x >= n.z {
+            0
+        } else if n.y >= n.z {
+            1
+        } else {
+            2
+        };
+        let mut outward = Vec3::new(0.0, 0.0, 0.0);
+        outward[axis] = if hit.p[axis] - self.box_min[axis] < self.box_max[axis] - hit.p[axis] {
+            -1.0
+        } else {
+            1.0
+        };
+        hit.set_face_normal(r, outward);
+        Some(hit)
     }
 
-    fn bounding_box(&self, _: f64, _: f64, output_box: &mut Aabb) -> bool {
-        *output_box = Aabb {
-            minimum: self.box_min,
-            maximum: self.box_max,
-        };
-        true
+    fn bounding_box(&self, _: f64, _: f64) -> Option<Aabb> {
+        Some(Aabb::new(self.box_min, self.box_max))
     }
 }
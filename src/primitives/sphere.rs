@@ -5,6 +5,7 @@ use crate::core::hittable::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
 use crate::material::*;
+use crate::utils::*;
 
 #[derive(Clone)]
 pub struct Sphere {
@@ -46,6 +47,20 @@ impl Sphere {
             theta / std::f64::consts::PI,
         )
     }
+
+    // dp/du and dp/dv of get_uv's parametrization at the unit direction n, dp/dv is
+    // left at zero on the poles where it vanishes
+    fn partial_derivatives(&self, n: Vec3) -> (Vec3, Vec3) {
+        let pi = std::f64::consts::PI;
+        let dpdu = Vec3::new(n.z, 0.0, -n.x) * (2.0 * pi * self.radius);
+        let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - n.y * n.y));
+        if sin_theta == 0.0 {
+            return (dpdu, Vec3::new(0.0, 0.0, 0.0));
+        }
+        let dpdv = Vec3::new(-n.x * n.y / sin_theta, sin_theta, -n.z * n.y / sin_theta)
+            * (pi * self.radius);
+        (dpdu, dpdv)
+    }
 }
 
 impl Hittable for Sphere {
@@ -61,18 +76,34 @@ impl Hittable for Sphere {
         }
         let sqrt_disc = discriminant.sqrt();
 
+        // Stable form of the roots, (-b +- sqrt_disc) / a cancels badly for the root near zero
+        // which is exactly the one that matters for rays leaving the surface
+        let q = -(half_b + half_b.signum() * sqrt_disc);
+        if q == 0.0 {
+            return None;
+        }
+        let (mut t0, mut t1) = (q / a, c / q);
+        if t0 > t1 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+
         // Find the nearest root that lies in the acceptable range
-        let mut root = (-half_b - sqrt_disc) / a;
-        if root < t_min || t_max < root {
-            root = (-half_b + sqrt_disc) / a;
-            if root < t_min || t_max < root {
+        let mut root = t0;
+        if root <= t_min || t_max < root {
+            root = t1;
+            if root <= t_min || t_max < root {
                 return None;
             }
         }
 
         let t_temp = root;
-        let p_temp = r.at(t_temp);
-        let outward_normal = (p_temp - self.center) / self.radius;
+        // Reproject the point onto the surface to get rid of most of the error of r.at(t)
+        let mut p_local = r.at(t_temp) - self.center;
+        p_local = p_local * (self.radius / p_local.length());
+        let p_temp = self.center + p_local;
+        let p_error = p_local.abs() * gamma(5) + p_temp.abs() * gamma(1);
+
+        let outward_normal = p_local / self.radius;
         // Need to temporarily create this to calculate the U,V
         let mut hitrec = HitRecord::new(
             p_temp,
@@ -82,19 +113,17 @@ impl Hittable for Sphere {
             0.0,
             Arc::clone(&self.mat_ptr),
         );
+        hitrec.p_error = p_error;
         hitrec.set_face_normal(r, outward_normal);
 
         (hitrec.u, hitrec.v) = Sphere::get_uv(hitrec.normal);
+        (hitrec.dpdu, hitrec.dpdv) = self.partial_derivatives(hitrec.normal);
 
         Some(hitrec)
     }
 
-    // TODO: Rustify this part, currently it is C-like
-    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
-        *output_box = Aabb {
-            minimum: self.center - Vec3::new(self.radius, self.radius, self.radius),
-            maximum: self.center + Vec3::new(self.radius, self.radius, self.radius),
-        };
-        true
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<Aabb> {
+        let rvec = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.center - rvec, self.center + rvec))
     }
 }
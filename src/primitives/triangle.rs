@@ -4,16 +4,16 @@ use crate::accelerators::aabb::*;
 use crate::core::hittable::*;
 use crate::geometry::ray::*;
 use crate::geometry::vector3::*;
+use crate::lights::{area::*, light::*};
 use crate::material::*;
+use crate::utils::*;
 
-const EPSILON: f64 = 0.0000001;
-
-// TODO : Normal is already being interpolated
-// TODO : Make sure the color of mat's is also interpolated...?
 pub struct Triangle {
     a: Vec3,
     b: Vec3,
     c: Vec3,
+    normals: Option<[Vec3; 3]>,   // Per-vertex normals for smooth shading
+    uvs: Option<[(f64, f64); 3]>, // Per-vertex texture coordinates
     mat_ptr: Arc<dyn Material + Sync + Send>,
 }
 
@@ -25,87 +25,174 @@ impl Triangle {
         c: Vec3,
         mat_ptr: Arc<dyn Material + Sync + Send>,
     ) -> Self {
-        Self { a, b, c, mat_ptr }
+        Self {
+            a,
+            b,
+            c,
+            normals: None,
+            uvs: None,
+            mat_ptr,
+        }
     }
-}
-
-impl Hittable for Triangle {
-    // U for A, V for B and W for c
-    // MT Ray-Triangle Intersection algorithm
-    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let vert0 = self.a;
-        let vert1 = self.b;
-        let vert2 = self.c;
 
-        let edge1: Vec3 = vert1 - vert0;
-        let edge2: Vec3 = vert2 - vert0;
-        let pvec: Vec3 = r.dir.cross(edge2);
-        let det: f64 = edge1.dot(pvec);
-
-        if f64::abs(det) < EPSILON {
-            return None; // Ray is parallel to the plane of triangle
+    #[allow(dead_code)]
+    pub fn new(
+        verts: [Vec3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: Option<[(f64, f64); 3]>,
+        mat_ptr: Arc<dyn Material + Sync + Send>,
+    ) -> Self {
+        Self {
+            a: verts[0],
+            b: verts[1],
+            c: verts[2],
+            normals,
+            uvs,
+            mat_ptr,
         }
+    }
 
-        let inv_det: f64 = 1.0 / det;
-        let tvec: Vec3 = r.orig - vert0;
-        let u: f64 = inv_det * tvec.dot(pvec);
-
-        if !(0.0..=1.0).contains(&u) {
+    // The light to sample for this triangle, when its material emits
+    #[allow(dead_code)]
+    pub fn area_light(&self) -> Option<Arc<dyn Light>> {
+        if !self.mat_ptr.is_emissive() {
             return None;
         }
+        Some(Arc::new(TriangleLight::new(
+            [self.a, self.b, self.c],
+            self.normals,
+            self.uvs,
+            Arc::clone(&self.mat_ptr),
+        )))
+    }
+}
 
-        let qvec: Vec3 = tvec.cross(edge1);
-        let v: f64 = inv_det * r.dir.dot(qvec);
+// Barycentrics b0, b1 and b2 weight p0, p1 and p2
+// Watertight Ray-Triangle Intersection (Woop et al. 2013, as in PBRT 3.6.2)
+pub fn intersect_triangle(
+    r: Ray,
+    verts: [Vec3; 3],
+    t_min: f64,
+    t_max: f64,
+) -> Option<(f64, [f64; 3])> {
+    // Transform the vertices to a space where the ray starts at the origin and goes along +Z
+    let kz = r.dir.abs().max_dimension();
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let d = r.dir.permute(kx, ky, kz);
+    let mut p0t = (verts[0] - r.orig).permute(kx, ky, kz);
+    let mut p1t = (verts[1] - r.orig).permute(kx, ky, kz);
+    let mut p2t = (verts[2] - r.orig).permute(kx, ky, kz);
+
+    let sx = -d.x / d.z;
+    let sy = -d.y / d.z;
+    let sz = 1.0 / d.z;
+    p0t.x += sx * p0t.z;
+    p0t.y += sy * p0t.z;
+    p1t.x += sx * p1t.z;
+    p1t.y += sy * p1t.z;
+    p2t.x += sx * p2t.z;
+    p2t.y += sy * p2t.z;
+
+    // Edge functions, a ray through a shared edge hits exactly one of the two triangles
+    let e0 = p1t.x * p2t.y - p1t.y * p2t.x;
+    let e1 = p2t.x * p0t.y - p2t.y * p0t.x;
+    let e2 = p0t.x * p1t.y - p0t.y * p1t.x;
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return None;
+    }
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+        return None; // Ray is parallel to the plane of triangle
+    }
 
-        if v < 0.0 || u + v > 1.0 {
-            return None;
-        }
+    p0t.z *= sz;
+    p1t.z *= sz;
+    p2t.z *= sz;
+    let inv_det = 1.0 / det;
+    let t = (e0 * p0t.z + e1 * p1t.z + e2 * p2t.z) * inv_det;
+
+    // Conservative bound on the error of t, a hit inside it can't be told apart from t_min
+    let max_zt = Vec3::new(p0t.z, p1t.z, p2t.z).abs().max_component();
+    let max_xt = Vec3::new(p0t.x, p1t.x, p2t.x).abs().max_component();
+    let max_yt = Vec3::new(p0t.y, p1t.y, p2t.y).abs().max_component();
+    let delta_z = gamma(3) * max_zt;
+    let delta_x = gamma(5) * (max_xt + max_zt);
+    let delta_y = gamma(5) * (max_yt + max_zt);
+    let delta_e = 2.0 * (gamma(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+    let max_e = Vec3::new(e0, e1, e2).abs().max_component();
+    let delta_t =
+        3.0 * (gamma(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e) * f64::abs(inv_det);
+
+    if t - delta_t <= t_min || t > t_max {
+        return None;
+    }
 
-        // Now can compute t and find the intersection
-        let t = inv_det * edge2.dot(qvec);
+    Some((t, [e0 * inv_det, e1 * inv_det, e2 * inv_det]))
+}
 
-        if t < t_min || t > t_max {
-            return None;
+// Hit record for a hit found by intersect_triangle
+pub fn triangle_hit_record(
+    r: Ray,
+    verts: [Vec3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: Option<[(f64, f64); 3]>,
+    t: f64,
+    [b0, b1, b2]: [f64; 3],
+    mat_ptr: &Arc<dyn Material + Sync + Send>,
+) -> HitRecord {
+    let [a, b, c] = verts;
+    // Interpolating the vertices is more precise than r.at(t)
+    let p = a * b0 + b * b1 + c * b2;
+    let p_error = ((a * b0).abs() + (b * b1).abs() + (c * b2).abs()) * gamma(7);
+
+    let mut normal = Vec3::unit_vector((b - a).cross(c - a));
+    // Linearly interp the normals, missing (zero) normals leave the triangle flat
+    let shading_normal = normals
+        .map(|[na, nb, nc]| na * b0 + nb * b1 + nc * b2)
+        .filter(|n| n.lenght_squared() > 0.0)
+        .map(Vec3::unit_vector);
+    // Vertex normals are a better hint for the outside than the winding order
+    if let Some(ns) = shading_normal {
+        if ns.dot(normal) < 0.0 {
+            normal = -normal;
         }
+    }
 
-        if t > EPSILON {
-            // TODO: Can simply calculate the normal of triangle
-            let normal = Vec3::unit_vector(edge1.cross(edge2));
-            // U for A, V for B and W for c
-            // TODO: Can read normals from the file
-            // Linearly interp the normals
-            // let normal =
-            //    Vec3::unit_vector(self.a_norm * u + self.b_norm * v + self.c_norm * (1.0 - u - v));
-
-            let p = r.at(t);
-
-            let mut hit = HitRecord::new(p, normal, t, u, v, Arc::clone(&self.mat_ptr));
-            // Set the face of the triangle
-            hit.set_face_normal(r, normal);
-            Some(hit)
-        } else {
-            None
-        }
+    // Without texture coordinates fall back to the barycentrics
+    let [ta, tb, tc] = uvs.unwrap_or([(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]);
+    let u = ta.0 * b0 + tb.0 * b1 + tc.0 * b2;
+    let v = ta.1 * b0 + tb.1 * b1 + tc.1 * b2;
+
+    let mut hit = HitRecord::new(p, normal, t, u, v, Arc::clone(mat_ptr));
+    hit.p_error = p_error;
+    // Solve the edges for dp/du and dp/dv (PBRT 3.6.2), degenerate uvs leave them at zero
+    let (duv02, duv12) = ((ta.0 - tc.0, ta.1 - tc.1), (tb.0 - tc.0, tb.1 - tc.1));
+    let (dp02, dp12) = (a - c, b - c);
+    let det = duv02.0 * duv12.1 - duv02.1 * duv12.0;
+    if det.abs() > 1e-12 {
+        hit.dpdu = (dp02 * duv12.1 - dp12 * duv02.1) / det;
+        hit.dpdv = (dp12 * duv02.0 - dp02 * duv12.0) / det;
+    }
+    // Set the face of the triangle
+    hit.set_face_normal(r, normal);
+    if let Some(ns) = shading_normal {
+        hit.set_shading_normal(ns);
+    }
+    hit
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let verts = [self.a, self.b, self.c];
+        intersect_triangle(r, verts, t_min, t_max).map(|(t, bary)| {
+            triangle_hit_record(r, verts, self.normals, self.uvs, t, bary, &self.mat_ptr)
+        })
     }
 
-    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
-        let a = self.a;
-        let b = self.b;
-        let c = self.c;
-
-        let minimum = Vec3::new(
-            f64::min(a.x, f64::min(b.x, c.x)) - 0.0001,
-            f64::min(a.y, f64::min(b.y, c.y)) - 0.0001,
-            f64::min(a.z, f64::min(b.z, c.z)) - 0.0001,
-        );
-
-        let maximum = Vec3::new(
-            f64::max(a.x, f64::max(b.x, c.x)) + 0.0001,
-            f64::max(a.y, f64::max(b.y, c.y)) + 0.0001,
-            f64::max(a.z, f64::max(b.z, c.z)) + 0.0001,
-        );
-
-        *output_box = Aabb { minimum, maximum };
-        true
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<Aabb> {
+        // Pad so that axis aligned triangles don't end up with a flat box
+        Some(Aabb::from_points(&[self.a, self.b, self.c]).pad(0.0002))
     }
 }
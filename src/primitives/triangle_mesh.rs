@@ -0,0 +1,493 @@
+use std::sync::Arc;
+
+use crate::accelerators::aabb::*;
+use crate::core::hittable::*;
+use crate::geometry::ray::*;
+use crate::geometry::vector3::*;
+use crate::lights::{area::*, light::*};
+use crate::material::*;
+use crate::texture::*;
+
+use crate::primitives::{cutout::*, triangle::*};
+
+// Triangles per BVH leaf
+const MAX_LEAF_SIZE: usize = 4;
+// Bins the centroids are sorted into to look for the cheapest split
+const SAH_BUCKETS: usize = 12;
+
+// Storage precision of the vertex attributes, Single halves the memory of big scans
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    Single,
+    Double,
+}
+
+// Flat attribute buffer, `n` consecutive values per vertex
+pub enum VertexBuffer {
+    Single(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl VertexBuffer {
+    pub fn with_capacity(precision: Precision, capacity: usize) -> Self {
+        match precision {
+            Precision::Single => VertexBuffer::Single(Vec::with_capacity(capacity)),
+            Precision::Double => VertexBuffer::Double(Vec::with_capacity(capacity)),
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, value: f64) {
+        match self {
+            VertexBuffer::Single(data) => data.push(value as f32),
+            VertexBuffer::Double(data) => data.push(value),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            VertexBuffer::Single(data) => data.len(),
+            VertexBuffer::Double(data) => data.len(),
+        }
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // False when a value is NaN or infinite, e.g. a broken vertex in a file
+    pub fn is_finite(&self) -> bool {
+        match self {
+            VertexBuffer::Single(data) => data.iter().all(|v| v.is_finite()),
+            VertexBuffer::Double(data) => data.iter().all(|v| v.is_finite()),
+        }
+    }
+
+    #[inline]
+    fn get(&self, i: usize) -> f64 {
+        match self {
+            VertexBuffer::Single(data) => data[i] as f64,
+            VertexBuffer::Double(data) => data[i],
+        }
+    }
+
+    #[inline]
+    pub fn vec3(&self, vertex: usize) -> Vec3 {
+        Vec3::new(
+            self.get(vertex * 3),
+            self.get(vertex * 3 + 1),
+            self.get(vertex * 3 + 2),
+        )
+    }
+
+    #[inline]
+    pub fn vec2(&self, vertex: usize) -> (f64, f64) {
+        (self.get(vertex * 2), self.get(vertex * 2 + 1))
+    }
+}
+
+// Interior nodes keep their first child right after them and `offset` points to the
+// second one, leaves hold `count` triangles starting at `offset`
+struct BvhNode {
+    bbox: Aabb,
+    offset: u32,
+    count: u16,
+    axis: u8,
+}
+
+/*
+ *  Indexed triangle mesh. Vertex attributes are shared between the triangles and a
+ *  triangle is only three indices (plus a material id when the mesh has more than one
+ *  material) so millions of them fit in memory. Has its own flat BVH over the triangles
+ *  instead of one Arc<dyn Hittable> per triangle.
+ */
+pub struct TriangleMesh {
+    positions: VertexBuffer,
+    normals: Option<VertexBuffer>,
+    uvs: Option<VertexBuffer>,
+    indices: Vec<u32>,
+    materials: Vec<Arc<dyn Material + Sync + Send>>,
+    material_ids: Vec<u32>, // Empty when the whole mesh uses materials[0]
+    nodes: Vec<BvhNode>,
+    opacity: Option<(Arc<dyn Texture + Sync + Send>, AlphaMode)>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: VertexBuffer,
+        normals: Option<VertexBuffer>,
+        uvs: Option<VertexBuffer>,
+        indices: Vec<u32>,
+        materials: Vec<Arc<dyn Material + Sync + Send>>,
+        material_ids: Vec<u32>,
+    ) -> Self {
+        assert!(!materials.is_empty(), "TriangleMesh needs a material");
+        assert!(
+            material_ids.is_empty() || material_ids.len() * 3 == indices.len(),
+            "One material id per triangle"
+        );
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            indices,
+            materials,
+            material_ids,
+            nodes: vec![],
+            opacity: None,
+        };
+        mesh.build_bvh();
+        mesh
+    }
+
+    /*
+     *  Cuts holes in the mesh by the first channel of an alpha texture, like a Cutout.
+     *  The alpha is tested on every triangle the ray crosses while the BVH is walked, so
+     *  the ray goes through all the holes of a pile of leaves in one traversal.
+     */
+    pub fn with_alpha(mut self, alpha: Arc<dyn Texture + Sync + Send>, mode: AlphaMode) -> Self {
+        self.opacity = Some((alpha, mode));
+        self
+    }
+
+    #[inline]
+    pub fn num_triangles(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    #[inline]
+    fn vertex_indices(&self, tri: usize) -> [usize; 3] {
+        [
+            self.indices[tri * 3] as usize,
+            self.indices[tri * 3 + 1] as usize,
+            self.indices[tri * 3 + 2] as usize,
+        ]
+    }
+
+    #[inline]
+    pub fn vertices(&self, tri: usize) -> [Vec3; 3] {
+        self.vertex_indices(tri).map(|i| self.positions.vec3(i))
+    }
+
+    #[inline]
+    pub fn material(&self, tri: usize) -> &Arc<dyn Material + Sync + Send> {
+        if self.material_ids.is_empty() {
+            &self.materials[0]
+        } else {
+            &self.materials[self.material_ids[tri] as usize]
+        }
+    }
+
+    // One light per triangle with an emissive material
+    pub fn area_lights(&self) -> Vec<Arc<dyn Light>> {
+        (0..self.num_triangles())
+            .filter(|&tri| self.material(tri).is_emissive())
+            .map(|tri| {
+                let idx = self.vertex_indices(tri);
+                let light: Arc<dyn Light> = Arc::new(TriangleLight::new(
+                    self.vertices(tri),
+                    self.normals.as_ref().map(|n| idx.map(|i| n.vec3(i))),
+                    self.uvs.as_ref().map(|uv| idx.map(|i| uv.vec2(i))),
+                    Arc::clone(self.material(tri)),
+                ));
+                light
+            })
+            .collect()
+    }
+
+    fn triangle_bbox(&self, tri: usize) -> Aabb {
+        // Pad so that axis aligned triangles don't end up with a flat box
+        Aabb::from_points(&self.vertices(tri)).pad(0.0002)
+    }
+
+    fn build_bvh(&mut self) {
+        let num_triangles = self.num_triangles();
+        if num_triangles == 0 {
+            return;
+        }
+
+        let centroids: Vec<Vec3> = (0..num_triangles)
+            .map(|tri| self.triangle_bbox(tri).centroid())
+            .collect();
+        let mut order: Vec<u32> = (0..num_triangles as u32).collect();
+        let mut nodes = Vec::with_capacity(2 * num_triangles / MAX_LEAF_SIZE + 1);
+        self.build_node(&mut nodes, &centroids, &mut order, 0);
+
+        // Reorder the triangles so every leaf is a contiguous range
+        let mut indices = Vec::with_capacity(self.indices.len());
+        for &tri in order.iter() {
+            let tri = tri as usize;
+            indices.extend_from_slice(&self.indices[tri * 3..tri * 3 + 3]);
+        }
+        self.indices = indices;
+        if !self.material_ids.is_empty() {
+            self.material_ids = order
+                .iter()
+                .map(|&tri| self.material_ids[tri as usize])
+                .collect();
+        }
+        self.nodes = nodes;
+    }
+
+    // Split along the axis where the centroids are spread the most, at the bucket boundary
+    // with the lowest surface area heuristic cost. Falls back to the median when the
+    // buckets can't separate the centroids
+    fn build_node(
+        &self,
+        nodes: &mut Vec<BvhNode>,
+        centroids: &[Vec3],
+        order: &mut [u32],
+        first: usize,
+    ) -> usize {
+        let bbox = order.iter().fold(Aabb::empty(), |b, &tri| {
+            b.union(self.triangle_bbox(tri as usize))
+        });
+        let node_index = nodes.len();
+
+        if order.len() <= MAX_LEAF_SIZE {
+            nodes.push(BvhNode {
+                bbox,
+                offset: first as u32,
+                count: order.len() as u16,
+                axis: 0,
+            });
+            return node_index;
+        }
+
+        let centroid_bounds = order.iter().fold(Aabb::empty(), |b, &tri| {
+            let c = centroids[tri as usize];
+            b.union(Aabb::new(c, c))
+        });
+        let axis = centroid_bounds.longest_axis();
+        let mid = match self.sah_split(centroids, order, centroid_bounds, axis) {
+            Some(mid) => mid,
+            None => {
+                let mid = order.len() / 2;
+                // A NaN vertex in the file must not panic the build, total_cmp orders it anyway
+                order.select_nth_unstable_by(mid, |&a, &b| {
+                    centroids[a as usize][axis].total_cmp(&centroids[b as usize][axis])
+                });
+                mid
+            }
+        };
+
+        nodes.push(BvhNode {
+            bbox,
+            offset: 0,
+            count: 0,
+            axis: axis as u8,
+        });
+        let (left, right) = order.split_at_mut(mid);
+        self.build_node(nodes, centroids, left, first);
+        let second = self.build_node(nodes, centroids, right, first + mid);
+        nodes[node_index].offset = second as u32;
+        node_index
+    }
+
+    // Partitions order at the cheapest of the SAH_BUCKETS - 1 bucket boundaries and returns
+    // where the right side starts, None if every centroid falls in a single bucket
+    fn sah_split(
+        &self,
+        centroids: &[Vec3],
+        order: &mut [u32],
+        centroid_bounds: Aabb,
+        axis: usize,
+    ) -> Option<usize> {
+        let min = centroid_bounds.minimum[axis];
+        let extent = centroid_bounds.maximum[axis] - min;
+        if extent <= 0.0 || !extent.is_finite() {
+            return None;
+        }
+        // NaN centroids saturate into the first bucket
+        let bucket = |tri: u32| {
+            (((centroids[tri as usize][axis] - min) / extent * SAH_BUCKETS as f64) as usize)
+                .min(SAH_BUCKETS - 1)
+        };
+
+        let mut counts = [0usize; SAH_BUCKETS];
+        let mut bounds = [Aabb::empty(); SAH_BUCKETS];
+        for &tri in order.iter() {
+            let b = bucket(tri);
+            counts[b] += 1;
+            bounds[b] = bounds[b].union(self.triangle_bbox(tri as usize));
+        }
+
+        // Cost of splitting after bucket i, relative to the parent area which all share
+        let mut costs = [0.0; SAH_BUCKETS - 1];
+        let (mut area, mut count) = (Aabb::empty(), 0);
+        for i in 0..SAH_BUCKETS - 1 {
+            area = area.union(bounds[i]);
+            count += counts[i];
+            costs[i] = count as f64 * area.surface_area();
+        }
+        let (mut area, mut count) = (Aabb::empty(), 0);
+        for i in (0..SAH_BUCKETS - 1).rev() {
+            area = area.union(bounds[i + 1]);
+            count += counts[i + 1];
+            costs[i] += count as f64 * area.surface_area();
+        }
+        let split = (0..SAH_BUCKETS - 1)
+            .filter(|&i| {
+                let left: usize = counts[..=i].iter().sum();
+                left > 0 && left < order.len()
+            })
+            .min_by(|&a, &b| costs[a].total_cmp(&costs[b]))?;
+
+        let mut mid = 0;
+        for j in 0..order.len() {
+            if bucket(order[j]) <= split {
+                order.swap(mid, j);
+                mid += 1;
+            }
+        }
+        Some(mid)
+    }
+
+    // Whether the alpha keeps a hit, only the uv and the point are worked out for it
+    fn is_opaque(&self, r: Ray, tri: usize, t: f64, [b0, b1, b2]: [f64; 3]) -> bool {
+        let Some((alpha, mode)) = &self.opacity else {
+            return true;
+        };
+        // The same uv triangle_hit_record gives the hit
+        let [ta, tb, tc] = match &self.uvs {
+            Some(uvs) => self.vertex_indices(tri).map(|i| uvs.vec2(i)),
+            None => [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+        };
+        let u = ta.0 * b0 + tb.0 * b1 + tc.0 * b2;
+        let v = ta.1 * b0 + tb.1 * b1 + tc.1 * b2;
+        mode.is_opaque(alpha.value(u, v, r.at(t)).x)
+    }
+
+    fn hit_record(&self, r: Ray, tri: usize, t: f64, bary: [f64; 3]) -> HitRecord {
+        let idx = self.vertex_indices(tri);
+        let normals = self.normals.as_ref().map(|n| idx.map(|i| n.vec3(i)));
+        let uvs = self.uvs.as_ref().map(|uv| idx.map(|i| uv.vec2(i)));
+        triangle_hit_record(
+            r,
+            self.vertices(tri),
+            normals,
+            uvs,
+            t,
+            bary,
+            self.material(tri),
+        )
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, r: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut closest: Option<(usize, f64, [f64; 3])> = None;
+        let mut closest_so_far = t_max;
+        // Median splits keep the tree depth around log2(n), far below 64
+        let mut stack = [0usize; 64];
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node_index = stack[stack_len];
+            let node = &self.nodes[node_index];
+            if node.bbox.hit(r, t_min..closest_so_far).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                let first = node.offset as usize;
+                for tri in first..first + node.count as usize {
+                    if let Some((t, bary)) =
+                        intersect_triangle(r, self.vertices(tri), t_min, closest_so_far)
+                    {
+                        // A hole, whatever is behind it is found by the rest of the walk
+                        if !self.is_opaque(r, tri, t, bary) {
+                            continue;
+                        }
+                        closest_so_far = t;
+                        closest = Some((tri, t, bary));
+                    }
+                }
+            } else {
+                // Visit the child closer to the ray origin first
+                let (near, far) = if r.dir[node.axis as usize] < 0.0 {
+                    (node.offset as usize, node_index + 1)
+                } else {
+                    (node_index + 1, node.offset as usize)
+                };
+                stack[stack_len] = far;
+                stack[stack_len + 1] = near;
+                stack_len += 2;
+            }
+        }
+
+        closest.map(|(tri, t, bary)| self.hit_record(r, tri, t, bary))
+    }
+
+    fn bounding_box(&self, _: f64, _: f64) -> Option<Aabb> {
+        self.nodes.first().map(|root| root.bbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nan_vertex_does_not_panic_the_build() {
+        // A row of eight unit triangles along x, the fourth one with a NaN corner. The
+        // loaders reject those, a mesh built by hand still mustn't bring the BVH down
+        let mut positions = VertexBuffer::with_capacity(Precision::Double, 72);
+        let mut indices = vec![];
+        for i in 0..8 {
+            let x = i as f64 * 2.0;
+            let corner = if i == 3 { f64::NAN } else { x };
+            for v in [corner, 0.0, 0.0, x + 1.0, 0.0, 0.0, x, 1.0, 0.0] {
+                positions.push(v);
+            }
+            indices.extend([3 * i, 3 * i + 1, 3 * i + 2]);
+        }
+        let white: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(1.0, 1.0, 1.0)));
+        let mesh = TriangleMesh::new(positions, None, None, indices, vec![white], vec![]);
+
+        assert_eq!(mesh.num_triangles(), 8);
+    }
+
+    #[test]
+    fn sah_splits_off_the_far_cluster() {
+        // Sixteen triangles around the origin and four far off along x. A median split
+        // would mix them, the cheaper split keeps the far ones apart
+        let mut positions = VertexBuffer::with_capacity(Precision::Double, 180);
+        let mut indices = vec![];
+        let xs = (0..16)
+            .map(|i| i as f64 * 0.5)
+            .chain((0..4).map(|i| 100.0 + i as f64 * 2.0));
+        for (i, x) in (0u32..).zip(xs) {
+            for v in [x, 0.0, 0.0, x + 0.4, 0.0, 0.0, x, 0.4, 0.0] {
+                positions.push(v);
+            }
+            indices.extend([3 * i, 3 * i + 1, 3 * i + 2]);
+        }
+        let white: Arc<dyn Material + Sync + Send> =
+            Arc::new(Lambertian::from_color(Vec3::new(1.0, 1.0, 1.0)));
+        let mesh = TriangleMesh::new(positions, None, None, indices, vec![white], vec![]);
+
+        let left = &mesh.nodes[1];
+        let right = &mesh.nodes[mesh.nodes[0].offset as usize];
+        assert!(left.bbox.maximum.x < 10.0, "{:?}", left.bbox);
+        assert!(right.bbox.minimum.x > 99.0, "{:?}", right.bbox);
+
+        // Every triangle is still found
+        for tri in 0..mesh.num_triangles() {
+            let [a, b, c] = mesh.vertices(tri);
+            let target = (a + b + c) / 3.0;
+            let r = Ray::new(target + Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+            let hit = mesh.hit(r, 0.001, f64::INFINITY).expect("triangle missed");
+            assert!((hit.t - 1.0).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ *  Piecewise-constant distributions for importance sampling tabulated functions,
+ *  like the brightness of an environment map (PBRT 13.3 and 13.6.7), and alias
+ *  tables for picking one of many items by weight in constant time.
+ */
+
+// Sampling density of a tabulated function over [0, 1)
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>, // func.len() + 1 entries, from 0 to 1
+    func_int: f64, // Integral of func over [0, 1)
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].abs() / n as f64;
+        }
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            // Nothing to favor, fall back to uniform
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+        Self {
+            func,
+            cdf,
+            func_int,
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    // Sample in [0, 1) with its density, and the index of the segment it fell in
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // Last cdf entry not above u
+        let offset = (self.cdf.partition_point(|&c| c <= u) - 1).min(self.count() - 1);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = if self.func_int > 0.0 {
+            self.func[offset].abs() / self.func_int
+        } else {
+            1.0
+        };
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    // Density of sampling x in [0, 1)
+    pub fn pdf(&self, x: f64) -> f64 {
+        if self.func_int == 0.0 {
+            return 1.0;
+        }
+        let offset = ((x * self.count() as f64) as usize).min(self.count() - 1);
+        self.func[offset].abs() / self.func_int
+    }
+}
+
+// Sampling density of a tabulated function over [0, 1)^2, rows along v
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>, // Over u, one per row
+    marginal: Distribution1D,         // Over v
+}
+
+impl Distribution2D {
+    // `func` holds `height` rows of `width` values
+    pub fn new(func: &[f64], width: usize, height: usize) -> Self {
+        let conditional: Vec<Distribution1D> = func
+            .chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.func_int).collect());
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+
+    // Sample (u, v) in [0, 1)^2 with its density
+    pub fn sample(&self, u1: f64, u2: f64) -> (f64, f64, f64) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u2);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u1);
+        (u, v, pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let row = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        self.conditional[row].pdf(u) * self.marginal.pdf(v)
+    }
+}
+
+// Walker's alias method, built with Vose's algorithm (PBRT 18.3.1 in the 4th edition)
+#[derive(Clone)]
+pub struct AliasTable {
+    bins: Vec<(f64, f64, usize)>, // Pmf, chance to keep the bin and the alias otherwise
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f64]) -> Self {
+        let n = weights.len();
+        let sum: f64 = weights.iter().map(|w| w.max(0.0)).sum();
+        let pmf: Vec<f64> = if sum > 0.0 {
+            weights.iter().map(|w| w.max(0.0) / sum).collect()
+        } else {
+            vec![1.0 / n as f64; n]
+        };
+
+        // Bins are either under or over filled relative to the average of 1 / n
+        let mut q: Vec<f64> = pmf.iter().map(|p| p * n as f64).collect();
+        let mut alias = vec![0usize; n];
+        let (mut under, mut over): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| q[i] < 1.0);
+        while let (Some(&u), Some(&o)) = (under.last(), over.last()) {
+            under.pop();
+            // The over filled bin tops up the under filled one
+            alias[u] = o;
+            q[o] -= 1.0 - q[u];
+            if q[o] < 1.0 {
+                over.pop();
+                under.push(o);
+            }
+        }
+        // Whatever is left is full up to rounding errors
+        for i in under.into_iter().chain(over) {
+            q[i] = 1.0;
+        }
+
+        Self {
+            bins: (0..n).map(|i| (pmf[i], q[i], alias[i])).collect(),
+        }
+    }
+
+    // Index picked with u in [0, 1) and its probability
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        let n = self.bins.len();
+        let scaled = u * n as f64;
+        let bin = (scaled as usize).min(n - 1);
+        let (_, keep, alias) = self.bins[bin];
+        let index = if scaled - (bin as f64) < keep {
+            bin
+        } else {
+            alias
+        };
+        (index, self.bins[index].0)
+    }
+
+    #[inline]
+    pub fn pmf(&self, index: usize) -> f64 {
+        self.bins[index].0
+    }
+}
+
+// Multiple importance sampling weight of a strategy with density `f` against one with `g`
+#[inline]
+pub fn power_heuristic(f: f64, g: f64) -> f64 {
+    if f.is_infinite() {
+        return 1.0;
+    }
+    let (f2, g2) = (f * f, g * g);
+    if f2 + g2 == 0.0 {
+        0.0
+    } else {
+        f2 / (f2 + g2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias_table_matches_pmf() {
+        let table = AliasTable::new(&[1.0, 0.0, 3.0, 6.0, 0.5, -2.0]);
+        let total: f64 = (0..6).map(|i| table.pmf(i)).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        // Zero and negative weights are never picked
+        assert_eq!(table.pmf(1), 0.0);
+        assert_eq!(table.pmf(5), 0.0);
+        assert!((table.pmf(3) - 6.0 / 10.5).abs() < 1e-12);
+
+        let n = 100_000;
+        let mut counts = [0usize; 6];
+        for i in 0..n {
+            let (index, pmf) = table.sample((i as f64 + 0.5) / n as f64);
+            assert_eq!(pmf, table.pmf(index));
+            counts[index] += 1;
+        }
+        for (i, &count) in counts.iter().enumerate() {
+            let frequency = count as f64 / n as f64;
+            assert!((frequency - table.pmf(i)).abs() < 1e-3, "{i}: {frequency}");
+        }
+    }
+
+    #[test]
+    fn alias_table_without_weight_is_uniform() {
+        let table = AliasTable::new(&[0.0, 0.0, 0.0, 0.0]);
+        for i in 0..4 {
+            assert_eq!(table.pmf(i), 0.25);
+            assert_eq!(table.sample((i as f64 + 0.5) / 4.0), (i, 0.25));
+        }
+    }
+}
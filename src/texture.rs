@@ -1,10 +1,77 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
 
+use image::{codecs::hdr::HdrDecoder, DynamicImage, ImageResult, Rgb32FImage};
+
+use crate::core::hittable::HitRecord;
 use crate::geometry::vector3::*;
+use crate::microfacet::luminance;
+use crate::noise::*;
 use crate::perlin::*;
+use crate::utils::*;
+
+// How the hit point and its u and v move from one pixel to the next, zero when unknown
+#[derive(Clone, Copy, Default)]
+pub struct Footprint {
+    pub dpdx: Vec3,
+    pub dpdy: Vec3,
+    pub dudx: f64,
+    pub dvdx: f64,
+    pub dudy: f64,
+    pub dvdy: f64,
+}
+
+// Everything a texture can be looked up by (PBRT's TextureEvalContext)
+#[derive(Clone, Copy)]
+pub struct TextureContext {
+    pub u: f64,
+    pub v: f64,
+    pub p: Vec3,
+    pub n: Vec3, // Shading normal, zero when there is no surface
+    pub footprint: Footprint,
+}
+
+impl TextureContext {
+    // A single point, nothing known about its surface
+    pub fn at(u: f64, v: f64, p: Vec3) -> Self {
+        Self {
+            u,
+            v,
+            p,
+            n: Vec3::new(0.0, 0.0, 0.0),
+            footprint: Footprint::default(),
+        }
+    }
+
+    pub fn from_hit(hit: &HitRecord) -> Self {
+        Self {
+            u: hit.u,
+            v: hit.v,
+            p: hit.p,
+            n: hit.normal,
+            footprint: hit.footprint,
+        }
+    }
+}
 
 pub trait Texture: Sync + Send {
     fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3;
+
+    // Value averaged over the footprint of a pixel. Only the textures that alias have to
+    // filter, and the ones made of other textures pass the context on to them
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        self.value(ctx.u, ctx.v, ctx.p)
+    }
+
+    // Value at a hit, over the footprint the camera ray differentials gave it
+    #[inline]
+    fn value_at(&self, hit: &HitRecord) -> Vec3 {
+        self.evaluate(&TextureContext::from_hit(hit))
+    }
 }
 #[derive(Clone, Copy)]
 pub struct SolidColor {
@@ -30,6 +97,8 @@ impl Texture for SolidColor {
     }
 }
 
+// Solid checkerboard of world space, the same at every point whatever the surface. See
+// UvCheckerTexture for squares that follow the uv of the surface
 #[derive(Clone)]
 pub struct CheckerTexture {
     odd: Arc<dyn Texture>,
@@ -49,16 +118,360 @@ impl CheckerTexture {
     }
 }
 
-impl Texture for CheckerTexture {
-    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+impl CheckerTexture {
+    #[inline]
+    fn is_odd(point: Vec3) -> bool {
         // let sines = sin(10*p.x())*sin(10*p.y())*sin(10*p.z());
         let sines = f64::sin(10.0 * point.x) * f64::sin(10.0 * point.y) * f64::sin(10.0 * point.z);
-        if sines < 0.0 {
+        sines < 0.0
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        if Self::is_odd(point) {
             self.odd.value(u, v, point)
         } else {
             self.even.value(u, v, point)
         }
     }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        if Self::is_odd(ctx.p) {
+            self.odd.evaluate(ctx)
+        } else {
+            self.even.evaluate(ctx)
+        }
+    }
+}
+
+/*
+ *  Checkerboard on the surface parametrization, `scale_u` by `scale_v` squares over the
+ *  unit square of uv. When a pixel spans squares it returns their average over the
+ *  footprint, worked out in closed form (PBRT 10.5.1), instead of flickering between the
+ *  two textures.
+ */
+pub struct UvCheckerTexture {
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+    scale_u: f64,
+    scale_v: f64,
+}
+
+impl UvCheckerTexture {
+    pub fn new(even: Arc<dyn Texture>, odd: Arc<dyn Texture>, scale_u: f64, scale_v: f64) -> Self {
+        Self {
+            even,
+            odd,
+            scale_u,
+            scale_v,
+        }
+    }
+
+    pub fn from_color(c1: Vec3, c2: Vec3, scale_u: f64, scale_v: f64) -> Self {
+        Self::new(
+            Arc::new(SolidColor::from_color(c1)),
+            Arc::new(SolidColor::from_color(c2)),
+            scale_u,
+            scale_v,
+        )
+    }
+
+    // Integral from 0 to x of the square wave that is 1 on the odd squares
+    #[inline]
+    fn bump_integral(x: f64) -> f64 {
+        let half = x / 2.0;
+        half.floor() + 2.0 * f64::max(half - half.floor() - 0.5, 0.0)
+    }
+}
+
+impl Texture for UvCheckerTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        let footprint = &ctx.footprint;
+        let (s, t) = (ctx.u * self.scale_u, ctx.v * self.scale_v);
+        let ds = self.scale_u * f64::max(footprint.dudx.abs(), footprint.dudy.abs());
+        let dt = self.scale_v * f64::max(footprint.dvdx.abs(), footprint.dvdy.abs());
+        let (s0, s1) = (s - ds, s + ds);
+        let (t0, t1) = (t - dt, t + dt);
+
+        // The whole footprint is in one square
+        if s0.floor() == s1.floor() && t0.floor() == t1.floor() {
+            return if (s.floor() + t.floor()) as i64 % 2 == 0 {
+                self.even.evaluate(ctx)
+            } else {
+                self.odd.evaluate(ctx)
+            };
+        }
+
+        // Fraction of the footprint on odd squares
+        let odd_s = (Self::bump_integral(s1) - Self::bump_integral(s0)) / (2.0 * ds);
+        let odd_t = (Self::bump_integral(t1) - Self::bump_integral(t0)) / (2.0 * dt);
+        let mut odd = odd_s + odd_t - 2.0 * odd_s * odd_t;
+        if ds > 1.0 || dt > 1.0 {
+            // Spans many squares, they average out
+            odd = 0.5;
+        }
+        self.even.evaluate(ctx) * (1.0 - odd) + self.odd.evaluate(ctx) * odd
+    }
+}
+
+/*
+ *  Moves the uv a texture is looked up at: scaled first, then rotated counterclockwise
+ *  about the origin, then offset. A scale of 4 repeats an image 4 times over the
+ *  surface. The footprint is carried through the same transform so filtering keeps up.
+ */
+pub struct UvTransform {
+    texture: Arc<dyn Texture>,
+    matrix: [[f64; 2]; 2],
+    offset: (f64, f64),
+}
+
+impl UvTransform {
+    pub fn new(texture: Arc<dyn Texture>) -> Self {
+        Self {
+            texture,
+            matrix: [[1.0, 0.0], [0.0, 1.0]],
+            offset: (0.0, 0.0),
+        }
+    }
+
+    pub fn with_scale(mut self, scale_u: f64, scale_v: f64) -> Self {
+        for row in self.matrix.iter_mut() {
+            row[0] *= scale_u;
+            row[1] *= scale_v;
+        }
+        self
+    }
+
+    // In degrees, applied after the scale
+    pub fn with_rotation(mut self, angle: f64) -> Self {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let [[a, b], [c, d]] = self.matrix;
+        self.matrix = [
+            [cos * a - sin * c, cos * b - sin * d],
+            [sin * a + cos * c, sin * b + cos * d],
+        ];
+        self
+    }
+
+    pub fn with_offset(mut self, offset_u: f64, offset_v: f64) -> Self {
+        self.offset = (offset_u, offset_v);
+        self
+    }
+
+    #[inline]
+    fn apply(&self, u: f64, v: f64) -> (f64, f64) {
+        let [[a, b], [c, d]] = self.matrix;
+        (a * u + b * v, c * u + d * v)
+    }
+}
+
+impl Texture for UvTransform {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        let mut ctx = *ctx;
+        let (u, v) = self.apply(ctx.u, ctx.v);
+        (ctx.u, ctx.v) = (u + self.offset.0, v + self.offset.1);
+        let footprint = &mut ctx.footprint;
+        (footprint.dudx, footprint.dvdx) = self.apply(footprint.dudx, footprint.dvdx);
+        (footprint.dudy, footprint.dvdy) = self.apply(footprint.dudy, footprint.dvdy);
+        self.texture.evaluate(&ctx)
+    }
+}
+
+// Blends from `a` to `b` by the red channel of `mask`, clamped to [0, 1]
+pub struct MixTexture {
+    a: Arc<dyn Texture>,
+    b: Arc<dyn Texture>,
+    mask: Arc<dyn Texture>,
+}
+
+impl MixTexture {
+    pub fn new(a: Arc<dyn Texture>, b: Arc<dyn Texture>, mask: Arc<dyn Texture>) -> Self {
+        Self { a, b, mask }
+    }
+}
+
+impl Texture for MixTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        let t = self.mask.evaluate(ctx).x.clamp(0.0, 1.0);
+        // The other side doesn't need to be looked up at the ends
+        if t == 0.0 {
+            return self.a.evaluate(ctx);
+        }
+        if t == 1.0 {
+            return self.b.evaluate(ctx);
+        }
+        self.a.evaluate(ctx) * (1.0 - t) + self.b.evaluate(ctx) * t
+    }
+}
+
+// Product of two textures per channel, a tint or a dirt map over a base color
+pub struct MultiplyTexture {
+    a: Arc<dyn Texture>,
+    b: Arc<dyn Texture>,
+}
+
+impl MultiplyTexture {
+    pub fn new(a: Arc<dyn Texture>, b: Arc<dyn Texture>) -> Self {
+        Self { a, b }
+    }
+}
+
+impl Texture for MultiplyTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        self.a.evaluate(ctx) * self.b.evaluate(ctx)
+    }
+}
+
+// Sum of two textures per channel, not clamped
+pub struct AddTexture {
+    a: Arc<dyn Texture>,
+    b: Arc<dyn Texture>,
+}
+
+impl AddTexture {
+    pub fn new(a: Arc<dyn Texture>, b: Arc<dyn Texture>) -> Self {
+        Self { a, b }
+    }
+}
+
+impl Texture for AddTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        self.a.evaluate(ctx) + self.b.evaluate(ctx)
+    }
+}
+
+// Recolors a texture through a ramp, by its luminance
+pub struct RampTexture {
+    input: Arc<dyn Texture>,
+    ramp: ColorRamp,
+}
+
+impl RampTexture {
+    pub fn new(input: Arc<dyn Texture>, ramp: ColorRamp) -> Self {
+        Self { input, ramp }
+    }
+}
+
+impl Texture for RampTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        self.ramp.at(luminance(self.input.evaluate(ctx)))
+    }
+}
+
+// One minus the texture, per channel. Turns a mask around
+pub struct InvertTexture {
+    texture: Arc<dyn Texture>,
+}
+
+impl InvertTexture {
+    pub fn new(texture: Arc<dyn Texture>) -> Self {
+        Self { texture }
+    }
+}
+
+impl Texture for InvertTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0) - self.texture.evaluate(ctx)
+    }
+}
+
+/*
+ *  Looks a uv texture up by the world position instead, projected along the three axes
+ *  and blended by how much the normal faces each of them. For surfaces without a usable
+ *  parametrization, like rocks or terrain. `scale` is the number of texture repeats per
+ *  unit of world space, and a higher `sharpness` narrows the blend between projections.
+ */
+pub struct TriplanarTexture {
+    texture: Arc<dyn Texture>,
+    scale: f64,
+    sharpness: f64,
+}
+
+impl TriplanarTexture {
+    pub fn new(texture: Arc<dyn Texture>, scale: f64) -> Self {
+        Self {
+            texture,
+            scale,
+            sharpness: 4.0,
+        }
+    }
+
+    pub fn with_sharpness(mut self, sharpness: f64) -> Self {
+        self.sharpness = sharpness;
+        self
+    }
+}
+
+impl Texture for TriplanarTexture {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        let n = ctx.n.abs();
+        let mut weights = [n.x, n.y, n.z].map(|w| w.powf(self.sharpness));
+        let total: f64 = weights.iter().sum();
+        if total > 0.0 {
+            weights = weights.map(|w| w / total);
+        } else {
+            // No normal to go by
+            weights = [1.0 / 3.0; 3];
+        }
+
+        // The two axes each projection keeps, as u and v
+        const PLANES: [(usize, usize); 3] = [(2, 1), (0, 2), (0, 1)];
+        let mut color = Vec3::new(0.0, 0.0, 0.0);
+        for (&weight, &(a, b)) in weights.iter().zip(PLANES.iter()) {
+            if weight < 1e-4 {
+                continue;
+            }
+            let dpdx = ctx.footprint.dpdx * self.scale;
+            let dpdy = ctx.footprint.dpdy * self.scale;
+            let projected = TextureContext {
+                u: ctx.p[a] * self.scale,
+                v: ctx.p[b] * self.scale,
+                footprint: Footprint {
+                    dudx: dpdx[a],
+                    dvdx: dpdx[b],
+                    dudy: dpdy[a],
+                    dvdy: dpdy[b],
+                    ..ctx.footprint
+                },
+                ..*ctx
+            };
+            color += self.texture.evaluate(&projected) * weight;
+        }
+        color
+    }
 }
 
 pub struct NoiseTexture {
@@ -83,43 +496,778 @@ impl Texture for NoiseTexture {
     }
 }
 
+// Colors at positions in [0, 1], blended linearly in between and held past the ends
+#[derive(Clone)]
+pub struct ColorRamp {
+    stops: Vec<(f64, Vec3)>,
+}
+
+impl ColorRamp {
+    pub fn new(stops: &[(f64, Vec3)]) -> Self {
+        assert!(!stops.is_empty(), "ColorRamp needs a color");
+        let mut stops = stops.to_vec();
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { stops }
+    }
+
+    pub fn at(&self, t: f64) -> Vec3 {
+        let after = self.stops.partition_point(|&(position, _)| position <= t);
+        if after == 0 {
+            return self.stops[0].1;
+        }
+        if after == self.stops.len() {
+            return self.stops[after - 1].1;
+        }
+        let (t0, c0) = self.stops[after - 1];
+        let (t1, c1) = self.stops[after];
+        let f = (t - t0) / (t1 - t0);
+        c0 * (1.0 - f) + c1 * f
+    }
+}
+
+/*
+ *  Solid textures made of noise, in the spirit of Perlin's "An Image Synthesizer" and
+ *  Ebert et al.'s "Texturing and Modeling". `scale` is the number of features per unit
+ *  of world space, and the ramp colors the pattern's value in [0, 1].
+ */
+
+// Bands along x bent by turbulence into veins
+pub struct MarbleTexture {
+    turbulence: Fractal,
+    scale: f64,
+    veining: f64,
+    ramp: ColorRamp,
+}
+
+impl MarbleTexture {
+    pub fn new(scale: f64, seed: u64) -> Self {
+        Self {
+            turbulence: Fractal::new(Arc::new(Perlin::with_seed(seed)), FractalKind::Billow)
+                .with_octaves(7),
+            scale,
+            veining: 6.0,
+            ramp: ColorRamp::new(&[
+                (0.0, Vec3::new(0.92, 0.91, 0.88)),
+                (0.6, Vec3::new(0.78, 0.78, 0.8)),
+                (1.0, Vec3::new(0.2, 0.22, 0.28)),
+            ]),
+        }
+    }
+
+    // How far the turbulence pushes the bands around, 0 leaves them straight
+    #[allow(dead_code)]
+    pub fn with_veining(mut self, veining: f64) -> Self {
+        self.veining = veining;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ramp(mut self, ramp: ColorRamp) -> Self {
+        self.ramp = ramp;
+        self
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _: f64, _: f64, point: Vec3) -> Vec3 {
+        let p = point * self.scale;
+        // Billow is 2|n| - 1, back to the |n| turbulence of the classic marble
+        let turbulence = 0.5 * (self.turbulence.noise(p) + 1.0);
+        let t = 0.5 * (1.0 + f64::sin(PI * p.x + self.veining * turbulence));
+        self.ramp.at(t)
+    }
+}
+
+// Growth rings around the y axis, warped by noise stretched along the grain
+pub struct WoodTexture {
+    noise: Fractal,
+    scale: f64,
+    ramp: ColorRamp,
+}
+
+impl WoodTexture {
+    pub fn new(scale: f64, seed: u64) -> Self {
+        Self {
+            noise: Fractal::new(Arc::new(Perlin::with_seed(seed)), FractalKind::Fbm)
+                .with_octaves(4),
+            scale,
+            ramp: ColorRamp::new(&[
+                (0.0, Vec3::new(0.55, 0.33, 0.16)),
+                (0.7, Vec3::new(0.45, 0.26, 0.12)),
+                (1.0, Vec3::new(0.24, 0.12, 0.05)),
+            ]),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ramp(mut self, ramp: ColorRamp) -> Self {
+        self.ramp = ramp;
+        self
+    }
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _: f64, _: f64, point: Vec3) -> Vec3 {
+        let p = point * self.scale;
+        let grain = self.noise.noise(Vec3::new(p.x * 2.0, p.y * 0.2, p.z * 2.0));
+        let radius = p.x.hypot(p.z) + 0.6 * grain;
+        // Wide early wood fading into a thin dark ring of late wood
+        let ring = radius - radius.floor();
+        self.ramp.at(ring * ring)
+    }
+}
+
+// Crystals of random tones with a fine grain on top
+pub struct GraniteTexture {
+    crystals: Worley,
+    grain: Fractal,
+    scale: f64,
+    ramp: ColorRamp,
+}
+
+impl GraniteTexture {
+    pub fn new(scale: f64, seed: u64) -> Self {
+        Self {
+            crystals: Worley::new(seed).with_feature(WorleyFeature::CellValue),
+            grain: Fractal::new(Arc::new(Simplex::new(seed + 1)), FractalKind::Fbm).with_octaves(3),
+            scale,
+            ramp: ColorRamp::new(&[
+                (0.0, Vec3::new(0.08, 0.08, 0.09)),
+                (0.35, Vec3::new(0.45, 0.43, 0.42)),
+                (0.7, Vec3::new(0.7, 0.62, 0.58)),
+                (1.0, Vec3::new(0.9, 0.88, 0.85)),
+            ]),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ramp(mut self, ramp: ColorRamp) -> Self {
+        self.ramp = ramp;
+        self
+    }
+}
+
+impl Texture for GraniteTexture {
+    fn value(&self, _: f64, _: f64, point: Vec3) -> Vec3 {
+        let p = point * self.scale;
+        let crystal = 0.5 * (self.crystals.noise(p) + 1.0);
+        let grain = 0.5 * (self.grain.noise(p * 4.0) + 1.0);
+        self.ramp.at(0.75 * crystal + 0.25 * grain)
+    }
+}
+
+// Fractal noise cut off below a threshold, patches of cloud over the sky color
+pub struct CloudsTexture {
+    noise: Fractal,
+    scale: f64,
+    coverage: f64,
+    ramp: ColorRamp,
+}
+
+impl CloudsTexture {
+    pub fn new(scale: f64, seed: u64) -> Self {
+        Self {
+            // Value noise is blobby, which reads as puffs. The high gain keeps the small
+            // octaves strong for wispy edges, and a lacunarity off 2 keeps their blobs
+            // from lining up
+            noise: Fractal::new(Arc::new(ValueNoise::new(seed)), FractalKind::Fbm)
+                .with_lacunarity(2.2)
+                .with_gain(0.6),
+            scale,
+            coverage: 0.5,
+            ramp: ColorRamp::new(&[
+                (0.0, Vec3::new(0.3, 0.5, 0.85)),
+                (0.6, Vec3::new(0.85, 0.88, 0.92)),
+                (1.0, Vec3::new(1.0, 1.0, 1.0)),
+            ]),
+        }
+    }
+
+    // Fraction of the sky the clouds cover, from 0 (clear) to 1 (overcast)
+    pub fn with_coverage(mut self, coverage: f64) -> Self {
+        self.coverage = coverage.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_ramp(mut self, ramp: ColorRamp) -> Self {
+        self.ramp = ramp;
+        self
+    }
+}
+
+impl Texture for CloudsTexture {
+    fn value(&self, _: f64, _: f64, point: Vec3) -> Vec3 {
+        // Value noise fBm mostly stays within [-1, 1]
+        let density = (0.5 * self.noise.noise(point * self.scale) + 0.5).clamp(0.0, 1.0);
+        let threshold = 1.0 - self.coverage;
+        if density <= threshold {
+            return self.ramp.at(0.0);
+        }
+        self.ramp.at((density - threshold) / (1.0 - threshold))
+    }
+}
+
+// Where the texture coordinates outside of [0, 1] land on the image
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+// How an image lookup averages the texels under the footprint of the pixel
+#[derive(Clone, Copy, Default, PartialEq)]
+#[allow(dead_code)]
+pub enum TextureFilter {
+    Bilinear, // Four closest texels of the full image, whatever the footprint
+    #[default]
+    Trilinear, // Bilinear in the two mip levels that fit the footprint width
+    Ewa,      // Gaussian over the elliptical footprint, sharp at grazing angles (Heckbert 1989)
+}
+
+// What the values of 8 and 16 bit images mean, float images are always linear
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(dead_code)]
+pub enum ColorEncoding {
+    Srgb,   // Colors
+    Linear, // Data, like roughness or normals
+}
+
+struct MipLevel {
+    width: usize,
+    height: usize,
+    texels: Vec<[f32; 4]>, // Linear RGBA, rows from the top
+}
+
+impl MipLevel {
+    // Half the size, every texel averages the ones it covers. Odd sizes spread the
+    // leftover row or column over the last texels instead of dropping it
+    fn downsample(&self) -> Self {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut texels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let (y0, y1) = (y * self.height / height, (y + 1) * self.height / height);
+            for x in 0..width {
+                let (x0, x1) = (x * self.width / width, (x + 1) * self.width / width);
+                let mut sum = [0.0; 4];
+                for row in self.texels[y0 * self.width..y1 * self.width].chunks_exact(self.width) {
+                    for &texel in &row[x0..x1] {
+                        add_weighted(&mut sum, texel, 1.0);
+                    }
+                }
+                let count = ((x1 - x0) * (y1 - y0)) as f64;
+                texels.push(sum.map(|c| (c / count) as f32));
+            }
+        }
+        Self {
+            width,
+            height,
+            texels,
+        }
+    }
+}
+
+// Full resolution first, down to a single texel
+type MipPyramid = Vec<MipLevel>;
+
+#[inline]
+fn add_weighted(sum: &mut [f64; 4], texel: [f32; 4], weight: f64) {
+    for (s, t) in sum.iter_mut().zip(texel) {
+        *s += t as f64 * weight;
+    }
+}
+
+#[inline]
+fn lerp4(a: [f64; 4], b: [f64; 4], t: f64) -> [f64; 4] {
+    [0, 1, 2, 3].map(|i| a[i] * (1.0 - t) + b[i] * t)
+}
+
+// Like image::open, except that Radiance .hdr files keep their float values instead of
+// getting tone mapped down to 8 bits
+pub fn open_image(file: impl AsRef<Path>) -> ImageResult<DynamicImage> {
+    let file = file.as_ref();
+    let is_hdr = file
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("hdr"));
+    if !is_hdr {
+        return image::open(file);
+    }
+    let decoder = HdrDecoder::new(BufReader::new(File::open(file)?))?;
+    let meta = decoder.metadata();
+    let values = decoder.read_image_hdr()?.iter().flat_map(|p| p.0).collect();
+    let image = Rgb32FImage::from_raw(meta.width, meta.height, values)
+        .expect("HDR decoder returned the wrong number of pixels");
+    Ok(DynamicImage::ImageRgb32F(image))
+}
+
+/*
+ *  Image with its mip pyramid (PBRT 10.4 of the 3rd edition). Whatever the source was,
+ *  the texels are kept as linear RGBA floats so 16 bit and HDR images lose nothing.
+ *  (u, v) = (0, 0) is the bottom left corner of the image. Clones share the pyramid,
+ *  so the same image can be used with other wrap modes or filters for free.
+ */
+#[derive(Clone)]
 pub struct ImageTexture {
-    data: Vec<u8>,
-    width: u64,
-    height: u64,
+    levels: Arc<MipPyramid>,
+    wrap: (WrapMode, WrapMode), // Along u and along v
+    filter: TextureFilter,
 }
 
 impl ImageTexture {
+    // Longest over shortest axis of the EWA footprint, longer ones get blurred instead
+    const MAX_ANISOTROPY: f64 = 8.0;
+    // Falloff of the EWA gaussian
+    const EWA_ALPHA: f64 = 2.0;
+
+    // 8 bit sRGB RGB, rows from the top
+    #[allow(dead_code)]
     pub fn new(data: Vec<u8>, width: u64, height: u64) -> Self {
-        ImageTexture {
-            data,
-            width,
-            height,
+        let decode = |c: u8| srgb_to_linear(c as f64 / 255.0) as f32;
+        let texels = data
+            .chunks_exact(3)
+            .map(|c| [decode(c[0]), decode(c[1]), decode(c[2]), 1.0])
+            .collect();
+        Self::from_rgba(texels, width as usize, height as usize)
+    }
+
+    // Linear RGBA, rows from the top
+    pub fn from_rgba(mut texels: Vec<[f32; 4]>, width: usize, height: usize) -> Self {
+        let mut levels = vec![];
+        if width > 0 && height > 0 && texels.len() >= width * height {
+            texels.truncate(width * height);
+            let mut level = MipLevel {
+                width,
+                height,
+                texels,
+            };
+            while level.width > 1 || level.height > 1 {
+                let next = level.downsample();
+                levels.push(level);
+                level = next;
+            }
+            levels.push(level);
+        }
+        Self {
+            levels: Arc::new(levels),
+            wrap: Default::default(),
+            filter: TextureFilter::default(),
+        }
+    }
+
+    // Gray, RGB or RGBA images in 8 bit, 16 bit or float
+    pub fn from_image(image: &DynamicImage, encoding: ColorEncoding) -> Self {
+        let is_float = matches!(
+            image,
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+        );
+        let decode = encoding == ColorEncoding::Srgb && !is_float;
+        let texels = image
+            .to_rgba32f()
+            .pixels()
+            .map(|p| {
+                let [r, g, b, a] = p.0;
+                if decode {
+                    let linear = |c: f32| srgb_to_linear(c as f64) as f32;
+                    [linear(r), linear(g), linear(b), a]
+                } else {
+                    [r, g, b, a]
+                }
+            })
+            .collect();
+        Self::from_rgba(texels, image.width() as usize, image.height() as usize)
+    }
+
+    // Any file open_image reads, float images are taken as linear whatever `encoding` says
+    pub fn from_file(file: impl AsRef<Path>, encoding: ColorEncoding) -> ImageResult<Self> {
+        Ok(Self::from_image(&open_image(file)?, encoding))
+    }
+
+    pub fn with_wrap(mut self, wrap: WrapMode) -> Self {
+        self.wrap = (wrap, wrap);
+        self
+    }
+
+    // Different modes along u and v, like the wrap_s and wrap_t of a glTF sampler
+    pub fn with_wrap_uv(mut self, wrap_u: WrapMode, wrap_v: WrapMode) -> Self {
+        self.wrap = (wrap_u, wrap_v);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: TextureFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    fn texel(&self, level: usize, x: i64, y: i64) -> [f32; 4] {
+        let level = &self.levels[level];
+        let wrap = |i: i64, n: usize, mode: WrapMode| {
+            let n = n as i64;
+            let i = match mode {
+                WrapMode::Repeat => i.rem_euclid(n),
+                WrapMode::Clamp => i.clamp(0, n - 1),
+                WrapMode::Mirror => {
+                    let i = i.rem_euclid(2 * n);
+                    if i < n {
+                        i
+                    } else {
+                        2 * n - 1 - i
+                    }
+                }
+            };
+            i as usize
+        };
+        let (wrap_u, wrap_v) = self.wrap;
+        level.texels[wrap(y, level.height, wrap_v) * level.width + wrap(x, level.width, wrap_u)]
+    }
+
+    // Continuous texel coordinates in a level, texel centers at whole numbers
+    fn texel_coords(&self, level: usize, u: f64, v: f64) -> (f64, f64) {
+        let level = &self.levels[level];
+        (
+            u * level.width as f64 - 0.5,
+            (1.0 - v) * level.height as f64 - 0.5,
+        )
+    }
+
+    fn bilinear(&self, level: usize, u: f64, v: f64) -> [f64; 4] {
+        let (s, t) = self.texel_coords(level, u, v);
+        let (x, y) = (s.floor(), t.floor());
+        let (ds, dt) = (s - x, t - y);
+        let (x, y) = (x as i64, y as i64);
+        let mut sum = [0.0; 4];
+        add_weighted(&mut sum, self.texel(level, x, y), (1.0 - ds) * (1.0 - dt));
+        add_weighted(&mut sum, self.texel(level, x + 1, y), ds * (1.0 - dt));
+        add_weighted(&mut sum, self.texel(level, x, y + 1), (1.0 - ds) * dt);
+        add_weighted(&mut sum, self.texel(level, x + 1, y + 1), ds * dt);
+        sum
+    }
+
+    // `width` of the footprint in texture space picks the level, a texel per pixel
+    fn trilinear(&self, u: f64, v: f64, width: f64) -> [f64; 4] {
+        let last = self.levels.len() - 1;
+        let level = last as f64 + width.max(1e-8).log2();
+        if level <= 0.0 {
+            return self.bilinear(0, u, v);
+        }
+        if level >= last as f64 {
+            return self.bilinear(last, u, v);
+        }
+        let i = level.floor() as usize;
+        lerp4(
+            self.bilinear(i, u, v),
+            self.bilinear(i + 1, u, v),
+            level - i as f64,
+        )
+    }
+
+    fn ewa(&self, u: f64, v: f64, footprint: &Footprint) -> [f64; 4] {
+        // Axes of the footprint, t goes down the image
+        let mut major = (footprint.dudx, -footprint.dvdx);
+        let mut minor = (footprint.dudy, -footprint.dvdy);
+        let length = |d: (f64, f64)| d.0.hypot(d.1);
+        if length(major) < length(minor) {
+            std::mem::swap(&mut major, &mut minor);
+        }
+        let major_length = length(major);
+        let mut minor_length = length(minor);
+
+        // Too eccentric ellipses cover too many texels, widen them
+        if minor_length * Self::MAX_ANISOTROPY < major_length && minor_length > 0.0 {
+            let scale = major_length / (minor_length * Self::MAX_ANISOTROPY);
+            minor = (minor.0 * scale, minor.1 * scale);
+            minor_length *= scale;
+        }
+        if minor_length == 0.0 {
+            return self.bilinear(0, u, v);
+        }
+
+        // The level where the minor axis is a few texels long
+        let lod = f64::max(0.0, (self.levels.len() - 1) as f64 + minor_length.log2());
+        let i = lod.floor() as usize;
+        lerp4(
+            self.ewa_level(i, u, v, major, minor),
+            self.ewa_level(i + 1, u, v, major, minor),
+            lod - i as f64,
+        )
+    }
+
+    fn ewa_level(&self, level: usize, u: f64, v: f64, d0: (f64, f64), d1: (f64, f64)) -> [f64; 4] {
+        if level >= self.levels.len() {
+            let mut texel = [0.0; 4];
+            add_weighted(&mut texel, self.texel(self.levels.len() - 1, 0, 0), 1.0);
+            return texel;
+        }
+        let (s, t) = self.texel_coords(level, u, v);
+        let (width, height) = (
+            self.levels[level].width as f64,
+            self.levels[level].height as f64,
+        );
+        let d0 = (d0.0 * width, d0.1 * height);
+        let d1 = (d1.0 * width, d1.1 * height);
+
+        // Implicit ellipse a s^2 + b s t + c t^2 < 1, the +1 keeps it at least a texel wide
+        let mut a = d0.1 * d0.1 + d1.1 * d1.1 + 1.0;
+        let mut b = -2.0 * (d0.0 * d0.1 + d1.0 * d1.1);
+        let mut c = d0.0 * d0.0 + d1.0 * d1.0 + 1.0;
+        let inv_f = 1.0 / (a * c - b * b * 0.25);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+
+        // Texels of the ellipse's bounding box
+        let det = 4.0 * a * c - b * b;
+        let (s_radius, t_radius) = (
+            2.0 * f64::sqrt(det * c) / det,
+            2.0 * f64::sqrt(det * a) / det,
+        );
+        let (s0, s1) = ((s - s_radius).ceil() as i64, (s + s_radius).floor() as i64);
+        let (t0, t1) = ((t - t_radius).ceil() as i64, (t + t_radius).floor() as i64);
+
+        let mut sum = [0.0; 4];
+        let mut weight_sum = 0.0;
+        for y in t0..=t1 {
+            let dt = y as f64 - t;
+            for x in s0..=s1 {
+                let ds = x as f64 - s;
+                let r2 = a * ds * ds + b * ds * dt + c * dt * dt;
+                if r2 < 1.0 {
+                    let weight = f64::exp(-Self::EWA_ALPHA * r2) - f64::exp(-Self::EWA_ALPHA);
+                    add_weighted(&mut sum, self.texel(level, x, y), weight);
+                    weight_sum += weight;
+                }
+            }
+        }
+        if weight_sum > 0.0 {
+            sum.map(|c| c / weight_sum)
+        } else {
+            self.bilinear(level, u, v)
+        }
+    }
+
+    // Filtered linear RGBA at (u, v)
+    fn lookup(&self, u: f64, v: f64, footprint: &Footprint) -> [f64; 4] {
+        match self.filter {
+            TextureFilter::Bilinear => self.bilinear(0, u, v),
+            TextureFilter::Trilinear => {
+                let width = 2.0
+                    * [
+                        footprint.dudx,
+                        footprint.dvdx,
+                        footprint.dudy,
+                        footprint.dvdy,
+                    ]
+                    .iter()
+                    .fold(0.0, |max, d| f64::max(max, d.abs()));
+                self.trilinear(u, v, width)
+            }
+            TextureFilter::Ewa => self.ewa(u, v, footprint),
         }
     }
 }
 
 impl Texture for ImageTexture {
-    fn value(&self, u: f64, v: f64, _: Vec3) -> Vec3 {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
         // If we have no texture data, then return solid cyan as a debugging aid.
-        if self.data.is_empty() {
+        if self.levels.is_empty() {
             return Vec3::new(0.0, 1.0, 1.0);
         }
+        let [r, g, b, _] = self.lookup(ctx.u, ctx.v, &ctx.footprint);
+        Vec3::new(r, g, b)
+    }
+}
 
-        let nx = self.width as usize;
-        let ny = self.height as usize;
-        let mut i = (u * nx as f64) as usize;
-        let mut j = ((1.0 - v) * ny as f64) as usize;
-        if i > nx - 1 {
-            i = nx - 1
+// The alpha channel of an image as a gray texture, for the cutouts
+#[derive(Clone)]
+pub struct ImageAlpha {
+    image: ImageTexture,
+}
+
+impl ImageTexture {
+    // Shares the pixels, wrap mode and filter of the image
+    pub fn alpha(&self) -> ImageAlpha {
+        ImageAlpha {
+            image: self.clone(),
         }
-        if j > ny - 1 {
-            j = ny - 1
+    }
+}
+
+impl Texture for ImageAlpha {
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.evaluate(&TextureContext::at(u, v, point))
+    }
+
+    fn evaluate(&self, ctx: &TextureContext) -> Vec3 {
+        if self.image.levels.is_empty() {
+            return Vec3::new(1.0, 1.0, 1.0);
         }
-        let idx = 3 * i + 3 * nx * j;
-        let r = self.data[idx] as f64 / 255.0;
-        let g = self.data[idx + 1] as f64 / 255.0;
-        let b = self.data[idx + 2] as f64 / 255.0;
-        Vec3::new(r, g, b)
+        let [_, _, _, a] = self.image.lookup(ctx.u, ctx.v, &ctx.footprint);
+        Vec3::new(a, a, a)
+    }
+}
+
+/*
+ *  Decoded images by path, so the materials and meshes that use the same file share
+ *  one pyramid. Only weak references are kept, an image nothing uses anymore is freed
+ *  and gets decoded again if it is asked for later.
+ */
+#[derive(Default)]
+pub struct TextureCache {
+    images: Mutex<HashMap<(PathBuf, ColorEncoding), Weak<MipPyramid>>>,
+}
+
+impl TextureCache {
+    // The cache the loaders share
+    pub fn shared() -> &'static TextureCache {
+        static SHARED: OnceLock<TextureCache> = OnceLock::new();
+        SHARED.get_or_init(TextureCache::default)
+    }
+
+    // The texture of the file with the default wrap mode and filter
+    pub fn load(
+        &self,
+        file: impl AsRef<Path>,
+        encoding: ColorEncoding,
+    ) -> ImageResult<ImageTexture> {
+        let file = file.as_ref();
+        // Different spellings of the same path share the entry
+        let key = (
+            file.canonicalize().unwrap_or_else(|_| file.to_path_buf()),
+            encoding,
+        );
+        let mut images = self.images.lock().unwrap();
+        if let Some(levels) = images.get(&key).and_then(Weak::upgrade) {
+            return Ok(ImageTexture {
+                levels,
+                wrap: Default::default(),
+                filter: TextureFilter::default(),
+            });
+        }
+        let texture = ImageTexture::from_file(file, encoding)?;
+        // Drop the entries of the images that were freed while at it
+        images.retain(|_, levels| levels.strong_count() > 0);
+        images.insert(key, Arc::downgrade(&texture.levels));
+        Ok(texture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shows the uv it is looked up at as a color
+    struct UvColor;
+
+    impl Texture for UvColor {
+        fn value(&self, u: f64, v: f64, _: Vec3) -> Vec3 {
+            Vec3::new(u, v, 0.0)
+        }
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn bump_integral_counts_the_odd_squares() {
+        for (x, expected) in [
+            (0.0, 0.0),
+            (1.0, 0.0),
+            (1.5, 0.5),
+            (2.0, 1.0),
+            (3.25, 1.25),
+            (4.0, 2.0),
+            (-1.0, -1.0),
+            (-2.5, -1.5),
+        ] {
+            assert_close(UvCheckerTexture::bump_integral(x), expected);
+        }
+    }
+
+    #[test]
+    fn checker_averages_the_footprint() {
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        let checker = UvCheckerTexture::from_color(white, black, 4.0, 4.0);
+        let at = |u: f64, v: f64, du: f64, dv: f64| {
+            let mut ctx = TextureContext::at(u, v, Vec3::default());
+            ctx.footprint.dudx = du;
+            ctx.footprint.dvdy = dv;
+            checker.evaluate(&ctx).x
+        };
+        // Inside a square, even then odd
+        assert_close(at(0.1, 0.1, 0.01, 0.01), 1.0);
+        assert_close(at(0.3, 0.1, 0.01, 0.01), 0.0);
+        // Centered on the edge between the two along u, half of each
+        assert_close(at(0.25, 0.1, 0.05, 0.01), 0.5);
+        // A quarter of the footprint over the edge
+        assert_close(at(0.225, 0.1, 0.05, 0.01), 0.75);
+        // Spanning many squares
+        assert_close(at(0.5, 0.5, 2.0, 2.0), 0.5);
+    }
+
+    #[test]
+    fn uv_transform_scales_then_rotates_then_offsets() {
+        let transform = UvTransform::new(Arc::new(UvColor))
+            .with_scale(2.0, 1.0)
+            .with_rotation(90.0)
+            .with_offset(0.5, 0.0);
+        // (1, 0) scales to (2, 0), which turns to (0, 2)
+        let uv = transform.value(1.0, 0.0, Vec3::default());
+        assert_close(uv.x, 0.5);
+        assert_close(uv.y, 2.0);
+        // (0, 1) keeps its length and turns to (-1, 0)
+        let uv = transform.value(0.0, 1.0, Vec3::default());
+        assert_close(uv.x, -0.5);
+        assert_close(uv.y, 0.0);
+
+        // The order of the builder calls doesn't change it
+        let swapped = UvTransform::new(Arc::new(UvColor))
+            .with_rotation(90.0)
+            .with_scale(2.0, 1.0)
+            .with_offset(0.5, 0.0);
+        let uv = swapped.value(1.0, 0.0, Vec3::default());
+        assert_close(uv.x, 0.5);
+        assert_close(uv.y, 2.0);
+    }
+
+    #[test]
+    fn uv_transform_carries_the_footprint() {
+        let checker = UvCheckerTexture::from_color(
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            1.0,
+            1.0,
+        );
+        let scaled = UvTransform::new(Arc::new(checker)).with_scale(4.0, 4.0);
+        // The same footprint as in checker_averages_the_footprint, once scaled up
+        let mut ctx = TextureContext::at(0.25, 0.1, Vec3::default());
+        ctx.footprint.dudx = 0.05;
+        ctx.footprint.dvdy = 0.01;
+        assert_close(scaled.evaluate(&ctx).x, 0.5);
+    }
+
+    #[test]
+    fn image_wraps_per_axis() {
+        let texels = vec![
+            [0.0, 0.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0, 1.0],
+            [0.0, 1.0, 0.0, 1.0],
+            [1.0, 1.0, 0.0, 1.0],
+        ];
+        let image = ImageTexture::from_rgba(texels, 2, 2)
+            .with_wrap_uv(WrapMode::Mirror, WrapMode::Clamp)
+            .with_filter(TextureFilter::Bilinear);
+        // Past the right edge the mirror reads the right column again
+        assert_close(image.value(1.25, 0.75, Vec3::default()).x, 1.0);
+        assert_close(image.value(1.75, 0.75, Vec3::default()).x, 0.0);
+        // Past the top the clamp keeps reading the top row
+        assert_close(image.value(0.25, 3.0, Vec3::default()).y, 0.0);
+        assert_close(image.value(0.25, -3.0, Vec3::default()).y, 1.0);
     }
 }
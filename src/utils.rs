@@ -16,6 +16,47 @@ pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
     x
 }
 
+// Undoes the sRGB transfer curve of 8 and 16 bit images, for values in [0, 1]
+#[inline]
+pub fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Conservative bound on the relative error of n floating point operations (PBRT's gamma)
+#[inline]
+pub fn gamma(n: i32) -> f64 {
+    let eps = f64::EPSILON * 0.5;
+    (n as f64 * eps) / (1.0 - n as f64 * eps)
+}
+
+// Next representable float towards +inf
+#[inline]
+pub fn next_float_up(v: f64) -> f64 {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+    let v = if v == -0.0 { 0.0 } else { v };
+    let bits = v.to_bits();
+    let bits = if v >= 0.0 { bits + 1 } else { bits - 1 };
+    f64::from_bits(bits)
+}
+
+// Next representable float towards -inf
+#[inline]
+pub fn next_float_down(v: f64) -> f64 {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+    let v = if v == 0.0 { -0.0 } else { v };
+    let bits = v.to_bits();
+    let bits = if v > 0.0 { bits - 1 } else { bits + 1 };
+    f64::from_bits(bits)
+}
+
 pub fn random_in_unit_disk() -> Vec3 {
     let mut rng = rand::thread_rng();
     loop {
@@ -27,6 +68,7 @@ pub fn random_in_unit_disk() -> Vec3 {
     }
 }
 
+#[allow(dead_code)]
 pub fn random_vec(l: f64, h: f64) -> Vec3 {
     let mut rng = rand::thread_rng();
     Vec3 {